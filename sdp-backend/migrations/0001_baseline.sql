@@ -0,0 +1,17 @@
+CREATE TABLE robot (
+    robot_serial_number TEXT PRIMARY KEY
+);
+CREATE TABLE users (
+    user_id BIGSERIAL PRIMARY KEY,
+    user_name TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    robot_serial_number TEXT NOT NULL REFERENCES robot (robot_serial_number)
+);
+CREATE TABLE Commands (
+    command_id BIGSERIAL PRIMARY KEY,
+    robot_serial_number TEXT NOT NULL,
+    time_issued TIMESTAMPTZ NOT NULL,
+    time_instruction TIMESTAMPTZ NOT NULL,
+    instruction TEXT NOT NULL,
+    completed BOOLEAN NOT NULL DEFAULT false
+);
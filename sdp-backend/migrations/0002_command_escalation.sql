@@ -0,0 +1,2 @@
+ALTER TABLE Commands ADD COLUMN acknowledged_at TIMESTAMPTZ;
+ALTER TABLE Commands ADD COLUMN escalated_at TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+ALTER TABLE Commands ADD COLUMN status TEXT;
+ALTER TABLE Commands ADD COLUMN status_detail TEXT;
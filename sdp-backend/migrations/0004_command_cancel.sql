@@ -0,0 +1,2 @@
+ALTER TABLE Commands ADD COLUMN cancelled BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE Commands ADD COLUMN cancel_reason TEXT;
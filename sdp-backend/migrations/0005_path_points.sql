@@ -0,0 +1,8 @@
+CREATE TABLE path_points (
+    path_point_id BIGSERIAL PRIMARY KEY,
+    robot_serial_number TEXT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    x DOUBLE PRECISION NOT NULL,
+    y DOUBLE PRECISION NOT NULL
+);
+CREATE INDEX path_points_robot_recorded_at ON path_points (robot_serial_number, recorded_at);
@@ -0,0 +1,2 @@
+ALTER TABLE Commands ADD COLUMN delivered_at TIMESTAMPTZ;
+ALTER TABLE robot ADD COLUMN require_ack BOOLEAN NOT NULL DEFAULT false;
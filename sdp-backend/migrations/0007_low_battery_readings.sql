@@ -0,0 +1 @@
+ALTER TABLE robot ADD COLUMN low_battery_readings INTEGER NOT NULL DEFAULT 0;
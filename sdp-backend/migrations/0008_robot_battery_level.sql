@@ -0,0 +1 @@
+ALTER TABLE robot ADD COLUMN battery_level BIGINT;
@@ -0,0 +1,2 @@
+ALTER TABLE Commands ADD COLUMN shift_id TEXT;
+CREATE INDEX commands_shift_id ON Commands (shift_id);
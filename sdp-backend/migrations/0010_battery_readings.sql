@@ -0,0 +1,7 @@
+CREATE TABLE battery_readings (
+    battery_reading_id BIGSERIAL PRIMARY KEY,
+    robot_serial_number TEXT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    battery_level BIGINT NOT NULL
+);
+CREATE INDEX battery_readings_robot_recorded_at ON battery_readings (robot_serial_number, recorded_at);
@@ -0,0 +1 @@
+ALTER TABLE robot ADD COLUMN pending_fallback TEXT;
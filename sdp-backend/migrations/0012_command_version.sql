@@ -0,0 +1 @@
+ALTER TABLE Commands ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
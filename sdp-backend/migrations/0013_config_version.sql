@@ -0,0 +1,5 @@
+CREATE TABLE config_version (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    version BIGINT NOT NULL DEFAULT 0
+);
+INSERT INTO config_version DEFAULT VALUES;
@@ -0,0 +1,8 @@
+CREATE TABLE transitions (
+    transition_id BIGSERIAL PRIMARY KEY,
+    robot_serial_number TEXT NOT NULL,
+    transitioned_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    from_instruction TEXT NOT NULL,
+    to_instruction TEXT NOT NULL
+);
+CREATE INDEX transitions_transitioned_at ON transitions (transitioned_at);
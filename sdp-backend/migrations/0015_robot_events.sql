@@ -0,0 +1,7 @@
+CREATE TABLE robot_events (
+    robot_event_id BIGSERIAL PRIMARY KEY,
+    robot_serial_number TEXT NOT NULL,
+    event TEXT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX robot_events_robot_event ON robot_events (robot_serial_number, event, recorded_at);
@@ -0,0 +1,6 @@
+ALTER TABLE Commands ADD COLUMN resource TEXT;
+CREATE TABLE resource_locks (
+    resource TEXT PRIMARY KEY,
+    command_id BIGINT NOT NULL,
+    locked_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
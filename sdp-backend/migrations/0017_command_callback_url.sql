@@ -0,0 +1 @@
+ALTER TABLE Commands ADD COLUMN callback_url TEXT;
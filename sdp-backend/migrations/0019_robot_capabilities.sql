@@ -0,0 +1 @@
+ALTER TABLE robot ADD COLUMN capabilities TEXT;
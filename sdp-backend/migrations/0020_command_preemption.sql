@@ -0,0 +1,11 @@
+ALTER TABLE Commands ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Commands ADD COLUMN progress DOUBLE PRECISION;
+
+-- A command put aside while a higher priority one runs, resumed once the
+-- preempting command completes
+CREATE TABLE preempted_commands (
+    preempting_command_id BIGINT PRIMARY KEY REFERENCES Commands (command_id),
+    command_id BIGINT NOT NULL REFERENCES Commands (command_id),
+    progress DOUBLE PRECISION,
+    preempted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+ALTER TABLE robot ADD COLUMN model TEXT;
+ALTER TABLE robot ADD COLUMN min_battery_level BIGINT;
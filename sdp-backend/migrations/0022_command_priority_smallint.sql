@@ -0,0 +1 @@
+ALTER TABLE Commands ALTER COLUMN priority TYPE SMALLINT;
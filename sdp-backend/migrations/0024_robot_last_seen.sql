@@ -0,0 +1,2 @@
+ALTER TABLE robot ADD COLUMN last_seen TIMESTAMPTZ;
+CREATE INDEX robot_last_seen_idx ON robot (last_seen);
@@ -0,0 +1 @@
+ALTER TABLE Commands ALTER COLUMN instruction TYPE JSONB USING instruction::jsonb;
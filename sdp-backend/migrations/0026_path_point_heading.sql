@@ -0,0 +1 @@
+ALTER TABLE path_points ADD COLUMN heading DOUBLE PRECISION;
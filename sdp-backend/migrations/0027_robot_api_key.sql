@@ -0,0 +1 @@
+ALTER TABLE robot ADD COLUMN api_key_hash TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE Commands ADD COLUMN issued_by TEXT;
+-- Commands are kept for auditing, deleting one only marks it as deleted
+ALTER TABLE Commands ADD COLUMN deleted_at TIMESTAMPTZ;
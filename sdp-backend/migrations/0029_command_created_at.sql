@@ -0,0 +1,3 @@
+-- When the row was written, unlike time_issued this isn't given by the client
+ALTER TABLE Commands ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+CREATE INDEX commands_robot_created_at_idx ON Commands (robot_serial_number, created_at);
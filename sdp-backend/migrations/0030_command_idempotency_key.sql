@@ -0,0 +1,5 @@
+-- Given by clients so a retried request returns the command the first
+-- attempt made instead of issuing it twice
+ALTER TABLE Commands ADD COLUMN idempotency_key TEXT;
+ALTER TABLE Commands ADD CONSTRAINT commands_robot_idempotency_key_key
+    UNIQUE (robot_serial_number, idempotency_key);
@@ -0,0 +1,4 @@
+-- Finding a robot's current command is done on every poll, this covers
+-- the order it's picked in so it's read straight off the index
+CREATE INDEX commands_robot_time_issued_idx
+    ON Commands (robot_serial_number, time_issued DESC, command_id DESC);
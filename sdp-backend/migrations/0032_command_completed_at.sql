@@ -0,0 +1,3 @@
+-- When the command was completed, commands completed before this was
+-- added are left without one
+ALTER TABLE Commands ADD COLUMN completed_at TIMESTAMPTZ;
@@ -0,0 +1,4 @@
+-- Finds the tasks issued for a zone without scanning every command
+CREATE INDEX commands_task_zone_idx
+    ON Commands ((instruction->'Task'->>'zone'))
+    WHERE instruction ? 'Task';
@@ -0,0 +1,2 @@
+-- The firmware the robot last said it was running
+ALTER TABLE robot ADD COLUMN firmware_version TEXT;
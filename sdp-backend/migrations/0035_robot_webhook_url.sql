@@ -0,0 +1,3 @@
+-- Where the robot's completed commands are POSTed when they don't have
+-- their own callback
+ALTER TABLE robot ADD COLUMN webhook_url TEXT;
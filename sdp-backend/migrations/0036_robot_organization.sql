@@ -0,0 +1,4 @@
+-- The organization the robot belongs to, robots in one are stored under
+-- `<organization_id>:<serial>` so serials can be reused between them
+ALTER TABLE robot ADD COLUMN organization_id TEXT;
+CREATE INDEX robot_organization_idx ON robot (organization_id);
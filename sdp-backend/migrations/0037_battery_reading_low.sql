@@ -0,0 +1,5 @@
+-- Whether each reading was below the robot's threshold, consecutive low
+-- readings are counted from here so robots that were never registered are
+-- counted too
+ALTER TABLE battery_readings ADD COLUMN low BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE robot DROP COLUMN low_battery_readings;
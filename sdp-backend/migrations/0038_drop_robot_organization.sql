@@ -0,0 +1,4 @@
+-- The organization a robot belongs to is the prefix of the serial it is
+-- stored under, `<organization_id>:<serial>`, so the column repeated it
+DROP INDEX robot_organization_idx;
+ALTER TABLE robot DROP COLUMN organization_id;
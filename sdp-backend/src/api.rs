@@ -1,7 +1,30 @@
 pub mod auth;
 pub mod command;
+pub mod health;
+pub mod metrics;
 pub mod poll;
+pub mod robot;
 pub mod user;
 
+use actix_web::web;
+
+/// Registers every route the server serves, shared by the server and the
+/// tests so they can't drift apart
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(user::create_user)
+        .service(command::create_command)
+        .service(poll::robot_poll)
+        .service(poll::robot_ack)
+        .service(poll::robot_socket)
+        .service(robot::robot_task)
+        .service(robot::robot_abort)
+        .service(robot::robot_idle)
+        .service(robot::robot_current)
+        .service(metrics::scrape_metrics)
+        .service(health::health)
+        .service(health::ready)
+        .service(auth::auth);
+}
+
 // #[cfg(test)]
 // mod tests {}
@@ -1,11 +1,16 @@
-use crate::command::{Command, Instruction};
+use crate::command::{BatteryRequirements, Command, CommandConfig, Instruction};
+use crate::robot::{Robot, RobotSerial};
+use crate::shift::{self, ShiftConfig};
 use crate::user::User;
 
-use actix_web::{get, post, web, web::Data, HttpResponse};
+use actix_web::{post, web, web::Data, HttpRequest, HttpResponse};
 use chrono::{serde::ts_seconds, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
 
+// Clients set this so a retried request doesn't issue the command twice
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CommandRequest {
     // robot_serial_number: String,
@@ -14,6 +19,9 @@ pub struct CommandRequest {
     #[serde(with = "ts_seconds")]
     time_instruction: chrono::DateTime<Utc>,
     instruction: Instruction,
+    // Worked out from the shift windows when it isn't given
+    #[serde(default)]
+    shift_id: Option<String>,
     // TODO: Include the frequency of a command
     // frequency: i64,
 }
@@ -22,15 +30,64 @@ pub struct CommandRequest {
 pub async fn create_command(
     conn: Data<PgPool>,
     user: User,
+    requirements: Data<BatteryRequirements>,
+    shifts: Data<ShiftConfig>,
+    command_config: Data<CommandConfig>,
+    req: HttpRequest,
     cmd: web::Json<CommandRequest>,
 ) -> HttpResponse {
-    Command::new(
+    let robot_serial_number = match RobotSerial::parse(&user.robot_serial_number) {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    // Don't start a cleaning pattern the robot doesn't have the battery for
+    if let Instruction::Task {
+        pattern: cleaning_pattern,
+        ..
+    } = &cmd.instruction
+    {
+        let battery_check = match Robot::battery_level(&conn, &robot_serial_number).await {
+            Ok(Some(battery_level)) => requirements.check(cleaning_pattern, battery_level),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = battery_check {
+            return e.into();
+        }
+    }
+
+    let idempotency_key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+
+    let mut command = match Command::new(
         &conn,
-        &user.robot_serial_number,
+        &robot_serial_number,
         cmd.time_issued,
         cmd.time_instruction,
         &cmd.instruction,
+        &command_config,
+        Some(&user.user_name),
+        idempotency_key,
     )
     .await
-    .map_or_else(|e| e.into(), |cmd| HttpResponse::Ok().json(cmd))
+    {
+        Ok(command) => command,
+        Err(e) => return e.into(),
+    };
+
+    let shift_id = cmd
+        .shift_id
+        .clone()
+        .or_else(|| shift::current(cmd.time_instruction, &shifts));
+    if let Some(shift_id) = shift_id {
+        if let Err(e) = command.tag_shift(&conn, &shift_id).await {
+            return e.into();
+        }
+    }
+
+    HttpResponse::Ok().json(command)
 }
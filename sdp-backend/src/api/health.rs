@@ -0,0 +1,24 @@
+use crate::db;
+
+use actix_web::{get, web::Data, HttpResponse};
+use serde_json::json;
+use sqlx::postgres::PgPool;
+
+// The server is only healthy once it can reach the database
+#[get("/health")]
+pub async fn health(conn: Data<PgPool>) -> HttpResponse {
+    db::ping(&conn).await.map_or_else(
+        |e| e.into(),
+        |()| HttpResponse::Ok().json(json!({ "status": "ok" })),
+    )
+}
+
+// Traffic is only sent once the database can be reached and has been
+// migrated, anything stopping that is given in the response
+#[get("/ready")]
+pub async fn ready(conn: Data<PgPool>) -> HttpResponse {
+    db::check_ready(&conn).await.map_or_else(
+        |e| HttpResponse::ServiceUnavailable().json(e.body()),
+        |()| HttpResponse::Ok().json(json!({ "status": "ready" })),
+    )
+}
@@ -0,0 +1,23 @@
+use crate::command::Command;
+use crate::metrics;
+
+use actix_web::{get, web::Data, HttpResponse};
+use sqlx::postgres::PgPool;
+
+// The command counters for Prometheus to scrape
+#[get("/metrics")]
+pub async fn scrape_metrics(conn: Data<PgPool>) -> HttpResponse {
+    let pending = match Command::pending_count(&conn).await {
+        Ok(pending) => pending,
+        Err(e) => return e.into(),
+    };
+
+    metrics::render(pending).map_or_else(
+        |e| e.into(),
+        |body| {
+            HttpResponse::Ok()
+                .content_type(prometheus::TEXT_FORMAT)
+                .body(body)
+        },
+    )
+}
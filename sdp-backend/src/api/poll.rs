@@ -1,11 +1,247 @@
+use crate::command::Command;
+use crate::error::ApiError;
 use crate::poll::Poll;
+use crate::push::{self, NewCommand};
+use crate::robot::{Robot, RobotSerial};
 
-use actix_web::{get, web, web::Data, HttpResponse};
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Message, StreamHandler};
+use actix_web::{get, post, web, web::Data, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use futures::{channel::mpsc, StreamExt};
+use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
+use tracing::{error, warn};
+
+// Header the robot sends its API key in
+const API_KEY_HEADER: &str = "X-Api-Key";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AckRequest {
+    robot_serial_number: RobotSerial,
+    command_id: i64,
+}
+
+// Checks the request carries the robot's API key, giving the serial the
+// robot is stored under, which for a robot in an organization is qualified
+// with the organization the key belongs to
+async fn authorize(
+    conn: &PgPool,
+    req: &HttpRequest,
+    robot_serial_number: &str,
+) -> Result<RobotSerial, ApiError> {
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|api_key| api_key.to_str().ok())
+        .ok_or(ApiError::Unauthorized)?;
+
+    match Robot::authenticate_key(conn, robot_serial_number, api_key).await? {
+        Some(robot_serial_number) => Ok(robot_serial_number),
+        None => {
+            warn!(robot_serial_number, "Incorrect API Key");
+            Err(ApiError::Unauthorized)
+        }
+    }
+}
 
 #[get("/poll")]
-pub async fn robot_poll(conn: Data<PgPool>, poll: web::Json<Poll>) -> HttpResponse {
-    Poll::poll(&conn, &poll)
+pub async fn robot_poll(
+    conn: Data<PgPool>,
+    req: HttpRequest,
+    poll: web::Json<Poll>,
+) -> HttpResponse {
+    let robot_serial_number = match authorize(&conn, &req, &poll.robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+    let poll = Poll {
+        robot_serial_number,
+        ..poll.into_inner()
+    };
+
+    Poll::poll_with_config(&conn, &poll)
         .await
-        .map_or_else(|e| e.into(), |cmd| HttpResponse::Ok().json(cmd))
+        .map_or_else(|e| e.into(), |response| HttpResponse::Ok().json(response))
+}
+
+// Lets the robot acknowledge a command without a full poll
+#[post("/ack")]
+pub async fn robot_ack(
+    conn: Data<PgPool>,
+    req: HttpRequest,
+    ack: web::Json<AckRequest>,
+) -> HttpResponse {
+    let robot_serial_number = match authorize(&conn, &req, &ack.robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    Command::ack(&conn, &robot_serial_number, ack.command_id)
+        .await
+        .map_or_else(|e| e.into(), |_| HttpResponse::Ok().finish())
+}
+
+/// Lets a robot be sent its commands as soon as they're issued rather
+/// than waiting for its next poll
+///
+/// The robot sends its polls as text frames and gets each command back the
+/// same way. Whenever a command is issued to it the robot's last poll is
+/// run again, so the command goes through the same checks as a poll. The
+/// robot's API key is only checked when the socket is opened.
+#[get("/ws/{robot_serial_number}")]
+pub async fn robot_socket(
+    conn: Data<PgPool>,
+    robot_serial_number: web::Path<String>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let polled_as = RobotSerial::parse(&robot_serial_number)?;
+    let robot_serial_number = authorize(&conn, &req, &polled_as).await?;
+
+    let (events, queued) = mpsc::unbounded();
+    let socket = RobotSocket {
+        conn: conn.get_ref().clone(),
+        robot_serial_number,
+        polled_as,
+        events,
+        queued: Some(queued),
+    };
+
+    ws::start(socket, &req, stream)
+}
+
+// Something the socket has to respond to, these are handled one at a time
+enum SocketEvent {
+    Polled(Box<Poll>),
+    Issued(i64),
+}
+
+// A text frame to send to the robot
+struct Reply(String);
+
+impl Message for Reply {
+    type Result = ();
+}
+
+struct RobotSocket {
+    conn: PgPool,
+    robot_serial_number: RobotSerial,
+    // The serial the robot polls with, which leaves out its organization
+    polled_as: RobotSerial,
+    events: mpsc::UnboundedSender<SocketEvent>,
+    // Taken when the socket starts handling events
+    queued: Option<mpsc::UnboundedReceiver<SocketEvent>>,
+}
+
+// Works through the socket's events in order, so a command issued while
+// polling has been handed over before it's looked at and doesn't cause
+// another poll. Ends once the socket has gone.
+async fn handle_events(
+    conn: PgPool,
+    mut queued: mpsc::UnboundedReceiver<SocketEvent>,
+    socket: Addr<RobotSocket>,
+) {
+    // What the robot last said it was doing, without anything that should
+    // only be handled once such as reports and acks
+    let mut last_poll: Option<Poll> = None;
+
+    while let Some(event) = queued.next().await {
+        let poll = match event {
+            SocketEvent::Polled(poll) => {
+                last_poll = Some(Poll {
+                    report: None,
+                    acknowledged: None,
+                    x: None,
+                    y: None,
+                    heading: None,
+                    progress: None,
+                    ..(*poll).clone()
+                });
+                *poll
+            }
+            SocketEvent::Issued(command_id) => match &last_poll {
+                Some(poll)
+                    if matches!(
+                        Command::awaiting_delivery(&conn, command_id).await,
+                        Ok(true)
+                    ) =>
+                {
+                    poll.clone()
+                }
+                // Nothing can be worked out until the robot has said what
+                // it's doing
+                _ => continue,
+            },
+        };
+
+        let reply = match Poll::poll(&conn, &poll).await {
+            Ok(command) => match serde_json::to_string(&command) {
+                Ok(json) => json,
+                Err(e) => {
+                    error!(command_id = command.id(), error = ?e, "Socket Command Json");
+                    continue;
+                }
+            },
+            Err(e) => e.body().to_string(),
+        };
+        socket.do_send(Reply(reply));
+    }
+}
+
+impl Actor for RobotSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(queued) = self.queued.take() {
+            actix_web::rt::spawn(handle_events(self.conn.clone(), queued, ctx.address()));
+        }
+        ctx.add_stream(push::subscribe(&self.robot_serial_number));
+    }
+}
+
+impl Handler<Reply> for RobotSocket {
+    type Result = ();
+
+    fn handle(&mut self, reply: Reply, ctx: &mut Self::Context) {
+        ctx.text(reply.0);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RobotSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<Poll>(&text) {
+                Ok(poll) if poll.robot_serial_number == self.polled_as => {
+                    let poll = Poll {
+                        robot_serial_number: self.robot_serial_number.clone(),
+                        ..poll
+                    };
+                    let _ = self
+                        .events
+                        .unbounded_send(SocketEvent::Polled(Box::new(poll)));
+                }
+                Ok(_) => ctx.text(ApiError::SerialMismatch.body().to_string()),
+                Err(_) => ctx.text(ApiError::SerializationError.body().to_string()),
+            },
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            // The robot carries on with HTTP polls once the socket is gone
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+
+impl StreamHandler<NewCommand> for RobotSocket {
+    fn handle(&mut self, new_command: NewCommand, _: &mut Self::Context) {
+        let _ = self
+            .events
+            .unbounded_send(SocketEvent::Issued(new_command.command_id));
+    }
+
+    // Stopping is left to the robot's side of the socket
+    fn finished(&mut self, _: &mut Self::Context) {}
 }
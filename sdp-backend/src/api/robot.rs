@@ -0,0 +1,101 @@
+use crate::command::{AbortReason, BatteryRequirements, CleaningPattern, Command};
+use crate::error::ApiError;
+use crate::robot::{Robot, RobotSerial};
+use crate::user::User;
+
+use actix_web::{get, post, web, web::Data, HttpResponse};
+use sqlx::postgres::PgPool;
+
+// The serial the robot is stored under in the caller's organization, which
+// is the one the caller's own robot is in. A robot in another organization
+// is not found, the same as one that doesn't exist.
+async fn scoped_serial(
+    conn: &PgPool,
+    user: &User,
+    robot_serial_number: &str,
+) -> Result<RobotSerial, ApiError> {
+    let robot_serial_number = RobotSerial::parse(robot_serial_number)?;
+
+    match RobotSerial::parse(&user.robot_serial_number)?.organization() {
+        Some(organization) => {
+            Robot::in_organization(conn, &organization, &robot_serial_number).await
+        }
+        // Callers outside of an organization only reach robots outside of one
+        None if robot_serial_number.organization().is_none() => Ok(robot_serial_number),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+// Start the robot on a cleaning pattern, as long as it has the battery for it
+#[post("/robots/{robot_serial_number}/task")]
+pub async fn robot_task(
+    conn: Data<PgPool>,
+    user: User,
+    requirements: Data<BatteryRequirements>,
+    robot_serial_number: web::Path<String>,
+    cleaning_pattern: web::Json<CleaningPattern>,
+) -> HttpResponse {
+    let robot_serial_number = match scoped_serial(&conn, &user, &robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    Command::task_with_battery(
+        &conn,
+        &robot_serial_number,
+        &cleaning_pattern,
+        &requirements,
+    )
+    .await
+    .map_or_else(|e| e.into(), |command| HttpResponse::Ok().json(command))
+}
+
+#[post("/robots/{robot_serial_number}/abort")]
+pub async fn robot_abort(
+    conn: Data<PgPool>,
+    user: User,
+    robot_serial_number: web::Path<String>,
+    reason: web::Json<AbortReason>,
+) -> HttpResponse {
+    let robot_serial_number = match scoped_serial(&conn, &user, &robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    Command::abort(&conn, &robot_serial_number, &reason)
+        .await
+        .map_or_else(|e| e.into(), |command| HttpResponse::Ok().json(command))
+}
+
+#[post("/robots/{robot_serial_number}/idle")]
+pub async fn robot_idle(
+    conn: Data<PgPool>,
+    user: User,
+    robot_serial_number: web::Path<String>,
+) -> HttpResponse {
+    let robot_serial_number = match scoped_serial(&conn, &user, &robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    Command::idle(&conn, &robot_serial_number)
+        .await
+        .map_or_else(|e| e.into(), |command| HttpResponse::Ok().json(command))
+}
+
+// The latest command the robot has been issued
+#[get("/robots/{robot_serial_number}/current")]
+pub async fn robot_current(
+    conn: Data<PgPool>,
+    user: User,
+    robot_serial_number: web::Path<String>,
+) -> HttpResponse {
+    let robot_serial_number = match scoped_serial(&conn, &user, &robot_serial_number).await {
+        Ok(robot_serial_number) => robot_serial_number,
+        Err(e) => return e.into(),
+    };
+
+    Command::current(&conn, &robot_serial_number)
+        .await
+        .map_or_else(|e| e.into(), |command| HttpResponse::Ok().json(command))
+}
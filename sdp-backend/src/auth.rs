@@ -85,11 +85,7 @@ impl FromRequest for User {
             _ => return err(ApiError::AuthenticationFailed),
         };
 
-        rt.block_on(async move {
-            token
-                .validate(&conn)
-                .await
-                .map_or_else(|e| err(e), |u| ok(u))
-        })
+        rt.block_on(async move { token.validate(&conn).await })
+            .map_or_else(err, ok)
     }
 }
@@ -0,0 +1,123 @@
+use crate::command::Command;
+use crate::error::ApiError;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::postgres::PgPool;
+use std::collections::BTreeMap;
+use tracing::error;
+
+// A battery that charges properly gets back above this level
+const RECOVERY_THRESHOLD: i64 = 80;
+// Fewer readings than this aren't enough to judge a battery on
+const MIN_READINGS: usize = 3;
+
+/// Robots whose battery stayed below the recovery threshold while they
+/// were docked to charge in the window, which points to a failing battery.
+///
+/// Readings taken while a robot wasn't charging are ignored, a robot that
+/// is working with a low battery hasn't had the chance to recover.
+pub async fn non_recovering(conn: &PgPool, window: Duration) -> Result<Vec<String>, ApiError> {
+    let since = Utc::now() - window;
+    let rows = sqlx::query!(
+        r#"
+SELECT B.robot_serial_number, B.battery_level, B.recorded_at FROM battery_readings B
+WHERE B.recorded_at >= $1
+ORDER BY B.robot_serial_number, B.recorded_at
+        "#,
+        since
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Battery Non Recovering");
+        ApiError::from(e)
+    })?;
+
+    let mut readings: BTreeMap<String, Vec<(DateTime<Utc>, i64)>> = BTreeMap::new();
+    for r in rows {
+        readings
+            .entry(r.robot_serial_number)
+            .or_default()
+            .push((r.recorded_at, r.battery_level));
+    }
+
+    let mut flagged = Vec::new();
+    for (serial, readings) in readings {
+        let charging = Command::charging_intervals(conn, &serial, since).await?;
+        if never_recovers(&readings, &charging) {
+            flagged.push(serial);
+        }
+    }
+
+    Ok(flagged)
+}
+
+// Whether the readings taken while charging never got back up to a healthy
+// level, the rest of the readings aren't counted
+fn never_recovers(
+    readings: &[(DateTime<Utc>, i64)],
+    charging: &[(DateTime<Utc>, DateTime<Utc>)],
+) -> bool {
+    let levels: Vec<i64> = readings
+        .iter()
+        .filter(|(at, _)| charging.iter().any(|(start, end)| start <= at && at <= end))
+        .map(|(_, level)| *level)
+        .collect();
+
+    levels.len() >= MIN_READINGS && levels.iter().all(|l| *l < RECOVERY_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::never_recovers;
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    // One reading a minute, starting on the hour
+    fn readings(levels: &[i64]) -> Vec<(DateTime<Utc>, i64)> {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+
+        levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| (start + Duration::minutes(i as i64), *level))
+            .collect()
+    }
+
+    // Charging for the whole of the first hour
+    fn charging() -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+
+        vec![(start, start + Duration::hours(1))]
+    }
+
+    #[test]
+    fn healthy_battery_recovers() {
+        assert!(!never_recovers(
+            &readings(&[30, 45, 60, 85, 95]),
+            &charging()
+        ));
+    }
+
+    #[test]
+    fn failing_battery_never_recovers() {
+        assert!(never_recovers(
+            &readings(&[30, 40, 45, 44, 46]),
+            &charging()
+        ));
+    }
+
+    #[test]
+    fn too_few_readings() {
+        assert!(!never_recovers(&readings(&[30, 40]), &charging()));
+        assert!(!never_recovers(&[], &charging()));
+    }
+
+    #[test]
+    fn readings_outside_charging_ignored() {
+        assert!(!never_recovers(&readings(&[30, 40, 45, 44, 46]), &[]));
+
+        // Only the last two readings were taken on the dock
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 3, 0);
+        let docked = [(start, start + Duration::hours(1))];
+        assert!(!never_recovers(&readings(&[30, 40, 45, 44, 46]), &docked));
+    }
+}
@@ -1,13 +1,210 @@
+use crate::db::{with_retry, RetryPolicy};
 use crate::error::ApiError;
-use chrono::{serde::ts_seconds, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgPool;
+use crate::metrics;
+use crate::notify::{CompletionSink, Notification};
+use crate::poll::POLL_INTERVAL_SECS;
+use crate::push;
+use crate::robot::{OrganizationId, Robot, RobotSerial};
+use crate::transition::Transition;
+use chrono::{
+    serde::{ts_seconds, ts_seconds_option},
+    Duration, SecondsFormat, Utc,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Done, Executor, Transaction,
+};
+use std::collections::HashMap;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+use tracing::{debug, error, warn};
 
-// TODO: Set this to a sensible value
-const TIME_ISSUED_BUFFER: i64 = 1000;
-const TIME_INSTRUCTION_BUFFER: i64 = 1000;
+// Both time buffers default to this when they aren't configured
+const DEFAULT_BUFFER_SECS: i64 = 1000;
+// By default a robot can be issued this many commands every
+// `DEFAULT_RATE_LIMIT_SECS`
+const DEFAULT_RATE_LIMIT: i64 = 10;
+const DEFAULT_RATE_LIMIT_SECS: i64 = 10;
+// Most commands a robot can have waiting before new ones are refused
+const DEFAULT_MAX_QUEUE_DEPTH: i64 = 50;
+// Aborts are for safety, so they come before anything else queued
+const ABORT_PRIORITY: i16 = i16::MAX;
+// Most commands returned by a single page of history
+const MAX_HISTORY_LIMIT: i64 = 500;
+// Tasks issued without parameters run at full speed and cover the area once
+const DEFAULT_TASK_SPEED: u8 = 100;
+const DEFAULT_TASK_PASSES: u8 = 1;
+// Low battery readings in a row before the robot is aborted, so a sensor
+// glitch doesn't stop a task
+const DEFAULT_LOW_BATTERY_READINGS: i32 = 3;
+// Longest a completion callback is given before it is dropped
+const CALLBACK_TIMEOUT_SECS: u64 = 5;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+static COMMAND_CONFIG: OnceLock<CommandConfig> = OnceLock::new();
+static DEFAULT_WEBHOOK_URL: OnceLock<String> = OnceLock::new();
+
+// How many of the next commands issued on this thread lose their
+// connection once they have been committed, for testing retries
+#[cfg(test)]
+thread_local! {
+    pub(crate) static LOSE_CONNECTION_AFTER_COMMIT: std::cell::Cell<u32> =
+        const { std::cell::Cell::new(0) };
+}
+
+/// How far a command's times may be from now, how often a robot can be
+/// issued commands, and how strictly stored commands are read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandConfig {
+    /// How far from now a command's issue time may be
+    pub time_issued_buffer: Duration,
+    /// How far from now a command's instruction time may be for it to be run
+    pub time_instruction_buffer: Duration,
+    /// Whether an instruction that can't be read is taken as a safety abort
+    /// rather than failing with `CorruptInstruction`
+    pub lenient_instructions: bool,
+    /// Most commands a robot can be issued within `rate_limit_window`,
+    /// aborts aren't limited
+    pub rate_limit: i64,
+    pub rate_limit_window: Duration,
+    /// Most commands a robot can have that aren't completed, aborts can
+    /// always be issued
+    pub max_queue_depth: i64,
+    /// What happens to a command issued when the queue is full
+    pub queue_mode: QueueMode,
+    /// How writes are retried when the database connection drops
+    pub retry: RetryPolicy,
+    /// How many low battery readings in a row a robot can report before
+    /// it is aborted
+    pub low_battery_readings: i32,
+}
+
+/// How a command issued to a robot with a full queue is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMode {
+    /// The command is refused with `QueueFull`
+    Reject,
+    /// The oldest waiting command that isn't an abort is cancelled to make
+    /// room
+    Replace,
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        CommandConfig {
+            time_issued_buffer: Duration::seconds(DEFAULT_BUFFER_SECS),
+            time_instruction_buffer: Duration::seconds(DEFAULT_BUFFER_SECS),
+            lenient_instructions: false,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            rate_limit_window: Duration::seconds(DEFAULT_RATE_LIMIT_SECS),
+            max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+            queue_mode: QueueMode::Reject,
+            retry: RetryPolicy::default(),
+            low_battery_readings: DEFAULT_LOW_BATTERY_READINGS,
+        }
+    }
+}
+
+impl CommandConfig {
+    /// Reads the buffers in seconds from `TIME_ISSUED_BUFFER_SECS` and
+    /// `TIME_INSTRUCTION_BUFFER_SECS`, the rate limit from
+    /// `COMMAND_RATE_LIMIT` and `COMMAND_RATE_LIMIT_SECS`, the queue from
+    /// `COMMAND_QUEUE_DEPTH` and `COMMAND_QUEUE_MODE` (`reject` or
+    /// `replace`), retries from `DB_RETRY_ATTEMPTS` and
+    /// `DB_RETRY_BASE_DELAY_MS`, the low battery readings allowed from
+    /// `LOW_BATTERY_READINGS`, and lenient reading from
+    /// `LENIENT_INSTRUCTIONS`, any that aren't set keep the default
+    pub fn from_env() -> Self {
+        CommandConfig::from_vars(|var| env::var(var).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let seconds = |name: &str, default: Duration| {
+            var(name)
+                .and_then(|s| s.parse().ok())
+                .map(Duration::seconds)
+                .unwrap_or(default)
+        };
+        let default = CommandConfig::default();
+
+        CommandConfig {
+            time_issued_buffer: seconds("TIME_ISSUED_BUFFER_SECS", default.time_issued_buffer),
+            time_instruction_buffer: seconds(
+                "TIME_INSTRUCTION_BUFFER_SECS",
+                default.time_instruction_buffer,
+            ),
+            lenient_instructions: var("LENIENT_INSTRUCTIONS")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.lenient_instructions),
+            rate_limit: var("COMMAND_RATE_LIMIT")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.rate_limit),
+            rate_limit_window: seconds("COMMAND_RATE_LIMIT_SECS", default.rate_limit_window),
+            max_queue_depth: var("COMMAND_QUEUE_DEPTH")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.max_queue_depth),
+            queue_mode: match var("COMMAND_QUEUE_MODE").as_deref() {
+                Some("reject") => QueueMode::Reject,
+                Some("replace") => QueueMode::Replace,
+                _ => default.queue_mode,
+            },
+            retry: RetryPolicy {
+                attempts: var("DB_RETRY_ATTEMPTS")
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(default.retry.attempts),
+                base_delay: var("DB_RETRY_BASE_DELAY_MS")
+                    .and_then(|s| s.parse().ok())
+                    .map(std::time::Duration::from_millis)
+                    .unwrap_or(default.retry.base_delay),
+            },
+            low_battery_readings: var("LOW_BATTERY_READINGS")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(default.low_battery_readings),
+        }
+    }
+
+    /// Makes this the config for the commands the server issues and for
+    /// finding pending commands, returns false if one was already installed
+    pub fn install(self) -> bool {
+        COMMAND_CONFIG.set(self).is_ok()
+    }
+
+    /// The installed config, or the default if none was installed
+    pub fn global() -> &'static CommandConfig {
+        COMMAND_CONFIG.get_or_init(CommandConfig::default)
+    }
+}
+
+// Reads an instruction as stored. One that can't be read is an error so a
+// bad row is noticed, unless the config is lenient when the robot is told
+// to abort to be safe.
+pub(crate) fn read_instruction(
+    command_id: i64,
+    instruction: Value,
+    config: &CommandConfig,
+) -> Result<Instruction, ApiError> {
+    match serde_json::from_value(instruction) {
+        Ok(instruction) => Ok(instruction),
+        Err(e) if config.lenient_instructions => {
+            warn!(command_id, error = ?e, "Corrupt Instruction");
+            Ok(Instruction::Abort(AbortReason::Saftey))
+        }
+        Err(e) => {
+            error!(command_id, error = ?e, "Corrupt Instruction");
+            Err(ApiError::CorruptInstruction { command_id })
+        }
+    }
+}
+
+// The time between two instants regardless of their order, to the
+// nearest whole second below as the buffers are given in seconds.
+fn elapsed(now: chrono::DateTime<Utc>, then: chrono::DateTime<Utc>) -> Duration {
+    Duration::seconds((now - then).num_seconds().abs())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Command {
     command_id: i64,
     pub robot_serial_number: String,
@@ -17,14 +214,246 @@ pub struct Command {
     time_instruction: chrono::DateTime<Utc>,
     pub instruction: Instruction,
     pub completed: bool,
+    pub status: Option<TaskStatus>,
+    pub cancelled: bool,
+    #[serde(default, with = "ts_seconds_option")]
+    pub acknowledged_at: Option<chrono::DateTime<Utc>>,
+    #[serde(default, with = "ts_seconds_option")]
+    pub delivered_at: Option<chrono::DateTime<Utc>>,
+    pub shift_id: Option<String>,
+    // Goes up each time the command is changed, so an update made from a
+    // stale copy can be turned away
+    pub version: i64,
+    pub resource: Option<String>,
+    pub callback_url: Option<String>,
+    // Pending commands with a higher priority are given to the robot first
+    pub priority: i16,
+    pub progress: Option<f64>,
+    // Who asked for the command, if it didn't come from the server itself
+    #[serde(default)]
+    pub issued_by: Option<String>,
+    // Deleted commands are kept for auditing but are otherwise ignored
+    #[serde(default, with = "ts_seconds_option")]
+    pub deleted_at: Option<chrono::DateTime<Utc>>,
+    // When the command was first completed, completing it again keeps this
+    #[serde(default, with = "ts_seconds_option")]
+    pub completed_at: Option<chrono::DateTime<Utc>>,
+}
+
+// Commands are the same if they have the same id, however much the row has
+// changed between reading them
+impl PartialEq for Command {
+    fn eq(&self, other: &Self) -> bool {
+        self.command_id == other.command_id
+    }
+}
+
+impl Eq for Command {}
+
+impl Hash for Command {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.command_id.hash(state);
+    }
+}
+
+// A row of the Commands table, every query that reads whole commands
+// reads them into this so they are all turned into a `Command` the same way.
+// `query_as!` needs a field for every column, including those a `Command`
+// doesn't keep.
+#[allow(dead_code)]
+struct CommandRow {
+    command_id: i64,
+    robot_serial_number: String,
+    time_issued: chrono::DateTime<Utc>,
+    time_instruction: chrono::DateTime<Utc>,
+    instruction: Value,
+    completed: bool,
+    acknowledged_at: Option<chrono::DateTime<Utc>>,
+    escalated_at: Option<chrono::DateTime<Utc>>,
+    status: Option<String>,
+    status_detail: Option<String>,
+    cancelled: bool,
+    cancel_reason: Option<String>,
+    delivered_at: Option<chrono::DateTime<Utc>>,
+    shift_id: Option<String>,
+    version: i64,
+    resource: Option<String>,
+    callback_url: Option<String>,
+    priority: i16,
+    progress: Option<f64>,
+    issued_by: Option<String>,
+    deleted_at: Option<chrono::DateTime<Utc>>,
+    created_at: chrono::DateTime<Utc>,
+    idempotency_key: Option<String>,
+    completed_at: Option<chrono::DateTime<Utc>>,
+}
+
+// What came of trying to issue a command
+enum Issued {
+    Inserted(Box<CommandRow>),
+    // Another request with the same idempotency key got there first
+    Duplicate,
+    // The robot wasn't following the instruction it was expected to be
+    NotCurrent,
+}
+
+// What a command is stored with besides its instruction and times, most
+// commands leave all of these at the default
+#[derive(Debug, Clone, Copy, Default)]
+struct CommandOptions<'a> {
+    issued_by: Option<&'a str>,
+    idempotency_key: Option<&'a str>,
+    priority: i16,
+    callback_url: Option<&'a str>,
+    resource: Option<&'a str>,
+    // Issued by the server because the robot needs it to carry on, so it
+    // is never turned away by the rate limit or a full queue
+    urgent: bool,
+}
+
+// One of several commands issued together in a single transaction
+#[derive(Debug, Clone)]
+struct Issue<'a> {
+    robot_serial_number: &'a RobotSerial,
+    time_issued: chrono::DateTime<Utc>,
+    time_instruction: chrono::DateTime<Utc>,
+    instruction: Instruction,
+    options: CommandOptions<'a>,
+}
+
+impl Command {
+    // An instruction that can't be read is handled as the installed config
+    // says, the same as everywhere else commands are read
+    fn from_row(c: CommandRow) -> Result<Self, ApiError> {
+        Ok(Self {
+            command_id: c.command_id,
+            robot_serial_number: c.robot_serial_number,
+            time_issued: c.time_issued,
+            time_instruction: c.time_instruction,
+            instruction: read_instruction(c.command_id, c.instruction, CommandConfig::global())?,
+            completed: c.completed,
+            status: c.status.and_then(|s| serde_json::from_str(&s).ok()),
+            cancelled: c.cancelled,
+            acknowledged_at: c.acknowledged_at,
+            delivered_at: c.delivered_at,
+            shift_id: c.shift_id,
+            version: c.version,
+            resource: c.resource,
+            callback_url: c.callback_url,
+            priority: c.priority,
+            progress: c.progress,
+            issued_by: c.issued_by,
+            deleted_at: c.deleted_at,
+            completed_at: c.completed_at,
+        })
+    }
+}
+
+/// How many polls a robot made during a window compared to how many it
+/// should have made
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PollGap {
+    pub robot_serial_number: String,
+    pub expected_polls: i64,
+    pub recorded_polls: i64,
+    pub missing_polls: i64,
+}
+
+/// Which of a robot's commands `Command::search` returns, anything left
+/// empty matches every command
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstructionFilter {
+    /// The type of instruction, as named by `Instruction::kind`
+    pub kind: Option<String>,
+    /// Only tasks running this cleaning pattern
+    pub pattern: Option<CleaningPattern>,
+    /// Issued at or after this time
+    pub from: Option<chrono::DateTime<Utc>>,
+    /// Issued before this time
+    pub to: Option<chrono::DateTime<Utc>>,
+}
+
+/// How `Command::export` writes out a robot's commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One row per command with the instruction as a readable cell, for
+    /// spreadsheets
+    Csv,
+    /// The commands as a JSON array
+    Json,
 }
 
+/// The outcome of a command, as reported by the robot
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TaskStatus {
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(remote = "Self")]
 pub enum CleaningPattern {
     ZigZag,
     Circular,
+    Spiral,
+    Spot,
+    Edge,
+}
+
+/// The battery level a robot needs before it can start each cleaning
+/// pattern, patterns that aren't listed have no requirement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryRequirements(pub HashMap<CleaningPattern, i64>);
+
+impl Default for BatteryRequirements {
+    fn default() -> Self {
+        let mut minimums = HashMap::new();
+        minimums.insert(CleaningPattern::ZigZag, 50);
+        minimums.insert(CleaningPattern::Circular, 70);
+        minimums.insert(CleaningPattern::Spiral, 60);
+        minimums.insert(CleaningPattern::Spot, 30);
+        minimums.insert(CleaningPattern::Edge, 50);
+
+        Self(minimums)
+    }
+}
+
+impl BatteryRequirements {
+    /// Checks the battery level is enough to start the cleaning pattern
+    pub fn check(
+        &self,
+        cleaning_pattern: &CleaningPattern,
+        battery_level: i64,
+    ) -> Result<(), ApiError> {
+        match self.0.get(cleaning_pattern) {
+            Some(minimum) if battery_level < *minimum => {
+                Err(ApiError::InsufficientBatteryForPattern)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The power each cleaning pattern draws in watts, instructions that
+/// aren't cleaning are assumed to draw nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyCoefficients(pub HashMap<CleaningPattern, f64>);
+
+impl Default for EnergyCoefficients {
+    fn default() -> Self {
+        let mut watts = HashMap::new();
+        watts.insert(CleaningPattern::ZigZag, 30.0);
+        watts.insert(CleaningPattern::Circular, 45.0);
+        watts.insert(CleaningPattern::Spiral, 40.0);
+        watts.insert(CleaningPattern::Spot, 20.0);
+        watts.insert(CleaningPattern::Edge, 35.0);
+
+        Self(watts)
+    }
 }
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(remote = "Self")]
 pub enum AbortReason {
     LowBattery,
     Saftey,
@@ -32,217 +461,4028 @@ pub enum AbortReason {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(remote = "Self")]
 pub enum Instruction {
     Continue,
     Pause,
     Abort(AbortReason),
-    Task(CleaningPattern),
+    /// `speed` is a percentage of the robot's top speed and `passes` how
+    /// many times the pattern is run. A task without a `zone` covers
+    /// wherever the robot is, otherwise it cleans the named region.
+    Task {
+        pattern: CleaningPattern,
+        #[serde(default = "default_task_speed")]
+        speed: u8,
+        #[serde(default = "default_task_passes")]
+        passes: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        zone: Option<String>,
+    },
     Idle,
+    /// Sitting on the dock charging. Time spent docked doesn't count
+    /// against a command's instruction buffer.
+    Dock,
+    /// Travelling back to the dock, e.g. at the end of a cleaning run. The
+    /// robot isn't charging yet, so unlike `Dock` this time does count
+    /// against the instruction buffer.
+    ReturnToDock,
+    Teleop {
+        session_id: String,
+    },
+    RefreshConfig {
+        config_version: u64,
+    },
+}
+
+fn default_task_speed() -> u8 {
+    DEFAULT_TASK_SPEED
+}
+
+fn default_task_passes() -> u8 {
+    DEFAULT_TASK_PASSES
+}
+
+// Robot firmwares don't agree on how to write variant names, e.g.
+// `zigzag`, `ZigZag` or `ZIG_ZAG`, so they are matched ignoring case,
+// spaces, dashes and underscores before being deserialized.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Swaps a variant name, or the tag of an enum with data, for the
+// canonical spelling if it matches one of the variants
+fn canonical_variant(value: Value, variants: &[&str]) -> Value {
+    let canonical = |name: String| {
+        variants
+            .iter()
+            .find(|v| normalize(v) == normalize(&name))
+            .map(|v| v.to_string())
+            .unwrap_or(name)
+    };
+
+    match value {
+        Value::String(name) => Value::String(canonical(name)),
+        Value::Object(fields) if fields.len() == 1 => Value::Object(
+            fields
+                .into_iter()
+                .map(|(tag, data)| (canonical(tag), data))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+impl Serialize for CleaningPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CleaningPattern::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CleaningPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = canonical_variant(
+            Value::deserialize(deserializer)?,
+            &["ZigZag", "Circular", "Spiral", "Spot", "Edge"],
+        );
+
+        CleaningPattern::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for AbortReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        AbortReason::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AbortReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = canonical_variant(
+            Value::deserialize(deserializer)?,
+            &["LowBattery", "Saftey", "Obstacle"],
+        );
+
+        AbortReason::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Instruction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Instruction::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Instruction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = canonical_variant(
+            Value::deserialize(deserializer)?,
+            &[
+                "Continue",
+                "Pause",
+                "Abort",
+                "Task",
+                "Idle",
+                "Dock",
+                "ReturnToDock",
+                "Teleop",
+                "RefreshConfig",
+            ],
+        );
+
+        // Tasks used to be just the pattern, e.g. `{"Task": "ZigZag"}`
+        let value = match value {
+            Value::Object(mut fields) if matches!(fields.get("Task"), Some(Value::String(_))) => {
+                if let Some(pattern) = fields.remove("Task") {
+                    fields.insert("Task".to_string(), json!({ "pattern": pattern }));
+                }
+                Value::Object(fields)
+            }
+            other => other,
+        };
+
+        Instruction::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Instruction {
+    /// A task with the default speed and number of passes
+    pub fn task(pattern: CleaningPattern) -> Self {
+        Instruction::Task {
+            pattern,
+            speed: DEFAULT_TASK_SPEED,
+            passes: DEFAULT_TASK_PASSES,
+            zone: None,
+        }
+    }
+
+    /// Checks if the other instruction is close enough to this one
+    /// that the robot can carry on with what it is doing.
+    ///
+    /// A task's speed is allowed to differ by `tolerance`, given as a
+    /// fraction of this task's speed, any more than that is a new task.
+    /// Changing the pattern, the number of passes or the zone is always a
+    /// new task.
+    pub fn equivalent(&self, other: &Instruction, tolerance: f64) -> bool {
+        match (self, other) {
+            (
+                Instruction::Task {
+                    pattern,
+                    speed,
+                    passes,
+                    zone,
+                },
+                Instruction::Task {
+                    pattern: other_pattern,
+                    speed: other_speed,
+                    passes: other_passes,
+                    zone: other_zone,
+                },
+            ) => {
+                let drift = (f64::from(*speed) - f64::from(*other_speed)).abs();
+
+                pattern == other_pattern
+                    && passes == other_passes
+                    && zone == other_zone
+                    && drift <= tolerance * f64::from(*speed)
+            }
+            _ => self == other,
+        }
+    }
+
+    /// The name of the instruction without any of its parameters
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Instruction::Continue => "Continue",
+            Instruction::Pause => "Pause",
+            Instruction::Abort(_) => "Abort",
+            Instruction::Task { .. } => "Task",
+            Instruction::Idle => "Idle",
+            Instruction::Dock => "Dock",
+            Instruction::ReturnToDock => "ReturnToDock",
+            Instruction::Teleop { .. } => "Teleop",
+            Instruction::RefreshConfig { .. } => "RefreshConfig",
+        }
+    }
+
+    /// A one byte code for the instruction, for robots that can't handle
+    /// JSON
+    ///
+    /// Each variant has a range of codes to itself: `0x00..=0x0F` for the
+    /// instructions without parameters, `0x10..=0x1F` for aborts and
+    /// `0x20..=0x2F` for tasks, with the pattern in the low bits and the
+    /// speed and passes as the first two bytes of the payload followed by
+    /// the zone, if there is one, in UTF-8, `0x30..=0x3F` for
+    /// teleop, which carries its session in the payload, and `0x40..=0x4F`
+    /// for config refreshes, with the version in the payload as 8 big
+    /// endian bytes.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Instruction::Continue => 0x00,
+            Instruction::Pause => 0x01,
+            Instruction::Idle => 0x02,
+            Instruction::Dock => 0x03,
+            Instruction::ReturnToDock => 0x04,
+            Instruction::Abort(AbortReason::LowBattery) => 0x10,
+            Instruction::Abort(AbortReason::Saftey) => 0x11,
+            Instruction::Abort(AbortReason::Obstacle) => 0x12,
+            Instruction::Task {
+                pattern: CleaningPattern::ZigZag,
+                ..
+            } => 0x20,
+            Instruction::Task {
+                pattern: CleaningPattern::Circular,
+                ..
+            } => 0x21,
+            Instruction::Task {
+                pattern: CleaningPattern::Spiral,
+                ..
+            } => 0x22,
+            Instruction::Task {
+                pattern: CleaningPattern::Spot,
+                ..
+            } => 0x23,
+            Instruction::Task {
+                pattern: CleaningPattern::Edge,
+                ..
+            } => 0x24,
+            Instruction::Teleop { .. } => 0x30,
+            Instruction::RefreshConfig { .. } => 0x40,
+        }
+    }
+
+    /// The bytes sent after the opcode, empty unless the instruction has
+    /// parameters that don't fit in the opcode
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            Instruction::Teleop { session_id } => session_id.as_bytes().to_vec(),
+            Instruction::RefreshConfig { config_version } => config_version.to_be_bytes().to_vec(),
+            Instruction::Task {
+                speed,
+                passes,
+                zone,
+                ..
+            } => {
+                let mut payload = vec![*speed, *passes];
+                payload.extend(zone.iter().flat_map(|zone| zone.as_bytes()));
+                payload
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Reads an instruction back from its opcode and payload
+    pub fn from_opcode(opcode: u8, payload: &[u8]) -> Result<Instruction, ApiError> {
+        match opcode {
+            0x00 => Ok(Instruction::Continue),
+            0x01 => Ok(Instruction::Pause),
+            0x02 => Ok(Instruction::Idle),
+            0x03 => Ok(Instruction::Dock),
+            0x04 => Ok(Instruction::ReturnToDock),
+            0x10 => Ok(Instruction::Abort(AbortReason::LowBattery)),
+            0x11 => Ok(Instruction::Abort(AbortReason::Saftey)),
+            0x12 => Ok(Instruction::Abort(AbortReason::Obstacle)),
+            0x20..=0x24 => {
+                let pattern = match opcode {
+                    0x20 => CleaningPattern::ZigZag,
+                    0x21 => CleaningPattern::Circular,
+                    0x22 => CleaningPattern::Spiral,
+                    0x23 => CleaningPattern::Spot,
+                    _ => CleaningPattern::Edge,
+                };
+
+                // Robots that don't send the parameters get the defaults
+                match payload {
+                    [] => Ok(Instruction::task(pattern)),
+                    [speed, passes] => Ok(Instruction::Task {
+                        pattern,
+                        speed: *speed,
+                        passes: *passes,
+                        zone: None,
+                    }),
+                    [speed, passes, zone @ ..] => std::str::from_utf8(zone)
+                        .map(|zone| Instruction::Task {
+                            pattern,
+                            speed: *speed,
+                            passes: *passes,
+                            zone: Some(zone.to_string()),
+                        })
+                        .map_err(|_| ApiError::InvalidOpcode),
+                    _ => Err(ApiError::InvalidOpcode),
+                }
+            }
+            0x30 => String::from_utf8(payload.to_vec())
+                .map(|session_id| Instruction::Teleop { session_id })
+                .map_err(|_| ApiError::InvalidOpcode),
+            0x40 => {
+                let mut version = [0; 8];
+                if payload.len() != version.len() {
+                    return Err(ApiError::InvalidOpcode);
+                }
+                version.copy_from_slice(payload);
+
+                Ok(Instruction::RefreshConfig {
+                    config_version: u64::from_be_bytes(version),
+                })
+            }
+            _ => Err(ApiError::InvalidOpcode),
+        }
+    }
 }
 
 impl Command {
+    /// Issues the instruction to the robot, `issued_by` is who asked for it
+    /// and is left empty for commands the server issues itself.
+    ///
+    /// A command issued with an `idempotency_key` the robot has already
+    /// seen isn't issued again, the command made the first time is returned
+    /// so clients can safely retry.
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         conn: &PgPool,
-        robot_serial_number: &str,
+        robot_serial_number: &RobotSerial,
         time_issued: chrono::DateTime<Utc>,
         time_instruction: chrono::DateTime<Utc>,
         instruction: &Instruction,
+        config: &CommandConfig,
+        issued_by: Option<&str>,
+        idempotency_key: Option<&str>,
     ) -> Result<Command, ApiError> {
-        // Check that the commands was given within the
-        //   time buffer
-        let time_difference = (chrono::Utc::now() - time_issued).num_seconds().abs();
-        if time_difference > TIME_ISSUED_BUFFER {
-            println!(
-                "Error: Outside of the time buffer\nTime Diff: {}",
-                time_difference
-            );
-            return Err(ApiError::CommandNotInTimeIssuedBuffer);
-        }
-
-        let instruction_json = serde_json::to_string(instruction).map_err(|e| {
-            println!("Instrution Json: {:?}", e);
-            ApiError::SerializationError
-        })?;
-
-        let command_id = sqlx::query!(
-            r#"
-        INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
-        VALUES ( $1, $2, $3, $4 )
-        RETURNING command_id
-                "#,
+        Command::new_with_options(
+            conn,
             robot_serial_number,
             time_issued,
             time_instruction,
-            instruction_json
+            instruction,
+            config,
+            &CommandOptions {
+                issued_by,
+                idempotency_key,
+                ..CommandOptions::default()
+            },
         )
-        .fetch_one(conn)
         .await
-        .map_err(|e| {
-            println!("Command New: {:?}", e);
-            ApiError::DatabaseConnFailed
-        })?
-        .command_id;
+    }
+
+    // Issues the command with everything it is stored with, going through
+    // the same checks as `new`
+    async fn new_with_options(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        config: &CommandConfig,
+        options: &CommandOptions<'_>,
+    ) -> Result<Command, ApiError> {
+        // A retry is answered before the checks, which it may no longer pass
+        if let Some(idempotency_key) = options.idempotency_key {
+            if let Some(existing) =
+                Command::by_idempotency_key(conn, robot_serial_number, idempotency_key).await?
+            {
+                return Ok(existing);
+            }
+        }
 
-        let robot_serial_number = robot_serial_number.to_string();
+        Command::check_time_issued(time_issued, config)?;
 
-        Ok(Self {
-            command_id,
+        // Nothing is expected of the robot, so the command is always issued
+        Command::issue(
+            conn,
             robot_serial_number,
             time_issued,
             time_instruction,
-            instruction: instruction.clone(),
-            completed: false,
-        })
+            instruction,
+            config,
+            options,
+            None,
+        )
+        .await?
+        .ok_or(ApiError::NotFound)
     }
 
-    // Get the current task the robot is doing
-    pub async fn current(conn: &PgPool, robot_serial_number: &str) -> Result<Self, ApiError> {
-        sqlx::query!(
-            r#"
-SELECT * FROM Commands C
-NATURAL JOIN
-(SELECT MAX(C1.time_issued) AS time_issued,
-        $1 AS robot_serial_number
-FROM Commands C1
-WHERE C1.robot_serial_number = $1) MaxTimeIssued
-               "#,
-            robot_serial_number
+    /// Issues a command the server needs the robot to act on, such as a
+    /// safety abort or the idle a poll falls back to
+    ///
+    /// The server picks the times itself so they aren't checked against the
+    /// time buffer, and the command is never turned away by the robot's
+    /// rate limit or a full queue. Aborts go in ahead of anything already
+    /// queued.
+    pub async fn new_unbuffered(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+    ) -> Result<Command, ApiError> {
+        let priority = match instruction {
+            Instruction::Abort(_) => ABORT_PRIORITY,
+            _ => 0,
+        };
+
+        Command::issue(
+            conn,
+            robot_serial_number,
+            time_issued,
+            time_instruction,
+            instruction,
+            CommandConfig::global(),
+            &CommandOptions {
+                priority,
+                urgent: true,
+                ..CommandOptions::default()
+            },
+            None,
         )
-        .fetch_one(conn)
-        .await
-        .map(|cmd| Self {
-            command_id: cmd.command_id,
-            robot_serial_number: cmd.robot_serial_number,
-            time_issued: cmd.time_issued,
-            time_instruction: cmd.time_issued,
-            instruction: serde_json::from_str(&cmd.instruction)
-                .unwrap_or(Instruction::Abort(AbortReason::Saftey)),
-            completed: cmd.completed,
-        })
-        .map_err(|e| {
-            println!("Command Latest: {:?}", e);
-            ApiError::DatabaseConnFailed
-        })
+        .await?
+        .ok_or(ApiError::NotFound)
     }
 
-    /// Checks to see if there are any pending command for this robot
-    pub async fn pending(conn: &PgPool, robot_serial_number: &str) -> Result<Command, ApiError> {
-        let pending_commands = sqlx::query!(
-            r#"
-SELECT * FROM Commands C
-WHERE C.robot_serial_number = $1 AND
-      C.completed = false
-ORDER BY C.time_instruction DESC
-               "#,
-            robot_serial_number
-        )
-        .fetch_all(conn)
-        .await
-        .map(|cmds| {
-            let mut pending = Vec::new();
-
-            for c in cmds {
-                pending.push(Self {
-                    command_id: c.command_id,
-                    robot_serial_number: c.robot_serial_number,
-                    time_issued: c.time_issued,
-                    time_instruction: c.time_issued,
-                    instruction: serde_json::from_str(&c.instruction)
-                        .unwrap_or(Instruction::Abort(AbortReason::Saftey)),
-                    completed: c.completed,
-                })
+    // Checks the robot's rate limit and queue, then inserts the command, all
+    // in one transaction holding a lock on the robot so concurrent requests
+    // are checked one after another. Aborts skip both checks.
+    //
+    // With an `expected` instruction nothing is issued, and `None` is
+    // returned, unless the robot is currently following it.
+    #[allow(clippy::too_many_arguments)]
+    async fn issue(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        config: &CommandConfig,
+        options: &CommandOptions<'_>,
+        expected: Option<&Instruction>,
+    ) -> Result<Option<Command>, ApiError> {
+        let instruction_json = &serde_json::to_value(instruction).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        let attempt = || async move {
+            let mut tx = conn.begin().await.map_err(|e| {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Command New");
+                ApiError::from(e)
+            })?;
+            Command::lock_robot(&mut tx, robot_serial_number).await?;
+
+            // An attempt retried after the one before it was committed finds
+            // the command that attempt made
+            if let Some(idempotency_key) = options.idempotency_key {
+                if Command::by_idempotency_key(&mut tx, robot_serial_number, idempotency_key)
+                    .await?
+                    .is_some()
+                {
+                    return Ok(Issued::Duplicate);
+                }
             }
 
-            println!("Pending Commands {:?}", pending);
+            if let Some(expected) = expected {
+                let current = match Command::latest(&mut tx, robot_serial_number).await {
+                    Ok(current) => Some(current.instruction),
+                    Err(ApiError::NoCommandsForRobot) => None,
+                    Err(e) => return Err(e),
+                };
+                if current.as_ref() != Some(expected) {
+                    return Ok(Issued::NotCurrent);
+                }
+            }
 
-            pending
-        })
-        .map_err(|_| ApiError::DatabaseConnFailed)?;
+            let inserted = Command::check_and_insert(
+                &mut tx,
+                robot_serial_number,
+                time_issued,
+                time_instruction,
+                instruction,
+                instruction_json,
+                config,
+                options,
+            )
+            .await?;
+
+            tx.commit().await.map_err(|e| {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Command New");
+                ApiError::from(e)
+            })?;
+
+            #[cfg(test)]
+            if LOSE_CONNECTION_AFTER_COMMIT.with(|lose| lose.replace(lose.get().saturating_sub(1)))
+                > 0
+            {
+                return Err(ApiError::ConnectionLost);
+            }
+
+            Ok(match inserted {
+                Some(inserted) => Issued::Inserted(Box::new(inserted)),
+                None => Issued::Duplicate,
+            })
+        };
+
+        // A connection that drops may have lost the reply to a commit that
+        // went through, so only a command with a key, which stops it being
+        // inserted twice, is tried again
+        let issued = match options.idempotency_key {
+            Some(_) => with_retry(&config.retry, attempt).await?,
+            None => attempt().await?,
+        };
+
+        let inserted = match (issued, options.idempotency_key) {
+            (Issued::Inserted(inserted), _) => *inserted,
+            (Issued::NotCurrent, _) => return Ok(None),
+            (Issued::Duplicate, Some(idempotency_key)) => {
+                return Command::by_idempotency_key(conn, robot_serial_number, idempotency_key)
+                    .await?
+                    .ok_or(ApiError::NotFound)
+                    .map(Some);
+            }
+            // Without a key there is nothing the insert can conflict on
+            (Issued::Duplicate, None) => return Err(ApiError::NotFound),
+        };
+
+        Command::announce(inserted).map(Some)
+    }
+
+    // Issues the commands in one transaction, each going through the same
+    // checks as a command issued on its own with `issue`. If any of them
+    // is turned away none of them are issued.
+    async fn issue_all(
+        conn: &PgPool,
+        commands: &[Issue<'_>],
+        config: &CommandConfig,
+    ) -> Result<Vec<Command>, ApiError> {
+        let mut tx = conn.begin().await.map_err(|e| {
+            error!(error = ?e, "Command Issue All");
+            ApiError::from(e)
+        })?;
+
+        let inserted = Command::issue_in(&mut tx, commands, config).await?;
+
+        tx.commit().await.map_err(|e| {
+            error!(error = ?e, "Command Issue All");
+            ApiError::from(e)
+        })?;
+
+        inserted.into_iter().map(Command::announce).collect()
+    }
+
+    // Locks every robot the commands are for then checks and inserts them
+    // one after another, for callers that have more to do in the same
+    // transaction. The rows are announced once it commits.
+    async fn issue_in(
+        tx: &mut Transaction<'_, Postgres>,
+        commands: &[Issue<'_>],
+        config: &CommandConfig,
+    ) -> Result<Vec<CommandRow>, ApiError> {
+        // Always locked in the same order, so two batches for the same
+        // robots can't each wait on the other
+        let mut robots: Vec<&RobotSerial> =
+            commands.iter().map(|c| c.robot_serial_number).collect();
+        robots.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        robots.dedup();
+        for robot_serial_number in robots {
+            Command::lock_robot(tx, robot_serial_number).await?;
+        }
+
+        let mut inserted = Vec::new();
+        for command in commands {
+            let instruction_json = serde_json::to_value(&command.instruction).map_err(|e| {
+                error!(robot_serial_number = %command.robot_serial_number, error = ?e, "Instrution Json");
+                ApiError::SerializationError
+            })?;
 
-        match pending_commands.get(0) {
-            Some(cmd) if cmd.valid_time_instruction() => Ok(cmd.clone()),
-            _ => Command::idle(conn, robot_serial_number).await,
+            let row = Command::check_and_insert(
+                tx,
+                command.robot_serial_number,
+                command.time_issued,
+                command.time_instruction,
+                &command.instruction,
+                &instruction_json,
+                config,
+                &command.options,
+            )
+            .await?
+            // Only a command with a key can conflict, which these never have
+            .ok_or(ApiError::NotFound)?;
+            inserted.push(row);
         }
+
+        Ok(inserted)
     }
 
-    pub async fn complete(&self, conn: &PgPool) -> Result<(), ApiError> {
+    // Takes the robot's lock until the transaction ends, so commands for
+    // the robot are checked and inserted one request at a time
+    async fn lock_robot(
+        tx: &mut Transaction<'_, Postgres>,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<(), ApiError> {
         sqlx::query!(
             r#"
-UPDATE Commands C
-SET completed = true
-WHERE C.command_id= $1
+SELECT 1 AS locked FROM pg_advisory_xact_lock(hashtext($1))
                "#,
-            self.command_id
+            robot_serial_number.as_str()
         )
-        .execute(conn)
+        .fetch_one(tx)
         .await
         .map_err(|e| {
-            println!("Command Latest: {:?}", e);
-            ApiError::DatabaseConnFailed
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Lock Robot");
+            ApiError::from(e)
         })?;
 
         Ok(())
     }
 
-    pub fn valid_time_instruction(&self) -> bool {
-        let time_difference = (chrono::Utc::now() - self.time_instruction)
-            .num_seconds()
-            .abs();
+    // Checks the robot's rate limit and queue then inserts the command, in
+    // a transaction that already holds the robot's lock. Aborts and urgent
+    // commands skip both checks.
+    #[allow(clippy::too_many_arguments)]
+    async fn check_and_insert(
+        tx: &mut Transaction<'_, Postgres>,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        instruction_json: &Value,
+        config: &CommandConfig,
+        options: &CommandOptions<'_>,
+    ) -> Result<Option<CommandRow>, ApiError> {
+        if !matches!(instruction, Instruction::Abort(_)) && !options.urgent {
+            Command::check_rate_limit(tx, robot_serial_number, config).await?;
+            Command::make_room(tx, robot_serial_number, config).await?;
+        }
 
-        time_difference < TIME_INSTRUCTION_BUFFER
+        Command::insert(
+            tx,
+            robot_serial_number,
+            time_issued,
+            time_instruction,
+            instruction_json,
+            options,
+        )
+        .await
     }
-}
 
-impl Command {
-    // Abort the current task with the given reason
-    pub async fn abort(
-        conn: &PgPool,
-        robot_serial_number: &str,
+    // Counts a newly committed command and lets anyone listening for the
+    // robot's commands know about it
+    fn announce(inserted: CommandRow) -> Result<Command, ApiError> {
+        let command = Command::from_row(inserted)?;
+        metrics::record_command_issued(command.instruction.kind());
+        push::publish(&command.robot_serial_number, command.command_id);
+
+        Ok(command)
+    }
+
+    // When two requests share a key the constraint lets only one of them
+    // insert, the other gets `None`
+    async fn insert(
+        tx: &mut Transaction<'_, Postgres>,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction_json: &Value,
+        options: &CommandOptions<'_>,
+    ) -> Result<Option<CommandRow>, ApiError> {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction,
+                      issued_by, idempotency_key, priority, callback_url,
+                      resource)
+VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9 )
+ON CONFLICT (robot_serial_number, idempotency_key) DO NOTHING
+RETURNING *
+               "#,
+            robot_serial_number.as_str(),
+            time_issued,
+            time_instruction,
+            instruction_json,
+            options.issued_by,
+            options.idempotency_key,
+            options.priority,
+            options.callback_url,
+            options.resource
+        )
+        .fetch_optional(tx)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command New");
+            ApiError::from(e)
+        })
+    }
+
+    // The command the robot was issued with the key, deleted or not
+    async fn by_idempotency_key<'e, E>(
+        conn: E,
+        robot_serial_number: &str,
+        idempotency_key: &str,
+    ) -> Result<Option<Self>, ApiError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        let c = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.idempotency_key = $2
+               "#,
+            robot_serial_number,
+            idempotency_key
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command By Idempotency Key");
+            ApiError::from(e)
+        })?;
+
+        c.map(Command::from_row).transpose()
+    }
+
+    /// Issues the instruction only if the robot is currently following the
+    /// expected one, returning `None` when it isn't.
+    ///
+    /// The check and the insert happen in one transaction holding a lock on
+    /// the robot, so two operators can't race each other.
+    pub async fn new_if_current(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        expected: &Instruction,
+        instruction: &Instruction,
+    ) -> Result<Option<Command>, ApiError> {
+        let time_now = chrono::Utc::now();
+        let config = CommandConfig::global();
+        Command::check_time_issued(time_now, config)?;
+
+        Command::issue(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            instruction,
+            config,
+            &CommandOptions::default(),
+            Some(expected),
+        )
+        .await
+    }
+
+    /// Inserts several commands in a single transaction
+    ///
+    /// Each command is checked against the time buffer and the robot's
+    /// rate limit and queue, if any of them fail none of the commands are
+    /// inserted.
+    pub async fn new_group(
+        conn: &PgPool,
+        commands: &[(
+            RobotSerial,
+            chrono::DateTime<Utc>,
+            chrono::DateTime<Utc>,
+            Instruction,
+        )],
+    ) -> Result<Vec<Command>, ApiError> {
+        let mut group = Vec::new();
+
+        for (robot_serial_number, time_issued, time_instruction, instruction) in commands {
+            Command::check_time_issued(*time_issued, CommandConfig::global())?;
+
+            group.push(Issue {
+                robot_serial_number,
+                time_issued: *time_issued,
+                time_instruction: *time_instruction,
+                instruction: instruction.clone(),
+                options: CommandOptions::default(),
+            });
+        }
+
+        Command::issue_all(conn, &group, CommandConfig::global()).await
+    }
+
+    /// Issues each robot its instruction straight away, for starting a
+    /// whole fleet at once
+    ///
+    /// The commands are inserted together, so either every robot gets its
+    /// command or, if any of them is turned away, none of them do.
+    pub async fn new_batch(
+        conn: &PgPool,
+        commands: &[(&RobotSerial, Instruction)],
+    ) -> Result<Vec<Command>, ApiError> {
+        let time_now = chrono::Utc::now();
+        let commands: Vec<_> = commands
+            .iter()
+            .map(|(robot_serial_number, instruction)| {
+                (
+                    (*robot_serial_number).clone(),
+                    time_now,
+                    time_now,
+                    instruction.clone(),
+                )
+            })
+            .collect();
+
+        Command::new_group(conn, &commands).await
+    }
+
+    // Check that the commands was given within the
+    //   time buffer
+    fn check_time_issued(
+        time_issued: chrono::DateTime<Utc>,
+        config: &CommandConfig,
+    ) -> Result<(), ApiError> {
+        let time_difference = elapsed(chrono::Utc::now(), time_issued);
+        if time_difference > config.time_issued_buffer {
+            debug!(
+                time_difference_secs = time_difference.num_seconds(),
+                "Command outside of the time issued buffer"
+            );
+            return Err(ApiError::CommandNotInTimeIssuedBuffer);
+        }
+
+        Ok(())
+    }
+
+    // Turns the command away if the robot has already been issued as many
+    // as it's allowed within the window. When the rows were written is used
+    // rather than the time issued, which the client picks.
+    async fn check_rate_limit(
+        tx: &mut Transaction<'_, Postgres>,
+        robot_serial_number: &str,
+        config: &CommandConfig,
+    ) -> Result<(), ApiError> {
+        let recent = sqlx::query!(
+            r#"
+SELECT COUNT(*) AS "recent!" FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.created_at > $2
+               "#,
+            robot_serial_number,
+            Utc::now() - config.rate_limit_window
+        )
+        .fetch_one(tx)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Rate Limit");
+            ApiError::from(e)
+        })?
+        .recent;
+
+        if recent >= config.rate_limit {
+            warn!(robot_serial_number = %robot_serial_number, recent, "Command Rate Limited");
+            return Err(ApiError::RateLimited);
+        }
+
+        Ok(())
+    }
+
+    // Refuses the command if the robot has a full queue, or in replace mode
+    // cancels its oldest waiting commands until there's room. Only commands
+    // that haven't been delivered are queued, so the one the robot is
+    // running is never counted or cancelled. Aborts are never cancelled
+    // either, if they are all that's waiting the queue stays full.
+    async fn make_room(
+        tx: &mut Transaction<'_, Postgres>,
+        robot_serial_number: &str,
+        config: &CommandConfig,
+    ) -> Result<(), ApiError> {
+        let pending = sqlx::query!(
+            r#"
+SELECT COUNT(*) AS "pending!" FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.delivered_at IS NULL AND
+      C.deleted_at IS NULL
+               "#,
+            robot_serial_number
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Queue Depth");
+            ApiError::from(e)
+        })?
+        .pending;
+
+        if pending < config.max_queue_depth {
+            return Ok(());
+        }
+        if config.queue_mode == QueueMode::Reject {
+            warn!(robot_serial_number = %robot_serial_number, pending, "Command Queue Full");
+            return Err(ApiError::QueueFull);
+        }
+
+        let replaced = sqlx::query!(
+            r#"
+WITH Replaced AS (
+    UPDATE Commands C
+    SET version = version + 1,
+        completed = true,
+        completed_at = COALESCE(C.completed_at, now()),
+        cancelled = true,
+        cancel_reason = 'queue_full'
+    WHERE C.command_id IN (
+        SELECT O.command_id FROM Commands O
+        WHERE O.robot_serial_number = $1 AND
+              O.completed = false AND
+              O.delivered_at IS NULL AND
+              O.deleted_at IS NULL AND
+              NOT O.instruction ? 'Abort'
+        ORDER BY O.time_issued, O.command_id
+        LIMIT $2
+    )
+    RETURNING C.command_id
+), Released AS (
+    DELETE FROM resource_locks L
+    WHERE L.command_id IN (SELECT command_id FROM Replaced)
+)
+SELECT COUNT(*) AS "replaced!" FROM Replaced
+               "#,
+            robot_serial_number,
+            pending - config.max_queue_depth + 1
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Queue Replace");
+            ApiError::from(e)
+        })?
+        .replaced;
+
+        if replaced < pending - config.max_queue_depth + 1 {
+            warn!(robot_serial_number = %robot_serial_number, pending, "Command Queue Full");
+            return Err(ApiError::QueueFull);
+        }
+
+        Ok(())
+    }
+
+    // Get the current task the robot is doing, or NoCommandsForRobot if it
+    // has never been issued one. Of commands issued at the same time the
+    // last one inserted wins.
+    pub async fn current(conn: &PgPool, robot_serial_number: &str) -> Result<Self, ApiError> {
+        Command::latest(conn, robot_serial_number).await
+    }
+
+    // The query behind `current`, which is also run inside the transaction
+    // a command is issued in
+    async fn latest<'e, E>(conn: E, robot_serial_number: &str) -> Result<Self, ApiError>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued DESC, C.command_id DESC
+LIMIT 1
+               "#,
+            robot_serial_number
+        )
+        .fetch_one(conn)
+        .await
+        .map_err(|e| match e {
+            // The robot hasn't been issued anything, which isn't a failure
+            sqlx::Error::RowNotFound => ApiError::NoCommandsForRobot,
+            e => {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Latest");
+                ApiError::from(e)
+            }
+        })
+        .and_then(Command::from_row)
+    }
+
+    /// Finds the command the robot was following at the given time
+    ///
+    /// This is the latest command to take effect at or before `at`,
+    /// ignoring commands that had not been issued yet.
+    pub async fn active_at(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        at: chrono::DateTime<Utc>,
+    ) -> Result<Option<Self>, ApiError> {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.time_instruction <= $2 AND
+      C.time_issued <= $2 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_instruction DESC, C.time_issued DESC, C.command_id DESC
+LIMIT 1
+               "#,
+            robot_serial_number,
+            at
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Active At");
+            ApiError::from(e)
+        })?
+        .map(Command::from_row)
+        .transpose()
+    }
+
+    /// Checks to see if there are any pending command for this robot
+    ///
+    /// The candidates are fetched one at a time, the next is only read if
+    /// the resource of the one before was just taken by another robot. If
+    /// a candidate is outside of the instruction buffer the robot falls
+    /// back to idle.
+    pub async fn pending(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Command, ApiError> {
+        let config = CommandConfig::global();
+        Command::expire_stale(conn, robot_serial_number).await?;
+
+        // Candidates whose resource was taken before they could be locked
+        let mut passed_over: Vec<i64> = Vec::new();
+        loop {
+            let candidate = sqlx::query_as!(
+                CommandRow,
+                r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.deleted_at IS NULL AND
+      C.time_instruction <= $2 AND
+      C.command_id <> ALL($3) AND
+      NOT EXISTS (
+          SELECT 1 FROM resource_locks L
+          WHERE L.resource = C.resource AND
+                L.command_id <> C.command_id
+      )
+ORDER BY C.priority DESC, C.time_instruction DESC
+LIMIT 1
+               "#,
+                robot_serial_number.as_str(),
+                // Commands scheduled for later stay hidden until they are due
+                Utc::now() + config.time_instruction_buffer,
+                &passed_over
+            )
+            .fetch_optional(conn)
+            .await
+            .map_err(|e| {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Pending");
+                ApiError::from(e)
+            })?
+            .map(Command::from_row)
+            .transpose()?;
+
+            let cmd = match candidate {
+                Some(cmd) => cmd,
+                None => break,
+            };
+            debug!(robot_serial_number = %robot_serial_number, ?cmd, "Pending Command");
+
+            let charging =
+                Command::charging_intervals(conn, robot_serial_number, cmd.time_instruction)
+                    .await?;
+            if !cmd.valid_time_instruction(&charging, config) {
+                break;
+            }
+            if cmd.lock_resource(conn).await? {
+                return Ok(cmd);
+            }
+            passed_over.push(cmd.id());
+        }
+
+        Command::fallback(conn, robot_serial_number).await
+    }
+
+    /// Completes the robot's commands that were never delivered and are now
+    /// outside of the instruction buffer, returning how many were expired
+    ///
+    /// Time the robot spent charging doesn't count, the same as for
+    /// `valid_time_instruction`, so nothing `pending` could still return is
+    /// expired.
+    pub async fn expire_stale(conn: &PgPool, robot_serial_number: &str) -> Result<u64, ApiError> {
+        let config = CommandConfig::global();
+        let now = Utc::now();
+        let candidates = sqlx::query!(
+            r#"
+SELECT command_id, time_instruction FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.delivered_at IS NULL AND
+      C.deleted_at IS NULL AND
+      C.time_instruction < $2
+ORDER BY C.time_instruction
+               "#,
+            robot_serial_number,
+            now - config.time_instruction_buffer
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Stale");
+            ApiError::from(e)
+        })?;
+
+        let since = match candidates.first() {
+            Some(c) => c.time_instruction,
+            None => return Ok(0),
+        };
+        let charging = Command::charging_intervals(conn, robot_serial_number, since).await?;
+
+        let stale: Vec<i64> = candidates
+            .into_iter()
+            .filter(|c| !within_time_instruction_buffer(c.time_instruction, &charging, now, config))
+            .map(|c| c.command_id)
+            .collect();
+
+        let expired = sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now())
+WHERE C.command_id = ANY($1) AND
+      C.completed = false
+RETURNING C.command_id
+               "#,
+            &stale
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Expire Stale");
+            ApiError::from(e)
+        })?;
+
+        for c in &expired {
+            Command::release_resource(conn, c.command_id).await?;
+        }
+
+        Ok(expired.len() as u64)
+    }
+
+    pub async fn complete(&self, conn: &PgPool) -> Result<(), ApiError> {
+        let newly_completed = with_retry(&CommandConfig::global().retry, || async move {
+            sqlx::query!(
+                r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now())
+WHERE C.command_id= $1 AND
+      C.completed = false
+RETURNING C.version, C.completed_at
+               "#,
+                self.command_id
+            )
+            .fetch_optional(conn)
+            .await
+            .map_err(|e| {
+                error!(command_id = self.command_id, error = ?e, "Command Complete");
+                ApiError::from(e)
+            })
+        })
+        .await?;
+
+        if let Some(row) = newly_completed {
+            metrics::record_command_completed();
+            Self {
+                completed: true,
+                version: row.version,
+                completed_at: row.completed_at,
+                ..self.clone()
+            }
+            .send_callback(conn);
+        }
+
+        Command::release_resource(conn, self.command_id).await
+    }
+
+    /// Completes the command with the outcome reported by the robot
+    ///
+    /// Robots can only report on their own commands, a command of another
+    /// robot is a `SerialMismatch` and one that doesn't exist is
+    /// `NotFound`.
+    pub async fn complete_with_status(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        command_id: i64,
+        status: &TaskStatus,
+        detail: Option<&str>,
+    ) -> Result<(), ApiError> {
+        let status_json = &serde_json::to_string(status).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, command_id, error = ?e, "Status Json");
+            ApiError::SerializationError
+        })?;
+
+        let result = with_retry(&CommandConfig::global().retry, || async move {
+            sqlx::query!(
+                r#"
+WITH Previous AS (
+    SELECT completed FROM Commands
+    WHERE command_id = $1
+)
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now()),
+    status = $3,
+    status_detail = $4
+WHERE C.command_id = $1 AND
+      C.robot_serial_number = $2
+RETURNING C.command_id, (SELECT P.completed FROM Previous P) AS "was_completed!"
+               "#,
+                command_id,
+                robot_serial_number,
+                status_json,
+                detail
+            )
+            .fetch_optional(conn)
+            .await
+            .map_err(|e| {
+                error!(
+                    robot_serial_number = %robot_serial_number,
+                    command_id, error = ?e, "Command Status"
+                );
+                ApiError::from(e)
+            })
+        })
+        .await?;
+
+        let c = match result {
+            Some(c) => c,
+            None => {
+                let owner = sqlx::query!(
+                    r#"
+SELECT C.robot_serial_number FROM Commands C
+WHERE C.command_id = $1
+                   "#,
+                    command_id
+                )
+                .fetch_optional(conn)
+                .await
+                .map_err(|e| {
+                    error!(
+                        robot_serial_number = %robot_serial_number,
+                        command_id, error = ?e, "Command Status"
+                    );
+                    ApiError::from(e)
+                })?;
+
+                return Err(match owner {
+                    Some(_) => ApiError::SerialMismatch,
+                    None => ApiError::NotFound,
+                });
+            }
+        };
+
+        if !c.was_completed {
+            metrics::record_command_completed();
+            Command::get_including_deleted(conn, c.command_id)
+                .await?
+                .send_callback(conn);
+        }
+
+        Command::release_resource(conn, command_id).await
+    }
+
+    /// Cancels a single command that the robot has not completed yet
+    pub async fn cancel_by_id(
+        conn: &PgPool,
+        command_id: i64,
+        reason: String,
+    ) -> Result<(), ApiError> {
+        Command::cancel_command(conn, command_id, None, reason).await
+    }
+
+    /// Cancels the command only if it is still at the expected version,
+    /// otherwise someone else has changed it and `VersionConflict` is
+    /// returned.
+    pub async fn cancel_at_version(
+        conn: &PgPool,
+        command_id: i64,
+        expected_version: i64,
+        reason: String,
+    ) -> Result<(), ApiError> {
+        Command::cancel_command(conn, command_id, Some(expected_version), reason).await
+    }
+
+    /// Cancels the command so the robot is never given it, cancelling a
+    /// command that has already been completed does nothing
+    pub async fn cancel(&self, conn: &PgPool) -> Result<(), ApiError> {
+        let result = with_retry(&CommandConfig::global().retry, || async move {
+            sqlx::query!(
+                r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now()),
+    cancelled = true,
+    cancel_reason = 'cancelled'
+WHERE C.command_id = $1 AND
+      C.completed = false
+               "#,
+                self.command_id
+            )
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                error!(command_id = self.command_id, error = ?e, "Command Cancel");
+                ApiError::from(e)
+            })
+        })
+        .await?;
+
+        if result.rows_affected() > 0 {
+            Command::release_resource(conn, self.command_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Cancels every command the robot hasn't completed yet, returning how
+    /// many were cancelled
+    pub async fn cancel_pending(conn: &PgPool, robot_serial_number: &str) -> Result<u64, ApiError> {
+        let cancelled = sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now()),
+    cancelled = true,
+    cancel_reason = 'cancelled'
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.deleted_at IS NULL
+RETURNING C.command_id
+               "#,
+            robot_serial_number
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Cancel Pending");
+            ApiError::from(e)
+        })?;
+
+        for c in &cancelled {
+            Command::release_resource(conn, c.command_id).await?;
+        }
+
+        Ok(cancelled.len() as u64)
+    }
+
+    async fn cancel_command(
+        conn: &PgPool,
+        command_id: i64,
+        expected_version: Option<i64>,
+        reason: String,
+    ) -> Result<(), ApiError> {
+        let current = sqlx::query!(
+            r#"
+SELECT completed, version FROM Commands C
+WHERE C.command_id = $1
+               "#,
+            command_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Cancel");
+            ApiError::from(e)
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        if current.completed {
+            return Err(ApiError::CannotCancelCompleted);
+        }
+
+        if expected_version.is_some_and(|v| v != current.version) {
+            return Err(ApiError::VersionConflict);
+        }
+
+        // Only cancel if it wasn't changed in the meantime
+        let result = sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now()),
+    cancelled = true,
+    cancel_reason = $2
+WHERE C.command_id = $1 AND
+      C.completed = false AND
+      C.version = $3
+               "#,
+            command_id,
+            reason,
+            current.version
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Cancel");
+            ApiError::from(e)
+        })?;
+
+        match (result.rows_affected(), expected_version) {
+            (0, Some(_)) => Err(ApiError::VersionConflict),
+            (0, None) => Err(ApiError::CannotCancelCompleted),
+            _ => Command::release_resource(conn, command_id).await,
+        }
+    }
+
+    /// Issues a command that POSTs itself to the given URL once it has been
+    /// completed
+    pub async fn new_with_callback(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        callback_url: &str,
+    ) -> Result<Command, ApiError> {
+        check_callback_url(callback_url)?;
+
+        Command::new_with_options(
+            conn,
+            robot_serial_number,
+            time_issued,
+            time_instruction,
+            instruction,
+            CommandConfig::global(),
+            &CommandOptions {
+                callback_url: Some(callback_url),
+                ..CommandOptions::default()
+            },
+        )
+        .await
+    }
+
+    /// Issues a command with a priority, while the robot is running a task
+    /// a command with a higher priority than it takes over and the task is
+    /// resumed afterwards
+    pub async fn new_with_priority(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        priority: i16,
+    ) -> Result<Command, ApiError> {
+        Command::new_with_options(
+            conn,
+            robot_serial_number,
+            time_issued,
+            time_instruction,
+            instruction,
+            CommandConfig::global(),
+            &CommandOptions {
+                priority,
+                ..CommandOptions::default()
+            },
+        )
+        .await
+    }
+
+    /// Stores how far through the command it was last given the robot is
+    pub async fn record_progress(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        progress: f64,
+    ) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    progress = $2
+WHERE C.command_id = (
+    SELECT C1.command_id FROM Commands C1
+    WHERE C1.robot_serial_number = $1 AND
+          C1.delivered_at IS NOT NULL AND
+          C1.deleted_at IS NULL
+    ORDER BY C1.delivered_at DESC, C1.command_id DESC
+    LIMIT 1) AND
+      C.completed = false
+               "#,
+            robot_serial_number,
+            progress
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Record Progress");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Puts aside the task the robot is running if a command with a higher
+    /// priority is waiting, returning the command that takes over
+    ///
+    /// The task is stashed along with its progress so it can be resumed
+    /// once the preempting command completes, unless it is being aborted.
+    pub async fn preempt(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<Command>, ApiError> {
+        let running = match Command::last_delivered(conn, robot_serial_number).await? {
+            Some(
+                running @ Command {
+                    instruction: Instruction::Task { .. },
+                    completed: false,
+                    ..
+                },
+            ) => running,
+            _ => return Ok(None),
+        };
+
+        let preempting = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.delivered_at IS NULL AND
+      C.deleted_at IS NULL AND
+      C.priority > $2 AND
+      C.time_instruction <= now()
+ORDER BY C.priority DESC, C.time_instruction, C.command_id
+LIMIT 1
+               "#,
+            robot_serial_number,
+            running.priority
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Preempt");
+            ApiError::from(e)
+        })?
+        .map(Command::from_row)
+        .transpose()?;
+
+        let preempting = match preempting {
+            Some(preempting) => preempting,
+            None => return Ok(None),
+        };
+
+        sqlx::query!(
+            r#"
+WITH Preempted AS (
+    UPDATE Commands C
+    SET version = version + 1,
+        completed = true,
+        completed_at = COALESCE(C.completed_at, now()),
+        cancelled = true,
+        cancel_reason = 'preempted'
+    WHERE C.command_id = $1 AND
+          C.completed = false
+    RETURNING C.command_id, C.progress
+)
+INSERT INTO preempted_commands (preempting_command_id, command_id, progress)
+SELECT $2, P.command_id, P.progress FROM Preempted P
+WHERE $3
+               "#,
+            running.command_id,
+            preempting.command_id,
+            !matches!(preempting.instruction, Instruction::Abort(_))
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Preempt");
+            ApiError::from(e)
+        })?;
+        Command::release_resource(conn, running.command_id).await?;
+
+        Ok(Some(preempting))
+    }
+
+    /// Puts the task aside to be picked back up with `resume_preempted`
+    /// once the command that interrupted it completes
+    pub async fn stash_interrupted(
+        conn: &PgPool,
+        command_id: i64,
+        interrupting_command_id: i64,
+    ) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+INSERT INTO preempted_commands (preempting_command_id, command_id, progress)
+SELECT $2, C.command_id, C.progress FROM Commands C
+WHERE C.command_id = $1
+               "#,
+            command_id,
+            interrupting_command_id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Stash Interrupted");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Picks back up the task the given command preempted, if it preempted
+    /// one, as a new command carrying on from the task's progress
+    pub async fn resume_preempted(
+        conn: &PgPool,
+        preempting_command_id: i64,
+    ) -> Result<Option<Command>, ApiError> {
+        let resumed = sqlx::query_as!(
+            CommandRow,
+            r#"
+WITH Stashed AS (
+    DELETE FROM preempted_commands P
+    WHERE P.preempting_command_id = $1
+    RETURNING P.command_id, P.progress
+)
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction,
+                      shift_id, resource, callback_url, priority, progress)
+SELECT C.robot_serial_number, now(), now(), C.instruction,
+       C.shift_id, C.resource, C.callback_url, C.priority, S.progress
+FROM Commands C
+JOIN Stashed S ON S.command_id = C.command_id
+RETURNING *
+               "#,
+            preempting_command_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Resume Preempted");
+            ApiError::from(e)
+        })?
+        .map(Command::from_row)
+        .transpose()?;
+
+        // The resumed task needs its resource back before it can carry on
+        if let Some(resumed) = &resumed {
+            resumed.lock_resource(conn).await?;
+            metrics::record_command_issued(resumed.instruction.kind());
+            push::publish(&resumed.robot_serial_number, resumed.command_id);
+        }
+
+        Ok(resumed)
+    }
+
+    // Lets the command's callback know it has completed, in the background
+    // so a slow endpoint doesn't hold up the robot. Commands without their
+    // own callback go to the robot's webhook, or the default one.
+    fn send_callback(self, conn: &PgPool) {
+        let conn = conn.clone();
+
+        actix_web::rt::spawn(async move {
+            let callback_url = match &self.callback_url {
+                Some(callback_url) => Some(callback_url.clone()),
+                None => Robot::webhook_url(&conn, &self.robot_serial_number)
+                    .await
+                    .ok()
+                    .flatten()
+                    .or_else(|| DEFAULT_WEBHOOK_URL.get().cloned()),
+            };
+            let callback_url = match callback_url {
+                Some(callback_url) => callback_url,
+                None => return,
+            };
+
+            let result = actix_web::client::Client::new()
+                .post(&callback_url)
+                .timeout(std::time::Duration::from_secs(CALLBACK_TIMEOUT_SECS))
+                .send_json(&self)
+                .await;
+
+            if let Err(e) = result {
+                error!(command_id = self.command_id, %callback_url, error = ?e, "Command Callback");
+            }
+        });
+    }
+
+    /// Sets where completed commands are sent when neither the command nor
+    /// its robot has a callback, only the first one set is used
+    pub fn install_default_webhook(webhook_url: &str) -> Result<(), ApiError> {
+        check_callback_url(webhook_url)?;
+        DEFAULT_WEBHOOK_URL.set(webhook_url.to_string()).ok();
+
+        Ok(())
+    }
+
+    /// Issues a command that needs a shared resource, such as a charging
+    /// dock, that only one robot can use at a time
+    ///
+    /// The command is only handed to the robot once it holds the resource,
+    /// until then it stays pending. The resource is freed when the command
+    /// is completed or cancelled.
+    pub async fn new_with_resource(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        time_issued: chrono::DateTime<Utc>,
+        time_instruction: chrono::DateTime<Utc>,
+        instruction: &Instruction,
+        resource: &str,
+    ) -> Result<Command, ApiError> {
+        let command = Command::new_with_options(
+            conn,
+            robot_serial_number,
+            time_issued,
+            time_instruction,
+            instruction,
+            CommandConfig::global(),
+            &CommandOptions {
+                resource: Some(resource),
+                ..CommandOptions::default()
+            },
+        )
+        .await?;
+        command.lock_resource(conn).await?;
+
+        Ok(command)
+    }
+
+    // Takes the command's resource if it is free, returning whether the
+    // command holds it. Commands without a resource always can go ahead.
+    async fn lock_resource(&self, conn: &PgPool) -> Result<bool, ApiError> {
+        let resource = match &self.resource {
+            Some(resource) => resource,
+            None => return Ok(true),
+        };
+
+        // Taking the lock and finding who holds it is one statement, so the
+        // holder can't change in between
+        sqlx::query!(
+            r#"
+WITH Locked AS (
+    INSERT INTO resource_locks (resource, command_id)
+    VALUES ($1, $2)
+    ON CONFLICT (resource) DO NOTHING
+    RETURNING command_id
+)
+SELECT COALESCE(
+    (SELECT K.command_id FROM Locked K),
+    (SELECT L.command_id FROM resource_locks L WHERE L.resource = $1)
+) AS holder
+               "#,
+            resource,
+            self.command_id
+        )
+        .fetch_one(conn)
+        .await
+        .map(|lock| lock.holder == Some(self.command_id))
+        .map_err(|e| {
+            error!(command_id = self.command_id, error = ?e, "Command Lock Resource");
+            ApiError::from(e)
+        })
+    }
+
+    // Frees any resource the command was holding
+    async fn release_resource(conn: &PgPool, command_id: i64) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+DELETE FROM resource_locks L
+WHERE L.command_id = $1
+               "#,
+            command_id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Release Resource");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Copies the commands one robot has waiting onto another, e.g. when a
+    /// broken robot is swapped out for a replacement. Commands that were
+    /// already handed to the old robot, or deleted, aren't copied.
+    ///
+    /// The copies get new ids and are issued now, but keep their
+    /// instruction time so the queue order is unchanged. They are issued
+    /// together like any other commands for the robot, so they are checked
+    /// against its queue and keep the priority, resource and callback of
+    /// the originals.
+    pub async fn clone_pending(
+        conn: &PgPool,
+        from_serial: &RobotSerial,
+        to_serial: &RobotSerial,
+    ) -> Result<u64, ApiError> {
+        let pending = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.delivered_at IS NULL AND
+      C.deleted_at IS NULL
+ORDER BY C.time_instruction, C.command_id
+               "#,
+            from_serial.as_str()
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Clone Pending");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let now = Utc::now();
+        let copies: Vec<Issue> = pending
+            .iter()
+            .map(|c| Issue {
+                robot_serial_number: to_serial,
+                time_issued: now,
+                time_instruction: c.time_instruction,
+                instruction: c.instruction.clone(),
+                options: CommandOptions {
+                    issued_by: c.issued_by.as_deref(),
+                    idempotency_key: None,
+                    priority: c.priority,
+                    callback_url: c.callback_url.as_deref(),
+                    resource: c.resource.as_deref(),
+                    ..CommandOptions::default()
+                },
+            })
+            .collect();
+
+        let cloned = Command::issue_all(conn, &copies, CommandConfig::global()).await?;
+        Ok(cloned.len() as u64)
+    }
+
+    /// Replaces everything the robot has queued with the given
+    /// instructions, in a single transaction
+    ///
+    /// The old commands that haven't been delivered are cancelled, the one
+    /// the robot is running is left to finish. The new ones are queued one
+    /// second apart in the order given and are checked against the robot's
+    /// rate limit and queue, if any is turned away the old queue is kept.
+    /// The latest instruction time is served first, so the first one is
+    /// furthest from now and the last one is now.
+    pub async fn replace_queue(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        instructions: &[Instruction],
+    ) -> Result<Vec<Command>, ApiError> {
+        let time_now = chrono::Utc::now();
+        let commands: Vec<_> = instructions
+            .iter()
+            .enumerate()
+            .map(|(position, instruction)| {
+                let behind = instructions.len() - position - 1;
+                Issue {
+                    robot_serial_number,
+                    time_issued: time_now,
+                    time_instruction: time_now + Duration::seconds(behind as i64),
+                    instruction: instruction.clone(),
+                    options: CommandOptions::default(),
+                }
+            })
+            .collect();
+
+        let mut tx = conn.begin().await.map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Replace Queue");
+            ApiError::from(e)
+        })?;
+        Command::lock_robot(&mut tx, robot_serial_number).await?;
+
+        sqlx::query!(
+            r#"
+WITH Replaced AS (
+    UPDATE Commands C
+    SET version = version + 1,
+        completed = true,
+        completed_at = COALESCE(C.completed_at, now()),
+        cancelled = true,
+        cancel_reason = 'replaced'
+    WHERE C.robot_serial_number = $1 AND
+          C.completed = false AND
+          C.delivered_at IS NULL
+    RETURNING C.command_id
+)
+DELETE FROM resource_locks L
+WHERE L.command_id IN (SELECT command_id FROM Replaced)
+               "#,
+            robot_serial_number.as_str()
+        )
+        .execute(&mut tx)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Replace Queue");
+            ApiError::from(e)
+        })?;
+
+        let inserted = Command::issue_in(&mut tx, &commands, CommandConfig::global()).await?;
+
+        tx.commit().await.map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Replace Queue");
+            ApiError::from(e)
+        })?;
+
+        inserted.into_iter().map(Command::announce).collect()
+    }
+
+    /// Counts the uncompleted commands across the whole fleet, ignoring
+    /// robots that are just idling
+    pub async fn total_pending(conn: &PgPool) -> Result<i64, ApiError> {
+        Ok(Command::pending_breakdown(conn).await?.values().sum())
+    }
+
+    /// The uncompleted commands across the fleet by instruction type,
+    /// idle commands are left out
+    pub async fn pending_breakdown(conn: &PgPool) -> Result<HashMap<String, i64>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT C.instruction, COUNT(*) AS "count!" FROM Commands C
+WHERE C.completed = false AND
+      C.deleted_at IS NULL
+GROUP BY C.instruction
+               "#
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| tally_by_kind(rows.into_iter().map(|r| (r.instruction, r.count))))
+        .map_err(|e| {
+            error!(error = ?e, "Command Pending Breakdown");
+            ApiError::from(e)
+        })
+    }
+
+    /// How many commands the robot has been given of each instruction type
+    ///
+    /// Instructions without parameters are stored as a JSON string and
+    /// the rest as an object keyed by the type, so the type is read from
+    /// whichever of the two it is.
+    pub async fn count_by_type(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<HashMap<String, i64>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT K.kind AS "kind!", COUNT(*) AS "count!" FROM Commands C,
+LATERAL (
+    SELECT CASE jsonb_typeof(C.instruction)
+               WHEN 'object' THEN (SELECT jsonb_object_keys(C.instruction) LIMIT 1)
+               ELSE C.instruction #>> '{}'
+           END AS kind
+) K
+WHERE C.robot_serial_number = $1 AND
+      C.deleted_at IS NULL
+GROUP BY K.kind
+               "#,
+            robot_serial_number
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| rows.into_iter().map(|r| (r.kind, r.count)).collect())
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Count By Type");
+            ApiError::from(e)
+        })
+    }
+
+    /// Puts the command in the given shift
+    pub async fn tag_shift(&mut self, conn: &PgPool, shift_id: &str) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    shift_id = $2
+WHERE C.command_id = $1
+               "#,
+            self.command_id,
+            shift_id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id = self.command_id, error = ?e, "Command Tag Shift");
+            ApiError::from(e)
+        })?;
+
+        self.shift_id = Some(shift_id.to_string());
+        self.version += 1;
+
+        Ok(())
+    }
+
+    /// Every command issued in the shift, oldest first
+    pub async fn by_shift(conn: &PgPool, shift_id: &str) -> Result<Vec<Self>, ApiError> {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.shift_id = $1 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued, C.command_id
+               "#,
+            shift_id
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command By Shift");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect()
+    }
+
+    /// Robots in the organization whose current command is idle, these are
+    /// free to be given a new task
+    pub async fn idle_robots(
+        conn: &PgPool,
+        organization: &OrganizationId,
+    ) -> Result<Vec<String>, ApiError> {
+        let idle_json = serde_json::to_value(&Instruction::Idle).map_err(|e| {
+            error!(error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        sqlx::query!(
+            r#"
+SELECT L.robot_serial_number AS "robot_serial_number!" FROM (
+    SELECT DISTINCT ON (C.robot_serial_number) C.robot_serial_number, C.instruction
+    FROM Commands C
+    WHERE C.deleted_at IS NULL AND
+          starts_with(C.robot_serial_number, $2 || ':')
+    ORDER BY C.robot_serial_number, C.time_issued DESC, C.command_id DESC
+) L
+WHERE L.instruction = $1
+ORDER BY L.robot_serial_number
+               "#,
+            idle_json,
+            organization.as_str()
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| rows.into_iter().map(|r| r.robot_serial_number).collect())
+        .map_err(|e| {
+            error!(error = ?e, "Command Idle Robots");
+            ApiError::from(e)
+        })
+    }
+
+    /// The latest command of each robot in the organization, keyed by the
+    /// serial the robot is stored under
+    ///
+    /// A robot whose latest instruction can't be read is left out and
+    /// logged, so one bad row doesn't hide the rest of the fleet. When the
+    /// config is lenient it's a safety abort instead, the same as `current`.
+    pub async fn current_fleet(
+        conn: &PgPool,
+        organization: &OrganizationId,
+    ) -> Result<HashMap<String, Self>, ApiError> {
+        Command::latest_by_robot(conn, organization).await
+    }
+
+    /// The latest command the robot in the organization has been issued,
+    /// a robot in another organization is not found
+    pub async fn current_in(
+        conn: &PgPool,
+        organization: &OrganizationId,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Self, ApiError> {
+        let scoped = Robot::in_organization(conn, organization, robot_serial_number).await?;
+
+        Command::current(conn, &scoped).await
+    }
+
+    /// The next command for the robot in the organization, a robot in
+    /// another organization is not found
+    pub async fn pending_in(
+        conn: &PgPool,
+        organization: &OrganizationId,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Self, ApiError> {
+        let scoped = Robot::in_organization(conn, organization, robot_serial_number).await?;
+
+        Command::pending(conn, &scoped).await
+    }
+
+    // The latest command of every robot in the organization
+    async fn latest_by_robot(
+        conn: &PgPool,
+        organization: &OrganizationId,
+    ) -> Result<HashMap<String, Self>, ApiError> {
+        let latest = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT DISTINCT ON (C.robot_serial_number) * FROM Commands C
+WHERE C.deleted_at IS NULL AND
+      starts_with(C.robot_serial_number, $1 || ':')
+ORDER BY C.robot_serial_number, C.time_issued DESC, C.command_id DESC
+               "#,
+            organization.as_str()
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Current Fleet");
+            ApiError::from(e)
+        })?;
+
+        Ok(latest
+            .into_iter()
+            .filter_map(|c| {
+                // Already logged if it can't be read
+                let command = Command::from_row(c).ok()?;
+
+                Some((command.robot_serial_number.clone(), command))
+            })
+            .collect())
+    }
+
+    /// Every command issued between the two times, oldest first, e.g. to
+    /// audit what was lost during an outage
+    pub async fn issued_during(
+        conn: &PgPool,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> Result<Vec<Self>, ApiError> {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.time_issued >= $1 AND
+      C.time_issued <= $2 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued, C.command_id
+               "#,
+            from,
+            to
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Issued During");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect()
+    }
+
+    /// For each robot that was given commands during an outage, how many of
+    /// the polls it should have made in that time were recorded
+    pub async fn gap_analysis(
+        conn: &PgPool,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> Result<Vec<PollGap>, ApiError> {
+        let expected_polls = expected_polls(to - from);
+
+        sqlx::query!(
+            r#"
+SELECT C.robot_serial_number,
+       (SELECT COUNT(*) FROM battery_readings B
+        WHERE B.robot_serial_number = C.robot_serial_number AND
+              B.recorded_at >= $1 AND
+              B.recorded_at <= $2) AS "recorded_polls!"
+FROM Commands C
+WHERE C.time_issued >= $1 AND
+      C.time_issued <= $2
+GROUP BY C.robot_serial_number
+ORDER BY C.robot_serial_number
+               "#,
+            from,
+            to
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|r| PollGap {
+                    robot_serial_number: r.robot_serial_number,
+                    expected_polls,
+                    recorded_polls: r.recorded_polls,
+                    missing_polls: (expected_polls - r.recorded_polls).max(0),
+                })
+                .collect()
+        })
+        .map_err(|e| {
+            error!(error = ?e, "Command Gap Analysis");
+            ApiError::from(e)
+        })
+    }
+
+    /// Serial numbers that have commands but were never registered as a robot
+    pub async fn orphaned(conn: &PgPool) -> Result<Vec<String>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT DISTINCT C.robot_serial_number FROM Commands C
+WHERE NOT EXISTS (
+    SELECT 1 FROM robot R
+    WHERE R.robot_serial_number = C.robot_serial_number
+)
+ORDER BY C.robot_serial_number
+               "#
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| rows.into_iter().map(|r| r.robot_serial_number).collect())
+        .map_err(|e| {
+            error!(error = ?e, "Command Orphaned");
+            ApiError::from(e)
+        })
+    }
+
+    /// Each time the robot was switched from one cleaning pattern to
+    /// another since the given time, as `(time, from, to)`
+    pub async fn pattern_changes(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        since: chrono::DateTime<Utc>,
+    ) -> Result<Vec<(chrono::DateTime<Utc>, CleaningPattern, CleaningPattern)>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT C.time_instruction, C.instruction FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.time_instruction >= $2 AND
+      C.cancelled = false AND
+      C.deleted_at IS NULL
+ORDER BY C.time_instruction, C.command_id
+               "#,
+            robot_serial_number,
+            since
+        )
+        .fetch_all(conn)
+        .await
+        .map(|cmds| {
+            let tasks =
+                cmds.into_iter()
+                    .filter_map(|c| match serde_json::from_value(c.instruction) {
+                        Ok(Instruction::Task { pattern, .. }) => Some((c.time_instruction, pattern)),
+                        _ => None,
+                    });
+
+            pattern_transitions(tasks)
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Pattern Changes");
+            ApiError::from(e)
+        })
+    }
+
+    /// Each instruction the robot held between two times as
+    /// `(instruction, start, end)`, an instruction lasts until the next one
+    /// takes effect and the last one lasts until `to`.
+    pub async fn intervals(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        from: chrono::DateTime<Utc>,
+        to: chrono::DateTime<Utc>,
+    ) -> Result<Vec<(Instruction, chrono::DateTime<Utc>, chrono::DateTime<Utc>)>, ApiError> {
+        // Starts from the command that was already running at `from`
+        let cmds = sqlx::query!(
+            r#"
+SELECT C.command_id, C.time_instruction, C.instruction FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.cancelled = false AND
+      C.deleted_at IS NULL AND
+      C.time_instruction < $3 AND
+      C.time_instruction >= COALESCE(
+          (SELECT MAX(C1.time_instruction) FROM Commands C1
+           WHERE C1.robot_serial_number = $1 AND
+                 C1.cancelled = false AND
+                 C1.deleted_at IS NULL AND
+                 C1.time_instruction <= $2),
+          $2)
+ORDER BY C.time_instruction, C.command_id
+               "#,
+            robot_serial_number,
+            from,
+            to
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Intervals");
+            ApiError::from(e)
+        })?;
+
+        let instructions = cmds
+            .into_iter()
+            .map(|c| {
+                let time_instruction = c.time_instruction;
+                read_instruction(c.command_id, c.instruction, CommandConfig::global())
+                    .map(|instruction| (instruction, time_instruction))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(instruction_intervals(instructions.into_iter(), from, to))
+    }
+
+    /// The uncompleted command for the given teleop session, if the
+    /// session is still going
+    pub async fn teleop_session(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        session_id: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        let instruction_json = serde_json::to_value(&Instruction::Teleop {
+            session_id: session_id.to_string(),
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.instruction = $2 AND
+      C.completed = false AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued DESC
+LIMIT 1
+               "#,
+            robot_serial_number,
+            instruction_json
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Teleop Session");
+            ApiError::from(e)
+        })?
+        .map(Command::from_row)
+        .transpose()
+    }
+
+    /// The robot confirming it has received the command
+    pub async fn ack(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        command_id: i64,
+    ) -> Result<(), ApiError> {
+        let owner = sqlx::query!(
+            r#"
+SELECT C.robot_serial_number FROM Commands C
+WHERE C.command_id = $1
+               "#,
+            command_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, command_id, error = ?e, "Command Ack");
+            ApiError::from(e)
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        // Robots can only acknowledge their own commands
+        if owner.robot_serial_number != robot_serial_number {
+            return Err(ApiError::SerialMismatch);
+        }
+
+        sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    acknowledged_at = now()
+WHERE C.command_id = $1 AND
+      C.acknowledged_at IS NULL
+               "#,
+            command_id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, command_id, error = ?e, "Command Ack");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Records that the robot has received the command, which is separate
+    /// from it being completed. Only the first acknowledgement is kept.
+    pub async fn acknowledge(&self, conn: &PgPool) -> Result<(), ApiError> {
+        with_retry(&CommandConfig::global().retry, || async move {
+            sqlx::query!(
+                r#"
+UPDATE Commands C
+SET version = version + 1,
+    acknowledged_at = now()
+WHERE C.command_id = $1 AND
+      C.acknowledged_at IS NULL
+               "#,
+                self.command_id
+            )
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                error!(command_id = self.command_id, error = ?e, "Command Acknowledge");
+                ApiError::from(e)
+            })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records that the command has been handed to the robot
+    pub async fn deliver(&self, conn: &PgPool) -> Result<(), ApiError> {
+        // The first time a command is handed over the robot is moving on
+        // from the last one, which goes in the audit trail
+        if self.delivered_at.is_none() {
+            if let Some(last) = Command::last_delivered(conn, &self.robot_serial_number).await? {
+                if last.command_id != self.command_id {
+                    Transition::record(
+                        conn,
+                        &self.robot_serial_number,
+                        &last.instruction,
+                        &self.instruction,
+                    )
+                    .await
+                    .ok();
+                }
+            }
+        }
+
+        with_retry(&CommandConfig::global().retry, || async move {
+            sqlx::query!(
+                r#"
+UPDATE Commands C
+SET delivered_at = now()
+WHERE C.command_id = $1
+               "#,
+                self.command_id
+            )
+            .execute(conn)
+            .await
+            .map_err(|e| {
+                error!(command_id = self.command_id, error = ?e, "Command Deliver");
+                ApiError::from(e)
+            })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks the command as deleted, it's kept for auditing but no longer
+    /// shows up in the robot's commands or gets handed to the robot
+    pub async fn soft_delete(&self, conn: &PgPool) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    deleted_at = now()
+WHERE C.command_id = $1 AND
+      C.deleted_at IS NULL
+               "#,
+            self.command_id
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id = self.command_id, error = ?e, "Command Soft Delete");
+            ApiError::from(e)
+        })?;
+
+        Command::release_resource(conn, self.command_id).await
+    }
+
+    /// Looks up a command by its id, even if it has been deleted
+    pub async fn get_including_deleted(conn: &PgPool, command_id: i64) -> Result<Self, ApiError> {
+        let c = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.command_id = $1
+               "#,
+            command_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Get Including Deleted");
+            ApiError::from(e)
+        })?
+        .ok_or(ApiError::NotFound)?;
+
+        Command::from_row(c)
+    }
+
+    /// Whether the command is still waiting to be handed to the robot
+    pub async fn awaiting_delivery(conn: &PgPool, command_id: i64) -> Result<bool, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT 1 AS waiting FROM Commands C
+WHERE C.command_id = $1 AND
+      C.delivered_at IS NULL AND
+      C.completed = false AND
+      C.deleted_at IS NULL
+               "#,
+            command_id
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|c| c.is_some())
+        .map_err(|e| {
+            error!(command_id, error = ?e, "Command Awaiting Delivery");
+            ApiError::from(e)
+        })
+    }
+
+    /// How many commands across the fleet are still waiting to be handed
+    /// to their robots
+    pub async fn pending_count(conn: &PgPool) -> Result<i64, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT COUNT(*) AS "pending!" FROM Commands C
+WHERE C.delivered_at IS NULL AND
+      C.completed = false AND
+      C.deleted_at IS NULL
+               "#
+        )
+        .fetch_one(conn)
+        .await
+        .map(|c| c.pending)
+        .map_err(|e| {
+            error!(error = ?e, "Command Pending Count");
+            ApiError::from(e)
+        })
+    }
+
+    /// A page of every command the robot has been issued, newest first
+    ///
+    /// The limit has to be between 1 and `MAX_HISTORY_LIMIT` and the offset
+    /// can't be negative.
+    pub async fn history(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        check_page(limit, offset)?;
+
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued DESC, C.command_id DESC
+LIMIT $2 OFFSET $3
+               "#,
+            robot_serial_number,
+            limit,
+            offset
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command History");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect()
+    }
+
+    /// Every command the robot has been issued, newest first, written out
+    /// in the format
+    pub async fn export(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        format: ExportFormat,
+    ) -> Result<String, ApiError> {
+        let mut commands = Vec::new();
+        loop {
+            let page = Command::history(
+                conn,
+                robot_serial_number,
+                MAX_HISTORY_LIMIT,
+                commands.len() as i64,
+            )
+            .await?;
+            let last_page = (page.len() as i64) < MAX_HISTORY_LIMIT;
+            commands.extend(page);
+
+            if last_page {
+                break;
+            }
+        }
+
+        match format {
+            ExportFormat::Csv => Ok(commands_csv(&commands)),
+            ExportFormat::Json => serde_json::to_string(&commands).map_err(|e| {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Export");
+                ApiError::SerializationError
+            }),
+        }
+    }
+
+    /// The robot's commands that match the filter, newest first
+    ///
+    /// The filter is applied to the instruction JSON in the database.
+    /// Instructions without parameters are stored as a string and the rest
+    /// as an object keyed by the type, `?` matches either. Tasks written
+    /// before they had parameters only store the pattern.
+    pub async fn search(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        filter: &InstructionFilter,
+    ) -> Result<Vec<Self>, ApiError> {
+        let pattern = filter
+            .pattern
+            .as_ref()
+            .map(serde_json::to_value)
+            .transpose()
+            .map_err(|e| {
+                error!(robot_serial_number = %robot_serial_number, error = ?e, "Pattern Json");
+                ApiError::SerializationError
+            })?;
+
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.deleted_at IS NULL AND
+      ($2::TEXT IS NULL OR C.instruction ? $2) AND
+      ($3::JSONB IS NULL OR
+       COALESCE(C.instruction -> 'Task' -> 'pattern', C.instruction -> 'Task') = $3) AND
+      ($4::TIMESTAMPTZ IS NULL OR C.time_issued >= $4) AND
+      ($5::TIMESTAMPTZ IS NULL OR C.time_issued < $5)
+ORDER BY C.time_issued DESC, C.command_id DESC
+               "#,
+            robot_serial_number,
+            filter.kind,
+            pattern,
+            filter.from,
+            filter.to
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Search");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect()
+    }
+
+    /// The last command that was handed to the robot
+    pub async fn last_delivered(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.delivered_at IS NOT NULL AND
+      C.deleted_at IS NULL
+ORDER BY C.delivered_at DESC, C.command_id DESC
+LIMIT 1
+               "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Last Delivered");
+            ApiError::from(e)
+        })?
+        .map(Command::from_row)
+        .transpose()
+    }
+
+    /// Alerts the sink about any commands the robot has not acknowledged
+    /// within the timeout of them being handed to it, each command is only
+    /// escalated once. Commands still waiting to be delivered have nothing
+    /// to acknowledge yet.
+    pub async fn escalate_unacknowledged(
+        conn: &PgPool,
+        timeout: chrono::Duration,
+        sink: &dyn CompletionSink,
+    ) -> Result<u64, ApiError> {
+        let cutoff = chrono::Utc::now() - timeout;
+
+        let escalated = sqlx::query_as!(
+            CommandRow,
+            r#"
+UPDATE Commands C
+SET escalated_at = now()
+WHERE C.acknowledged_at IS NULL AND
+      C.escalated_at IS NULL AND
+      C.completed = false AND
+      C.deleted_at IS NULL AND
+      C.delivered_at < $1
+RETURNING *
+               "#,
+            cutoff
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Escalate");
+            ApiError::from(e)
+        })?;
+
+        let count = escalated.len() as u64;
+
+        // The rows are already marked as escalated, so one that can't be
+        // read is skipped rather than losing the alerts for the rest
+        for command in escalated
+            .into_iter()
+            .filter_map(|c| Command::from_row(c).ok())
+        {
+            sink.notify(Notification::Unacknowledged(command));
+        }
+
+        Ok(count)
+    }
+
+    /// The id the command was stored under, which it can be referred to
+    /// by later on
+    pub fn id(&self) -> i64 {
+        self.command_id
+    }
+
+    /// Whether the command is still close enough to its instruction time to
+    /// be run, the time the robot spent charging since then doesn't count
+    pub fn valid_time_instruction(
+        &self,
+        charging: &[(chrono::DateTime<Utc>, chrono::DateTime<Utc>)],
+        config: &CommandConfig,
+    ) -> bool {
+        within_time_instruction_buffer(self.time_instruction, charging, chrono::Utc::now(), config)
+    }
+
+    /// The times, as `(start, end)`, the robot has spent docked to charge
+    /// since the given time
+    pub async fn charging_intervals(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        since: chrono::DateTime<Utc>,
+    ) -> Result<Vec<(chrono::DateTime<Utc>, chrono::DateTime<Utc>)>, ApiError> {
+        let intervals = Command::intervals(conn, robot_serial_number, since, Utc::now()).await?;
+
+        Ok(intervals
+            .into_iter()
+            .filter(|(instruction, _, _)| *instruction == Instruction::Dock)
+            .map(|(_, start, end)| (start, end))
+            .collect())
+    }
+
+    /// Estimates the energy the command used if it ran for the given time
+    pub fn estimated_energy_wh(
+        &self,
+        duration: Duration,
+        coefficients: &EnergyCoefficients,
+    ) -> f64 {
+        let watts = match &self.instruction {
+            Instruction::Task { pattern, .. } => {
+                coefficients.0.get(pattern).copied().unwrap_or(0.0)
+            }
+            _ => 0.0,
+        };
+
+        watts * duration.num_milliseconds() as f64 / 3_600_000.0
+    }
+
+    /// The estimated energy used by the robot's completed commands since the
+    /// given time, each command runs until the next one takes effect.
+    pub async fn total_energy(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        since: chrono::DateTime<Utc>,
+        coefficients: &EnergyCoefficients,
+    ) -> Result<f64, ApiError> {
+        let commands = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.robot_serial_number = $1 AND
+      C.time_instruction >= $2 AND
+      C.cancelled = false AND
+      C.deleted_at IS NULL
+ORDER BY C.time_instruction, C.command_id
+               "#,
+            robot_serial_number,
+            since
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command Total Energy");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(energy_used(&commands, chrono::Utc::now(), coefficients))
+    }
+}
+
+impl Command {
+    // Abort the current task with the given reason
+    pub async fn abort(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        reason: &AbortReason,
+    ) -> Result<Self, ApiError> {
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        Command::new_unbuffered(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::Abort(reason.clone()),
+        )
+        .await
+    }
+
+    /// Aborts every robot in the organization that has a task in progress,
+    /// for when an operator needs to stop everything at once.
+    ///
+    /// A robot that already has an abort waiting isn't sent another one.
+    pub async fn abort_all(
+        conn: &PgPool,
+        organization: &OrganizationId,
         reason: &AbortReason,
+    ) -> Result<Vec<Self>, ApiError> {
+        let time_now = chrono::Utc::now();
+        let instruction = Instruction::Abort(reason.clone());
+        let instruction_json = serde_json::to_value(&instruction).map_err(|e| {
+            error!(error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        let mut tx = conn.begin().await.map_err(|e| {
+            error!(error = ?e, "Command Abort All");
+            ApiError::from(e)
+        })?;
+
+        // Two broadcasts at once would otherwise both see no abort pending
+        sqlx::query!(
+            r#"
+SELECT 1 AS locked FROM pg_advisory_xact_lock(hashtext('abort_all:' || $1))
+               "#,
+            organization.as_str()
+        )
+        .fetch_one(&mut tx)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Abort All");
+            ApiError::from(e)
+        })?;
+
+        let commands: Vec<Command> = sqlx::query_as!(
+            CommandRow,
+            r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction, priority)
+SELECT DISTINCT C.robot_serial_number, $1::TIMESTAMPTZ, $1::TIMESTAMPTZ, $2::JSONB, $3::SMALLINT
+FROM Commands C
+WHERE C.completed = false AND
+      C.deleted_at IS NULL AND
+      C.instruction ? 'Task' AND
+      starts_with(C.robot_serial_number, $4 || ':') AND
+      NOT EXISTS (
+          SELECT 1 FROM Commands A
+          WHERE A.robot_serial_number = C.robot_serial_number AND
+                A.completed = false AND
+                A.deleted_at IS NULL AND
+                A.instruction ? 'Abort'
+      )
+RETURNING *
+               "#,
+            time_now,
+            instruction_json,
+            ABORT_PRIORITY,
+            organization.as_str()
+        )
+        .fetch_all(&mut tx)
+        .await
+        .map_err(|e| {
+            error!(error = ?e, "Command Abort All");
+            ApiError::from(e)
+        })?
+        .into_iter()
+        .map(Command::from_row)
+        .collect::<Result<_, _>>()?;
+
+        tx.commit().await.map_err(|e| {
+            error!(error = ?e, "Command Abort All");
+            ApiError::from(e)
+        })?;
+
+        for command in &commands {
+            metrics::record_command_issued(instruction.kind());
+            push::publish(&command.robot_serial_number, command.command_id);
+        }
+
+        Ok(commands)
+    }
+
+    // Idle task the current task with the given reason
+    pub async fn idle(conn: &PgPool, robot_serial_number: &RobotSerial) -> Result<Self, ApiError> {
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        // The poll needs something to answer with, so like an abort it is
+        // never held back by the rate limit
+        Command::new_unbuffered(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::Idle,
+        )
+        .await
+    }
+
+    // Schedules the instruction to be run at a later time, until then
+    // the robot isn't given it
+    pub async fn schedule(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        instruction: &Instruction,
+        at: chrono::DateTime<Utc>,
+    ) -> Result<Self, ApiError> {
+        Command::new(
+            conn,
+            robot_serial_number,
+            chrono::Utc::now(),
+            at,
+            instruction,
+            CommandConfig::global(),
+            None,
+            None,
+        )
+        .await
+    }
+
+    // Tell the robot to hold where it is
+    pub async fn pause(conn: &PgPool, robot_serial_number: &RobotSerial) -> Result<Self, ApiError> {
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        // What the robot has said it's doing, so the poll can't be turned
+        // away for it
+        Command::new_unbuffered(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::Pause,
+        )
+        .await
+    }
+
+    // Send the robot back to its dock to charge
+    pub async fn return_to_dock(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Self, ApiError> {
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        // The robot is already heading back, this only records it
+        Command::new_unbuffered(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::ReturnToDock,
+        )
+        .await
+    }
+
+    // Give the robot whatever it should do when there is nothing else to
+    // do, this is idle unless the robot has been set up otherwise
+    pub async fn fallback(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Self, ApiError> {
+        let instruction = Robot::pending_fallback(conn, robot_serial_number).await?;
+
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        // Issued while answering a poll, so it skips the rate limit
+        Command::new_unbuffered(conn, robot_serial_number, time_now, time_now, &instruction).await
+    }
+
+    pub async fn task(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        cleaning_pattern: &CleaningPattern,
     ) -> Result<Self, ApiError> {
         // Create a new command with the current time
         let time_now = chrono::Utc::now();
 
-        Ok(Command::new(
+        Command::new(
             conn,
             robot_serial_number,
             time_now,
             time_now,
-            &Instruction::Abort(reason.clone()),
+            &Instruction::task(cleaning_pattern.clone()),
+            CommandConfig::global(),
+            None,
+            None,
         )
-        .await?)
+        .await
     }
 
-    // Idle task the current task with the given reason
-    pub async fn idle(conn: &PgPool, robot_serial_number: &str) -> Result<Self, ApiError> {
-        // Create a new command with the current time
+    // Give the robot a cleaning task run at a given speed for a number of
+    // passes over the pattern
+    pub async fn task_with_params(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        cleaning_pattern: &CleaningPattern,
+        speed: u8,
+        passes: u8,
+    ) -> Result<Self, ApiError> {
         let time_now = chrono::Utc::now();
 
-        Ok(Command::new(
+        Command::new(
             conn,
             robot_serial_number,
             time_now,
             time_now,
-            &Instruction::Idle,
+            &Instruction::Task {
+                pattern: cleaning_pattern.clone(),
+                speed,
+                passes,
+                zone: None,
+            },
+            CommandConfig::global(),
+            None,
+            None,
         )
-        .await?)
+        .await
     }
 
-    pub async fn task(
+    // Give the robot a cleaning task over a named region of the facility
+    pub async fn task_in_zone(
         conn: &PgPool,
-        robot_serial_number: &str,
+        robot_serial_number: &RobotSerial,
+        cleaning_pattern: &CleaningPattern,
+        zone: &str,
+    ) -> Result<Self, ApiError> {
+        let time_now = chrono::Utc::now();
+
+        Command::new(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::Task {
+                pattern: cleaning_pattern.clone(),
+                speed: DEFAULT_TASK_SPEED,
+                passes: DEFAULT_TASK_PASSES,
+                zone: Some(zone.to_string()),
+            },
+            CommandConfig::global(),
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Every task issued for the zone across the fleet, most recent first,
+    /// for reporting how well it is being covered
+    pub async fn tasks_for_zone(conn: &PgPool, zone: &str) -> Result<Vec<Self>, ApiError> {
+        let rows = sqlx::query_as!(
+            CommandRow,
+            r#"
+SELECT * FROM Commands C
+WHERE C.instruction ? 'Task' AND
+      C.instruction->'Task'->>'zone' = $1 AND
+      C.deleted_at IS NULL
+ORDER BY C.time_issued DESC, C.command_id DESC
+               "#,
+            zone
+        )
+        .fetch_all(conn)
+        .await
+        .map_err(|e| {
+            error!(zone, error = ?e, "Command Tasks For Zone");
+            ApiError::from(e)
+        })?;
+
+        rows.into_iter().map(Command::from_row).collect()
+    }
+
+    // Give the robot a cleaning task if it has enough battery for the
+    // pattern, robots that haven't reported their battery are let through
+    pub async fn task_with_battery(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
         cleaning_pattern: &CleaningPattern,
+        requirements: &BatteryRequirements,
+    ) -> Result<Self, ApiError> {
+        if let Some(battery_level) = Robot::battery_level(conn, robot_serial_number).await? {
+            requirements.check(cleaning_pattern, battery_level)?;
+        }
+
+        Command::task(conn, robot_serial_number, cleaning_pattern).await
+    }
+
+    // Tell the robot to reload the fleet settings, it only has to be told
+    // once so the command is completed straight away
+    pub async fn refresh_config(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        config_version: u64,
+    ) -> Result<Self, ApiError> {
+        // Create a new command with the current time
+        let time_now = chrono::Utc::now();
+
+        let command = Command::new(
+            conn,
+            robot_serial_number,
+            time_now,
+            time_now,
+            &Instruction::RefreshConfig { config_version },
+            CommandConfig::global(),
+            None,
+            None,
+        )
+        .await?;
+        command.complete(conn).await?;
+
+        // Read it back so the version and completion time match the row
+        Command::get_including_deleted(conn, command.command_id).await
+    }
+
+    // Put the robot under remote control
+    pub async fn start_teleop(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        session_id: &str,
     ) -> Result<Self, ApiError> {
         // Create a new command with the current time
         let time_now = chrono::Utc::now();
 
-        Ok(Command::new(
+        Command::new(
             conn,
             robot_serial_number,
             time_now,
             time_now,
-            &Instruction::Task(cleaning_pattern.clone()),
+            &Instruction::Teleop {
+                session_id: session_id.to_string(),
+            },
+            CommandConfig::global(),
+            None,
+            None,
+        )
+        .await
+    }
+
+    // End any remote control of the robot, it goes back to idle
+    pub async fn end_teleop(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<Self, ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE Commands C
+SET version = version + 1,
+    completed = true,
+    completed_at = COALESCE(C.completed_at, now())
+WHERE C.robot_serial_number = $1 AND
+      C.completed = false AND
+      C.instruction ? 'Teleop'
+               "#,
+            robot_serial_number.as_str()
         )
-        .await?)
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Command End Teleop");
+            ApiError::from(e)
+        })?;
+
+        Command::idle(conn, robot_serial_number).await
+    }
+
+    // Give the same cleaning task to a group of robots in one go, each
+    // robot's task going through the same checks as one issued on its own
+    pub async fn task_group(
+        conn: &PgPool,
+        robot_serial_numbers: &[RobotSerial],
+        cleaning_pattern: &CleaningPattern,
+    ) -> Result<Vec<Self>, ApiError> {
+        // Create the new commands with the current time
+        let time_now = chrono::Utc::now();
+
+        let commands: Vec<_> = robot_serial_numbers
+            .iter()
+            .map(|robot_serial_number| Issue {
+                robot_serial_number,
+                time_issued: time_now,
+                time_instruction: time_now,
+                instruction: Instruction::task(cleaning_pattern.clone()),
+                options: CommandOptions::default(),
+            })
+            .collect();
+
+        Command::issue_all(conn, &commands, CommandConfig::global()).await
+    }
+}
+
+// Finds where the pattern changes between consecutive tasks, the first
+// task has nothing to change from so it is skipped
+fn pattern_transitions(
+    tasks: impl Iterator<Item = (chrono::DateTime<Utc>, CleaningPattern)>,
+) -> Vec<(chrono::DateTime<Utc>, CleaningPattern, CleaningPattern)> {
+    let mut transitions = Vec::new();
+    let mut previous: Option<CleaningPattern> = None;
+
+    for (time, pattern) in tasks {
+        match previous {
+            Some(prev) if prev != pattern => transitions.push((time, prev, pattern.clone())),
+            _ => (),
+        }
+        previous = Some(pattern);
+    }
+
+    transitions
+}
+
+// Checks a page is a sensible size before it is fetched
+fn check_page(limit: i64, offset: i64) -> Result<(), ApiError> {
+    if !(1..=MAX_HISTORY_LIMIT).contains(&limit) || offset < 0 {
+        return Err(ApiError::InvalidPagination);
+    }
+
+    Ok(())
+}
+
+// The commands as CSV with a header, the times in RFC 3339
+fn commands_csv(commands: &[Command]) -> String {
+    let mut csv = String::from("command_id,time_issued,time_instruction,instruction,completed\n");
+
+    for command in commands {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            command.command_id,
+            command
+                .time_issued
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            command
+                .time_instruction
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            csv_field(&instruction_cell(&command.instruction)),
+            command.completed
+        ));
+    }
+
+    csv
+}
+
+// The instruction type followed by its parameters, e.g. `Abort Obstacle`
+// or `Task ZigZag speed=100 passes=1`
+fn instruction_cell(instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Abort(reason) => format!("Abort {:?}", reason),
+        Instruction::Task {
+            pattern,
+            speed,
+            passes,
+            zone,
+        } => {
+            let task = format!("Task {:?} speed={} passes={}", pattern, speed, passes);
+            match zone {
+                Some(zone) => format!("{} zone={}", task, zone),
+                None => task,
+            }
+        }
+        Instruction::Teleop { session_id } => format!("Teleop session={}", session_id),
+        Instruction::RefreshConfig { config_version } => {
+            format!("RefreshConfig version={}", config_version)
+        }
+        other => other.kind().to_string(),
+    }
+}
+
+// Quotes the field if it has anything that would break the row, doubling
+// any quotes inside it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Turns instructions in the order they took effect into the intervals
+// they were held for, clipped to between `from` and `to`
+fn instruction_intervals(
+    instructions: impl Iterator<Item = (Instruction, chrono::DateTime<Utc>)>,
+    from: chrono::DateTime<Utc>,
+    to: chrono::DateTime<Utc>,
+) -> Vec<(Instruction, chrono::DateTime<Utc>, chrono::DateTime<Utc>)> {
+    let mut intervals = Vec::new();
+    let mut previous: Option<(Instruction, chrono::DateTime<Utc>)> = None;
+
+    for (instruction, start) in instructions {
+        if let Some((prev, prev_start)) = previous {
+            intervals.push((prev, prev_start.max(from), start));
+        }
+        previous = Some((instruction, start));
+    }
+
+    if let Some((prev, prev_start)) = previous {
+        intervals.push((prev, prev_start.max(from), to));
+    }
+
+    intervals
+}
+
+// Whether a command for the given time can still be run at `now`, not
+// counting the time spent charging since
+fn within_time_instruction_buffer(
+    time_instruction: chrono::DateTime<Utc>,
+    charging: &[(chrono::DateTime<Utc>, chrono::DateTime<Utc>)],
+    now: chrono::DateTime<Utc>,
+    config: &CommandConfig,
+) -> bool {
+    let paused = charging_time(charging, time_instruction, now);
+
+    elapsed(now, time_instruction) - paused < config.time_instruction_buffer
+}
+
+// How much of the time between `from` and `to` was spent charging, to the
+// nearest whole second below
+fn charging_time(
+    charging: &[(chrono::DateTime<Utc>, chrono::DateTime<Utc>)],
+    from: chrono::DateTime<Utc>,
+    to: chrono::DateTime<Utc>,
+) -> Duration {
+    let charged = charging
+        .iter()
+        .map(|(start, end)| (*end).min(to) - (*start).max(from))
+        .filter(|overlap| *overlap > Duration::zero())
+        .fold(Duration::zero(), |total, overlap| total + overlap);
+
+    Duration::seconds(charged.num_seconds())
+}
+
+// Adds up the energy of the completed commands, in the order they took
+// effect, with the last one running until `until`
+fn energy_used(
+    commands: &[Command],
+    until: chrono::DateTime<Utc>,
+    coefficients: &EnergyCoefficients,
+) -> f64 {
+    let ends = commands
+        .iter()
+        .skip(1)
+        .map(|c| c.time_instruction)
+        .chain(std::iter::once(until));
+
+    commands
+        .iter()
+        .zip(ends)
+        .filter(|(c, _)| c.completed)
+        .map(|(c, end)| c.estimated_energy_wh(end - c.time_instruction, coefficients))
+        .sum()
+}
+
+// How many polls a robot makes in the given time
+fn expected_polls(window: Duration) -> i64 {
+    window.num_seconds().max(0) / POLL_INTERVAL_SECS as i64
+}
+
+// Callbacks have to be absolute http or https URLs
+pub(crate) fn check_callback_url(callback_url: &str) -> Result<(), ApiError> {
+    let uri: actix_web::http::Uri = callback_url
+        .parse()
+        .map_err(|_| ApiError::InvalidCallbackUrl)?;
+
+    match (uri.scheme_str(), uri.host()) {
+        (Some("http"), Some(_)) | (Some("https"), Some(_)) => Ok(()),
+        _ => Err(ApiError::InvalidCallbackUrl),
+    }
+}
+
+// Adds up counts of instruction JSON by the kind of instruction,
+// leaving out idle commands
+fn tally_by_kind(counts: impl Iterator<Item = (Value, i64)>) -> HashMap<String, i64> {
+    let mut tally = HashMap::new();
+
+    for (instruction_json, count) in counts {
+        let kind = serde_json::from_value::<Instruction>(instruction_json)
+            .map(|i| i.kind().to_string())
+            .unwrap_or_else(|_| "Unknown".to_string());
+
+        if kind != Instruction::Idle.kind() {
+            *tally.entry(kind).or_insert(0) += count;
+        }
+    }
+
+    tally
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        charging_time, check_callback_url, check_page, commands_csv, elapsed, energy_used,
+        expected_polls, instruction_intervals, pattern_transitions, read_instruction,
+        tally_by_kind, AbortReason, BatteryRequirements, CleaningPattern, Command, CommandConfig,
+        EnergyCoefficients, Instruction, QueueMode,
+    };
+    use crate::error::ApiError;
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn command_at(time_instruction: chrono::DateTime<Utc>) -> Command {
+        Command {
+            command_id: 0,
+            robot_serial_number: "testing".to_string(),
+            time_issued: time_instruction,
+            time_instruction,
+            instruction: Instruction::Idle,
+            completed: false,
+            status: None,
+            cancelled: false,
+            acknowledged_at: None,
+            delivered_at: None,
+            shift_id: None,
+            version: 0,
+            resource: None,
+            callback_url: None,
+            priority: 0,
+            progress: None,
+            issued_by: None,
+            deleted_at: None,
+            completed_at: None,
+        }
+    }
+
+    #[test]
+    fn commands_as_csv() {
+        let issued = Utc.ymd(2021, 3, 4).and_hms(9, 30, 0);
+        let abort = Command {
+            command_id: 7,
+            instruction: Instruction::Abort(AbortReason::Obstacle),
+            completed: true,
+            ..command_at(issued)
+        };
+        let task = Command {
+            command_id: 8,
+            instruction: Instruction::Task {
+                pattern: CleaningPattern::ZigZag,
+                speed: 80,
+                passes: 2,
+                zone: Some("aisle 4, \"north\"".to_string()),
+            },
+            ..command_at(issued + Duration::seconds(90))
+        };
+
+        assert_eq!(
+            "command_id,time_issued,time_instruction,instruction,completed\n\
+             7,2021-03-04T09:30:00Z,2021-03-04T09:30:00Z,Abort Obstacle,true\n\
+             8,2021-03-04T09:31:30Z,2021-03-04T09:31:30Z,\
+             \"Task ZigZag speed=80 passes=2 zone=aisle 4, \"\"north\"\"\",false\n",
+            commands_csv(&[abort, task])
+        );
+        assert_eq!(
+            "command_id,time_issued,time_instruction,instruction,completed\n",
+            commands_csv(&[])
+        );
+    }
+
+    #[test]
+    fn battery_requirements_per_pattern() {
+        let requirements = BatteryRequirements::default();
+
+        assert!(matches!(
+            requirements.check(&CleaningPattern::Circular, 55),
+            Err(ApiError::InsufficientBatteryForPattern)
+        ));
+        assert!(requirements.check(&CleaningPattern::Circular, 70).is_ok());
+        assert!(requirements.check(&CleaningPattern::ZigZag, 55).is_ok());
+    }
+
+    #[test]
+    fn intervals_between_instructions() {
+        let from = Utc::now();
+        let to = from + Duration::hours(3);
+        let instructions = vec![
+            (Instruction::Idle, from - Duration::hours(1)),
+            (
+                Instruction::task(CleaningPattern::ZigZag),
+                from + Duration::hours(1),
+            ),
+            (Instruction::Idle, from + Duration::hours(2)),
+        ];
+
+        assert_eq!(
+            vec![
+                (Instruction::Idle, from, from + Duration::hours(1)),
+                (
+                    Instruction::task(CleaningPattern::ZigZag),
+                    from + Duration::hours(1),
+                    from + Duration::hours(2)
+                ),
+                (Instruction::Idle, from + Duration::hours(2), to),
+            ],
+            instruction_intervals(instructions.into_iter(), from, to)
+        );
+        assert!(instruction_intervals(std::iter::empty(), from, to).is_empty());
+    }
+
+    #[test]
+    fn opcode_round_trip() {
+        let instructions = vec![
+            Instruction::Continue,
+            Instruction::Pause,
+            Instruction::Idle,
+            Instruction::Dock,
+            Instruction::ReturnToDock,
+            Instruction::Abort(AbortReason::LowBattery),
+            Instruction::Abort(AbortReason::Saftey),
+            Instruction::Abort(AbortReason::Obstacle),
+            Instruction::task(CleaningPattern::ZigZag),
+            Instruction::task(CleaningPattern::Circular),
+            Instruction::task(CleaningPattern::Spiral),
+            Instruction::task(CleaningPattern::Spot),
+            Instruction::task(CleaningPattern::Edge),
+            Instruction::Task {
+                pattern: CleaningPattern::Spiral,
+                speed: 40,
+                passes: 3,
+                zone: None,
+            },
+            Instruction::Task {
+                pattern: CleaningPattern::Spiral,
+                speed: 40,
+                passes: 3,
+                zone: Some("loading-bay".to_string()),
+            },
+            Instruction::Teleop {
+                session_id: "support-1".to_string(),
+            },
+            Instruction::RefreshConfig {
+                config_version: 300,
+            },
+        ];
+
+        for instruction in instructions {
+            let decoded = Instruction::from_opcode(instruction.opcode(), &instruction.payload());
+            assert_eq!(instruction, decoded.unwrap());
+        }
+    }
+
+    #[test]
+    fn opcode_ranges() {
+        assert_eq!(
+            0x10,
+            Instruction::Abort(AbortReason::LowBattery).opcode() & 0xF0
+        );
+        assert_eq!(
+            0x20,
+            Instruction::task(CleaningPattern::Circular).opcode() & 0xF0
+        );
+        assert!(Instruction::Idle.payload().is_empty());
+    }
+
+    #[test]
+    fn task_opcode_without_payload() {
+        assert_eq!(
+            Instruction::task(CleaningPattern::Spot),
+            Instruction::from_opcode(Instruction::task(CleaningPattern::Spot).opcode(), &[])
+                .unwrap()
+        );
+        assert!(matches!(
+            Instruction::from_opcode(0x20, &[50]),
+            Err(ApiError::InvalidOpcode)
+        ));
+    }
+
+    #[test]
+    fn invalid_opcodes() {
+        assert!(matches!(
+            Instruction::from_opcode(0xFF, &[]),
+            Err(ApiError::InvalidOpcode)
+        ));
+        assert!(matches!(
+            Instruction::from_opcode(0x30, &[0xC3, 0x28]),
+            Err(ApiError::InvalidOpcode)
+        ));
+        assert!(matches!(
+            Instruction::from_opcode(0x40, &[1, 2]),
+            Err(ApiError::InvalidOpcode)
+        ));
+    }
+
+    #[test]
+    fn energy_per_pattern() {
+        let coefficients = EnergyCoefficients::default();
+        let mut command = command_at(Utc::now());
+
+        command.instruction = Instruction::task(CleaningPattern::Circular);
+        assert_eq!(
+            45.0,
+            command.estimated_energy_wh(Duration::hours(1), &coefficients)
+        );
+
+        command.instruction = Instruction::task(CleaningPattern::ZigZag);
+        assert_eq!(
+            15.0,
+            command.estimated_energy_wh(Duration::minutes(30), &coefficients)
+        );
+
+        command.instruction = Instruction::Idle;
+        assert_eq!(
+            0.0,
+            command.estimated_energy_wh(Duration::hours(1), &coefficients)
+        );
+    }
+
+    #[test]
+    fn energy_of_completed_commands() {
+        let coefficients = EnergyCoefficients::default();
+        let start = Utc::now();
+        let mut commands = vec![
+            command_at(start),
+            command_at(start + Duration::hours(1)),
+            command_at(start + Duration::hours(2)),
+        ];
+        commands[0].instruction = Instruction::task(CleaningPattern::ZigZag);
+        commands[0].completed = true;
+        commands[1].instruction = Instruction::task(CleaningPattern::Circular);
+        commands[2].instruction = Instruction::task(CleaningPattern::Circular);
+        commands[2].completed = true;
+
+        // The uncompleted command in the middle isn't counted
+        assert_eq!(
+            30.0 + 22.5,
+            energy_used(&commands, start + Duration::minutes(150), &coefficients)
+        );
+    }
+
+    #[test]
+    fn tolerant_variant_names() {
+        for json in &[r#""zigzag""#, r#""ZigZag""#, r#""ZIG_ZAG""#, r#""zig zag""#] {
+            let pattern: CleaningPattern = serde_json::from_str(json).unwrap();
+            assert_eq!(CleaningPattern::ZigZag, pattern);
+        }
+
+        for json in &[r#""low_battery""#, r#""LOW-BATTERY""#, r#""lowBattery""#] {
+            let reason: AbortReason = serde_json::from_str(json).unwrap();
+            assert_eq!(AbortReason::LowBattery, reason);
+        }
+
+        for json in &[
+            r#"{"task":"zig_zag"}"#,
+            r#"{"TASK":"ZIGZAG"}"#,
+            r#"{"Task":"ZigZag"}"#,
+        ] {
+            let instruction: Instruction = serde_json::from_str(json).unwrap();
+            assert_eq!(Instruction::task(CleaningPattern::ZigZag), instruction);
+        }
+
+        let instruction: Instruction = serde_json::from_str(r#""IDLE""#).unwrap();
+        assert_eq!(Instruction::Idle, instruction);
+
+        let instruction: Instruction = serde_json::from_str(r#""return_to_dock""#).unwrap();
+        assert_eq!(Instruction::ReturnToDock, instruction);
+
+        let instruction: Instruction =
+            serde_json::from_str(r#"{"refresh_config":{"config_version":2}}"#).unwrap();
+        assert_eq!(
+            Instruction::RefreshConfig { config_version: 2 },
+            instruction
+        );
+    }
+
+    #[test]
+    fn canonical_names_are_written() {
+        let json = serde_json::to_string(&Instruction::task(CleaningPattern::ZigZag)).unwrap();
+        assert_eq!(
+            r#"{"Task":{"pattern":"ZigZag","speed":100,"passes":1}}"#,
+            json
+        );
+        assert!(serde_json::from_str::<CleaningPattern>(r#""figure_eight""#).is_err());
+    }
+
+    #[test]
+    fn task_zone() {
+        let zoned = Instruction::Task {
+            pattern: CleaningPattern::Spot,
+            speed: 100,
+            passes: 1,
+            zone: Some("aisle-4".to_string()),
+        };
+        let json = serde_json::to_value(&zoned).unwrap();
+
+        assert_eq!(json!("aisle-4"), json["Task"]["zone"]);
+        assert_eq!(zoned, serde_json::from_value(json).unwrap());
+        // Tasks without a zone are stored as they were before zones
+        assert_eq!(
+            json!({"Task": {"pattern": "Spot", "speed": 100, "passes": 1}}),
+            serde_json::to_value(Instruction::task(CleaningPattern::Spot)).unwrap()
+        );
+    }
+
+    #[test]
+    fn task_parameters() {
+        let task = Instruction::Task {
+            pattern: CleaningPattern::Edge,
+            speed: 60,
+            passes: 2,
+            zone: None,
+        };
+        let json = serde_json::to_string(&task).unwrap();
+        assert_eq!(task, serde_json::from_str(&json).unwrap());
+
+        // Tasks written before they had parameters read as the defaults
+        let legacy: Instruction = serde_json::from_str(r#"{"Task":"Edge"}"#).unwrap();
+        assert_eq!(Instruction::task(CleaningPattern::Edge), legacy);
+        let partial: Instruction =
+            serde_json::from_str(r#"{"Task":{"pattern":"Edge","passes":2}}"#).unwrap();
+        assert_eq!(
+            Instruction::Task {
+                pattern: CleaningPattern::Edge,
+                speed: 100,
+                passes: 2,
+                zone: None,
+            },
+            partial
+        );
+    }
+
+    #[test]
+    fn callback_urls() {
+        assert!(check_callback_url("http://localhost:8080/done").is_ok());
+        assert!(check_callback_url("https://example.com/hooks/1?x=y").is_ok());
+        assert!(matches!(
+            check_callback_url("ftp://example.com/done"),
+            Err(ApiError::InvalidCallbackUrl)
+        ));
+        assert!(check_callback_url("/done").is_err());
+        assert!(check_callback_url("not a url").is_err());
+    }
+
+    #[test]
+    fn polls_expected_in_window() {
+        assert_eq!(12, expected_polls(Duration::minutes(1)));
+        assert_eq!(0, expected_polls(Duration::seconds(4)));
+        assert_eq!(0, expected_polls(Duration::seconds(-30)));
+    }
+
+    #[test]
+    fn buffers_in_seconds() {
+        let config = CommandConfig::default();
+
+        assert_eq!(1000, config.time_issued_buffer.num_seconds());
+        assert_eq!(1000, config.time_instruction_buffer.num_seconds());
+    }
+
+    #[test]
+    fn buffers_from_vars() {
+        let config = CommandConfig::from_vars(|var| match var {
+            "TIME_ISSUED_BUFFER_SECS" => Some("30".to_string()),
+            "TIME_INSTRUCTION_BUFFER_SECS" => Some("soon".to_string()),
+            "LENIENT_INSTRUCTIONS" => Some("true".to_string()),
+            "COMMAND_RATE_LIMIT" => Some("50".to_string()),
+            "COMMAND_RATE_LIMIT_SECS" => Some("60".to_string()),
+            "COMMAND_QUEUE_DEPTH" => Some("5".to_string()),
+            "COMMAND_QUEUE_MODE" => Some("replace".to_string()),
+            "DB_RETRY_ATTEMPTS" => Some("5".to_string()),
+            "LOW_BATTERY_READINGS" => Some("5".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(Duration::seconds(30), config.time_issued_buffer);
+        assert!(config.lenient_instructions);
+        assert_eq!(50, config.rate_limit);
+        assert_eq!(Duration::seconds(60), config.rate_limit_window);
+        assert_eq!(5, config.max_queue_depth);
+        assert_eq!(QueueMode::Replace, config.queue_mode);
+        assert_eq!(5, config.retry.attempts);
+        assert_eq!(5, config.low_battery_readings);
+        assert_eq!(
+            CommandConfig::default().retry.base_delay,
+            config.retry.base_delay
+        );
+        assert_eq!(
+            CommandConfig::default().time_instruction_buffer,
+            config.time_instruction_buffer
+        );
+        assert_eq!(CommandConfig::default(), CommandConfig::from_vars(|_| None));
+    }
+
+    #[test]
+    fn elapsed_whole_seconds() {
+        let now = Utc::now();
+        let then = now - Duration::milliseconds(1_000_500);
+
+        assert_eq!(Duration::seconds(1000), elapsed(now, then));
+        assert_eq!(Duration::seconds(1000), elapsed(then, now));
+    }
+
+    #[test]
+    fn time_issued_buffer_boundary() {
+        let now = Utc::now();
+
+        let config = CommandConfig::default();
+
+        assert!(Command::check_time_issued(now - Duration::seconds(990), &config).is_ok());
+        assert!(Command::check_time_issued(now + Duration::seconds(990), &config).is_ok());
+        assert!(Command::check_time_issued(now - Duration::seconds(1010), &config).is_err());
+        assert!(Command::check_time_issued(now + Duration::seconds(1010), &config).is_err());
+    }
+
+    #[test]
+    fn time_instruction_buffer_boundary() {
+        let now = Utc::now();
+
+        let config = CommandConfig::default();
+
+        assert!(command_at(now - Duration::seconds(990)).valid_time_instruction(&[], &config));
+        assert!(command_at(now + Duration::seconds(990)).valid_time_instruction(&[], &config));
+        assert!(!command_at(now - Duration::seconds(1010)).valid_time_instruction(&[], &config));
+        assert!(!command_at(now + Duration::seconds(1010)).valid_time_instruction(&[], &config));
+    }
+
+    #[test]
+    fn corrupt_instructions() {
+        let strict = CommandConfig::default();
+        let lenient = CommandConfig {
+            lenient_instructions: true,
+            ..CommandConfig::default()
+        };
+        let corrupt = || json!({"Task": "Diagonal"});
+
+        assert!(matches!(
+            read_instruction(7, corrupt(), &strict),
+            Err(ApiError::CorruptInstruction { command_id: 7 })
+        ));
+        assert_eq!(
+            Instruction::Abort(AbortReason::Saftey),
+            read_instruction(7, corrupt(), &lenient).unwrap()
+        );
+        assert_eq!(
+            Instruction::Idle,
+            read_instruction(7, json!("Idle"), &strict).unwrap()
+        );
+    }
+
+    #[test]
+    fn tight_buffers() {
+        let now = Utc::now();
+        let config = CommandConfig {
+            time_issued_buffer: Duration::seconds(5),
+            time_instruction_buffer: Duration::seconds(5),
+            ..CommandConfig::default()
+        };
+
+        assert!(Command::check_time_issued(now - Duration::seconds(3), &config).is_ok());
+        assert!(Command::check_time_issued(now - Duration::seconds(10), &config).is_err());
+        assert!(command_at(now - Duration::seconds(3)).valid_time_instruction(&[], &config));
+        assert!(!command_at(now - Duration::seconds(10)).valid_time_instruction(&[], &config));
+    }
+
+    #[test]
+    fn charging_pauses_time_instruction() {
+        let now = Utc::now();
+        let task = command_at(now - Duration::seconds(1500));
+        let charging = [(now - Duration::seconds(1200), now - Duration::seconds(600))];
+
+        let config = CommandConfig::default();
+
+        assert!(!task.valid_time_instruction(&[], &config));
+        assert!(task.valid_time_instruction(&charging, &config));
+    }
+
+    #[test]
+    fn charging_time_within_window() {
+        let now = Utc::now();
+        let from = now - Duration::seconds(100);
+        let charging = [
+            // Only the part after `from` counts
+            (now - Duration::seconds(150), now - Duration::seconds(80)),
+            (now - Duration::seconds(50), now - Duration::seconds(40)),
+            // Entirely before `from`
+            (now - Duration::seconds(300), now - Duration::seconds(200)),
+        ];
+
+        assert_eq!(Duration::seconds(30), charging_time(&charging, from, now));
+        assert_eq!(Duration::zero(), charging_time(&[], from, now));
+    }
+
+    #[test]
+    fn equivalent_same_task() {
+        let task = Instruction::task(CleaningPattern::ZigZag);
+
+        assert!(task.equivalent(&Instruction::task(CleaningPattern::ZigZag), 0.0));
+        assert!(task.equivalent(&Instruction::task(CleaningPattern::ZigZag), 0.05));
+    }
+
+    #[test]
+    fn equivalent_speed_within_tolerance() {
+        let task = Instruction::task(CleaningPattern::ZigZag);
+        let at_speed = |speed| Instruction::Task {
+            pattern: CleaningPattern::ZigZag,
+            speed,
+            passes: 1,
+            zone: None,
+        };
+
+        assert!(task.equivalent(&at_speed(95), 0.05));
+        assert!(!task.equivalent(&at_speed(94), 0.05));
+        assert!(!task.equivalent(&at_speed(99), 0.0));
+    }
+
+    #[test]
+    fn equivalent_different_instruction() {
+        let task = Instruction::task(CleaningPattern::ZigZag);
+
+        assert!(!task.equivalent(&Instruction::task(CleaningPattern::Circular), 0.05));
+        assert!(!task.equivalent(
+            &Instruction::Task {
+                pattern: CleaningPattern::ZigZag,
+                speed: 100,
+                passes: 2,
+                zone: None,
+            },
+            0.05
+        ));
+        assert!(!task.equivalent(
+            &Instruction::Task {
+                pattern: CleaningPattern::ZigZag,
+                speed: 100,
+                passes: 1,
+                zone: Some("aisle-4".to_string()),
+            },
+            0.05
+        ));
+        assert!(!task.equivalent(&Instruction::Idle, 0.05));
+        assert!(!Instruction::Abort(AbortReason::Obstacle)
+            .equivalent(&Instruction::Abort(AbortReason::LowBattery), 0.05));
+    }
+
+    #[test]
+    fn tally_pending_by_kind() {
+        let counts = vec![
+            (json!({"Task": "ZigZag"}), 2),
+            (json!({"Task": "Circular"}), 3),
+            (json!({"Abort": "Obstacle"}), 1),
+            (json!("Pause"), 4),
+            (json!("Idle"), 7),
+        ];
+
+        let mut expected = HashMap::new();
+        expected.insert("Task".to_string(), 5);
+        expected.insert("Abort".to_string(), 1);
+        expected.insert("Pause".to_string(), 4);
+
+        assert_eq!(expected, tally_by_kind(counts.into_iter()));
+    }
+
+    #[test]
+    fn pattern_transitions_skip_repeats() {
+        let now = Utc::now();
+        let at = |s| now + Duration::seconds(s);
+        let tasks = vec![
+            (at(0), CleaningPattern::ZigZag),
+            (at(1), CleaningPattern::ZigZag),
+            (at(2), CleaningPattern::Circular),
+        ];
+
+        assert_eq!(
+            vec![(at(2), CleaningPattern::ZigZag, CleaningPattern::Circular)],
+            pattern_transitions(tasks.into_iter())
+        );
+        assert!(pattern_transitions(vec![(at(0), CleaningPattern::ZigZag)].into_iter()).is_empty());
+    }
+
+    #[test]
+    fn history_page_sizes() {
+        assert!(check_page(1, 0).is_ok());
+        assert!(check_page(500, 10_000).is_ok());
+
+        for (limit, offset) in &[(0, 0), (-5, 0), (501, 0), (10, -1)] {
+            assert!(matches!(
+                check_page(*limit, *offset),
+                Err(ApiError::InvalidPagination)
+            ));
+        }
     }
 }
@@ -0,0 +1,38 @@
+use crate::error::ApiError;
+use sqlx::postgres::PgPool;
+use tracing::error;
+
+/// The version of the fleet wide settings, robots that report an older
+/// version are told to refresh.
+pub async fn version(conn: &PgPool) -> Result<u64, ApiError> {
+    sqlx::query!(
+        r#"
+SELECT version FROM config_version
+        "#
+    )
+    .fetch_one(conn)
+    .await
+    .map(|c| c.version as u64)
+    .map_err(|e| {
+        error!(error = ?e, "Config Version");
+        ApiError::from(e)
+    })
+}
+
+/// Moves to a new version after a setting has changed, returning it
+pub async fn bump(conn: &PgPool) -> Result<u64, ApiError> {
+    sqlx::query!(
+        r#"
+UPDATE config_version
+SET version = version + 1
+RETURNING version
+        "#
+    )
+    .fetch_one(conn)
+    .await
+    .map(|c| c.version as u64)
+    .map_err(|e| {
+        error!(error = ?e, "Config Bump");
+        ApiError::from(e)
+    })
+}
@@ -0,0 +1,279 @@
+use crate::error::ApiError;
+use actix::clock::delay_for;
+use sqlx::postgres::PgPool;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{error, warn};
+
+// The columns the code expects on the Commands table, with their types
+// as named by information_schema.
+const COMMANDS_COLUMNS: &[(&str, &str)] = &[
+    ("command_id", "bigint"),
+    ("robot_serial_number", "text"),
+    ("time_issued", "timestamp with time zone"),
+    ("time_instruction", "timestamp with time zone"),
+    ("instruction", "jsonb"),
+    ("completed", "boolean"),
+    ("acknowledged_at", "timestamp with time zone"),
+    ("escalated_at", "timestamp with time zone"),
+    ("status", "text"),
+    ("status_detail", "text"),
+    ("cancelled", "boolean"),
+    ("cancel_reason", "text"),
+    ("delivered_at", "timestamp with time zone"),
+    ("shift_id", "text"),
+    ("version", "bigint"),
+    ("resource", "text"),
+    ("callback_url", "text"),
+    ("priority", "smallint"),
+    ("progress", "double precision"),
+    ("issued_by", "text"),
+    ("deleted_at", "timestamp with time zone"),
+    ("created_at", "timestamp with time zone"),
+    ("idempotency_key", "text"),
+    ("completed_at", "timestamp with time zone"),
+];
+
+// The tables the code needs before it can serve any requests
+const REQUIRED_TABLES: &[&str] = &["commands", "robot"];
+
+/// How many times a database call is tried when the connection drops, and
+/// how long to wait before the first retry, which doubles for each one after
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Runs the call, trying it again after a backoff while it fails with an
+/// error that might go away, such as losing the connection or the pool
+/// timing out. Any other error is returned straight away.
+///
+/// A call whose connection drops may have been run anyway, so only calls
+/// that are safe to repeat should be retried.
+pub async fn with_retry<T, F, Fut>(policy: &RetryPolicy, mut call: F) -> Result<T, ApiError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ApiError>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match call().await {
+            Err(e) if e.is_retryable() && attempt < policy.attempts => {
+                let delay = policy.base_delay * 2u32.saturating_pow(attempt - 1);
+                warn!(attempt, error = ?e, ?delay, "Database Retry");
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Checks the Commands table in the database has the columns the code
+/// expects, listing any that are missing or have the wrong type.
+pub async fn verify_schema(conn: &PgPool) -> Result<(), ApiError> {
+    let columns = sqlx::query!(
+        r#"
+SELECT column_name::TEXT AS "column_name!",
+       data_type::TEXT AS "data_type!"
+FROM information_schema.columns
+WHERE table_schema = current_schema() AND
+      table_name = 'commands'
+        "#
+    )
+    .fetch_all(conn)
+    .await
+    .map(|cols| {
+        cols.into_iter()
+            .map(|c| (c.column_name, c.data_type))
+            .collect::<Vec<_>>()
+    })
+    .map_err(|e| {
+        error!(error = ?e, "Verify Schema");
+        ApiError::from(e)
+    })?;
+
+    let discrepancies = schema_discrepancies(COMMANDS_COLUMNS, &columns);
+
+    if discrepancies.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::SchemaMismatch(discrepancies))
+    }
+}
+
+/// Checks the database can be reached and answers a query
+pub async fn ping(conn: &PgPool) -> Result<(), ApiError> {
+    sqlx::query!(r#"SELECT 1 AS "one!""#)
+        .fetch_one(conn)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            error!(error = ?e, "Database Ping");
+            ApiError::from(e)
+        })
+}
+
+/// Checks the database is ready to serve requests, it has to answer a
+/// query and have been migrated so the tables and columns the code needs
+/// are there
+pub async fn check_ready(conn: &PgPool) -> Result<(), ApiError> {
+    ping(conn).await?;
+
+    let tables = sqlx::query!(
+        r#"
+SELECT table_name::TEXT AS "table_name!"
+FROM information_schema.tables
+WHERE table_schema = current_schema()
+        "#
+    )
+    .fetch_all(conn)
+    .await
+    .map(|tables| tables.into_iter().map(|t| t.table_name).collect::<Vec<_>>())
+    .map_err(|e| {
+        error!(error = ?e, "Database Ready");
+        ApiError::from(e)
+    })?;
+
+    let missing = missing_tables(REQUIRED_TABLES, &tables);
+    if !missing.is_empty() {
+        return Err(ApiError::SchemaMismatch(missing));
+    }
+
+    verify_schema(conn).await
+}
+
+// The expected tables that aren't in the database
+fn missing_tables(expected: &[&str], actual: &[String]) -> Vec<String> {
+    expected
+        .iter()
+        .filter(|table| !actual.iter().any(|actual| actual == *table))
+        .map(|table| format!("{} is missing", table))
+        .collect()
+}
+
+// Compares the expected columns against those found in the database
+fn schema_discrepancies(expected: &[(&str, &str)], actual: &[(String, String)]) -> Vec<String> {
+    expected
+        .iter()
+        .filter_map(|(name, data_type)| {
+            match actual.iter().find(|(actual_name, _)| actual_name == name) {
+                None => Some(format!("Commands.{} is missing", name)),
+                Some((_, actual_type)) if actual_type != data_type => Some(format!(
+                    "Commands.{} is {}, expected {}",
+                    name, actual_type, data_type
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        missing_tables, schema_discrepancies, with_retry, RetryPolicy, COMMANDS_COLUMNS,
+        REQUIRED_TABLES,
+    };
+    use crate::error::ApiError;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    const QUICK_RETRIES: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        base_delay: Duration::from_millis(1),
+    };
+
+    // A call that fails with each of the errors in turn before succeeding
+    async fn calls_until_success(errors: Vec<ApiError>) -> (Result<u32, ApiError>, u32) {
+        let calls = Cell::new(0);
+        let result = with_retry(&QUICK_RETRIES, || {
+            let call = calls.get();
+            calls.set(call + 1);
+            let result = errors.get(call as usize).cloned().map_or(Ok(call), Err);
+            async move { result }
+        })
+        .await;
+
+        (result, calls.get())
+    }
+
+    #[actix_rt::test]
+    async fn retried_after_connection_lost() {
+        let (result, calls) = calls_until_success(vec![ApiError::ConnectionLost]).await;
+
+        assert_eq!(1, result.unwrap());
+        assert_eq!(2, calls);
+    }
+
+    #[actix_rt::test]
+    async fn not_retried_after_other_errors() {
+        let (result, calls) =
+            calls_until_success(vec![ApiError::ConstraintViolation("duplicate".to_string())]).await;
+
+        assert!(matches!(result, Err(ApiError::ConstraintViolation(_))));
+        assert_eq!(1, calls);
+    }
+
+    #[actix_rt::test]
+    async fn retries_give_up() {
+        let (result, calls) = calls_until_success(vec![ApiError::ConnectionLost; 5]).await;
+
+        assert!(matches!(result, Err(ApiError::ConnectionLost)));
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn required_tables() {
+        let tables = vec!["commands".to_string(), "robot".to_string()];
+        assert!(missing_tables(REQUIRED_TABLES, &tables).is_empty());
+
+        assert_eq!(
+            vec!["commands is missing", "robot is missing"],
+            missing_tables(REQUIRED_TABLES, &[])
+        );
+    }
+
+    fn columns() -> Vec<(String, String)> {
+        COMMANDS_COLUMNS
+            .iter()
+            .map(|(n, t)| (n.to_string(), t.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn matching_schema() {
+        assert!(schema_discrepancies(COMMANDS_COLUMNS, &columns()).is_empty());
+    }
+
+    #[test]
+    fn altered_schema() {
+        let mut altered: Vec<_> = columns()
+            .into_iter()
+            .filter(|(n, _)| n != "completed")
+            .collect();
+        altered
+            .iter_mut()
+            .filter(|(n, _)| n == "time_issued")
+            .for_each(|(_, t)| *t = "timestamp without time zone".to_string());
+
+        assert_eq!(
+            vec![
+                "Commands.time_issued is timestamp without time zone, expected timestamp with time zone",
+                "Commands.completed is missing",
+            ],
+            schema_discrepancies(COMMANDS_COLUMNS, &altered)
+        );
+    }
+}
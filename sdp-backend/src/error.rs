@@ -1,5 +1,6 @@
 use actix_web::{dev::Body, http::StatusCode, HttpResponse, ResponseError};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -13,50 +14,418 @@ pub enum ApiError {
     RobotInitializationFailed,
     SerializationError,
     AuthenticationFailed,
+    NotFound,
+    CannotCancelCompleted,
+    SchemaMismatch(Vec<String>),
+    SerialMismatch,
+    InsufficientBatteryForPattern,
+    InvalidOpcode,
+    VersionConflict,
+    InvalidCallbackUrl,
+    InvalidSchedule,
+    InvalidCsv(usize),
+    ConnectionLost,
+    QueryFailed(String),
+    ConstraintViolation(String),
+    InvalidPagination,
+    Unauthorized,
+    NoCommandsForRobot,
+    CorruptInstruction { command_id: i64 },
+    RateLimited,
+    InvalidSerial,
+    QueueFull,
+    InvalidOrganization,
+    InvalidBatteryLevel,
+}
+
+impl ApiError {
+    /// Whether trying again might succeed, which is only the case when the
+    /// database couldn't be reached
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ApiError::ConnectionLost)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => ApiError::ConnectionLost,
+            // A lookup that expected a row but the database had none
+            sqlx::Error::RowNotFound => ApiError::NotFound,
+            sqlx::Error::Database(e) => match e.code().as_deref() {
+                // Class 08 is a connection exception, 57P01 to 57P03 are the
+                // server shutting down or not accepting connections
+                Some(code)
+                    if code.starts_with("08") || matches!(code, "57P01" | "57P02" | "57P03") =>
+                {
+                    ApiError::ConnectionLost
+                }
+                // Class 23 is an integrity constraint violation
+                Some(code) if code.starts_with("23") => {
+                    ApiError::ConstraintViolation(e.message().to_string())
+                }
+                _ => ApiError::QueryFailed(e.message().to_string()),
+            },
+            e => ApiError::QueryFailed(e.to_string()),
+        }
+    }
 }
 
 impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let error_json = serde_json::to_string(&self).unwrap_or(String::new());
-        write!(f, "{}", error_json)
+        match self {
+            ApiError::CommandNotInTimeIssuedBuffer => {
+                write!(f, "command issued outside the allowed time window")
+            }
+            ApiError::DatabaseConnFailed => write!(f, "could not use the database"),
+            ApiError::HashingFailed => write!(f, "could not hash the password"),
+            ApiError::LoginFailedUserNotExist => write!(f, "no user with that name exists"),
+            ApiError::LoginFailedPasswordIncorrect => write!(f, "the password is incorrect"),
+            ApiError::CmdInstructionNotSupported => {
+                write!(
+                    f,
+                    "the instruction isn't supported from the robot's current state"
+                )
+            }
+            ApiError::RobotInitializationFailed => write!(f, "could not register the robot"),
+            ApiError::SerializationError => write!(f, "could not serialize the data"),
+            ApiError::AuthenticationFailed => write!(f, "authentication failed"),
+            ApiError::NotFound => write!(f, "not found"),
+            ApiError::CannotCancelCompleted => {
+                write!(f, "the command has already been completed")
+            }
+            ApiError::SchemaMismatch(discrepancies) => write!(
+                f,
+                "the database schema doesn't match: {}",
+                discrepancies.join(", ")
+            ),
+            ApiError::SerialMismatch => {
+                write!(f, "the command belongs to a different robot")
+            }
+            ApiError::InsufficientBatteryForPattern => {
+                write!(f, "the battery is too low for the cleaning pattern")
+            }
+            ApiError::InvalidOpcode => write!(f, "invalid instruction opcode"),
+            ApiError::VersionConflict => {
+                write!(f, "the command was changed by someone else")
+            }
+            ApiError::InvalidCallbackUrl => write!(f, "invalid callback URL"),
+            ApiError::InvalidSchedule => write!(f, "unsupported schedule"),
+            ApiError::InvalidCsv(line) => write!(f, "invalid CSV on line {}", line),
+            ApiError::ConnectionLost => write!(f, "lost the connection to the database"),
+            ApiError::QueryFailed(message) => write!(f, "database query failed: {}", message),
+            ApiError::ConstraintViolation(message) => {
+                write!(f, "database constraint violated: {}", message)
+            }
+            ApiError::InvalidPagination => {
+                write!(f, "the page limit or offset is out of range")
+            }
+            ApiError::Unauthorized => write!(f, "missing or incorrect robot API key"),
+            ApiError::NoCommandsForRobot => {
+                write!(f, "the robot hasn't been issued any commands")
+            }
+            ApiError::CorruptInstruction { command_id } => {
+                write!(
+                    f,
+                    "command {} has an instruction that can't be read",
+                    command_id
+                )
+            }
+            ApiError::RateLimited => {
+                write!(
+                    f,
+                    "too many commands have been issued to the robot recently"
+                )
+            }
+            ApiError::InvalidSerial => {
+                write!(f, "serial numbers are 1 to 64 letters, digits, `-` or `_`")
+            }
+            ApiError::QueueFull => {
+                write!(f, "the robot already has too many commands waiting")
+            }
+            ApiError::InvalidOrganization => {
+                write!(
+                    f,
+                    "organization ids are 1 to 64 letters, digits, `-` or `_`"
+                )
+            }
+            ApiError::InvalidBatteryLevel => write!(f, "battery levels are from 0 to 100"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ApiError {
+    /// A stable name for the error that clients can branch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::CommandNotInTimeIssuedBuffer => "CommandNotInTimeIssuedBuffer",
+            ApiError::DatabaseConnFailed => "DatabaseConnFailed",
+            ApiError::HashingFailed => "HashingFailed",
+            ApiError::LoginFailedUserNotExist => "LoginFailedUserNotExist",
+            ApiError::LoginFailedPasswordIncorrect => "LoginFailedPasswordIncorrect",
+            ApiError::CmdInstructionNotSupported => "CmdInstructionNotSupported",
+            ApiError::RobotInitializationFailed => "RobotInitializationFailed",
+            ApiError::SerializationError => "SerializationError",
+            ApiError::AuthenticationFailed => "AuthenticationFailed",
+            ApiError::NotFound => "NotFound",
+            ApiError::CannotCancelCompleted => "CannotCancelCompleted",
+            ApiError::SchemaMismatch(_) => "SchemaMismatch",
+            ApiError::SerialMismatch => "SerialMismatch",
+            ApiError::InsufficientBatteryForPattern => "InsufficientBatteryForPattern",
+            ApiError::InvalidOpcode => "InvalidOpcode",
+            ApiError::VersionConflict => "VersionConflict",
+            ApiError::InvalidCallbackUrl => "InvalidCallbackUrl",
+            ApiError::InvalidSchedule => "InvalidSchedule",
+            ApiError::InvalidCsv(_) => "InvalidCsv",
+            ApiError::ConnectionLost => "ConnectionLost",
+            ApiError::QueryFailed(_) => "QueryFailed",
+            ApiError::ConstraintViolation(_) => "ConstraintViolation",
+            ApiError::InvalidPagination => "InvalidPagination",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::NoCommandsForRobot => "NoCommandsForRobot",
+            ApiError::CorruptInstruction { .. } => "CorruptInstruction",
+            ApiError::RateLimited => "RateLimited",
+            ApiError::InvalidSerial => "InvalidSerial",
+            ApiError::QueueFull => "QueueFull",
+            ApiError::InvalidOrganization => "InvalidOrganization",
+            ApiError::InvalidBatteryLevel => "InvalidBatteryLevel",
+        }
+    }
+
+    // The body sent with the error's response
+    pub(crate) fn body(&self) -> Value {
+        json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        })
     }
 }
 
 impl From<ApiError> for HttpResponse {
     fn from(error: ApiError) -> Self {
-        // Get the json value for the error
-        let error_json = match serde_json::to_string(&error) {
-            Ok(j) => j,
-            _ => return HttpResponse::InternalServerError().finish(),
-        };
+        HttpResponse::build(error.status_code()).json(error.body())
+    }
+}
 
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
         // Give an appropiate error code for the error.
-        match error {
-            ApiError::CommandNotInTimeIssuedBuffer => HttpResponse::BadRequest().json(error_json),
-            ApiError::DatabaseConnFailed => HttpResponse::InternalServerError().json(error_json),
-            ApiError::HashingFailed => HttpResponse::InternalServerError().json(error_json),
-            ApiError::LoginFailedUserNotExist => HttpResponse::Unauthorized().json(error_json),
-            ApiError::LoginFailedPasswordIncorrect => HttpResponse::Unauthorized().json(error_json),
-            ApiError::CmdInstructionNotSupported => HttpResponse::BadRequest().json(error_json),
-            ApiError::RobotInitializationFailed => HttpResponse::BadRequest().json(error_json),
-            ApiError::SerializationError => HttpResponse::InternalServerError().json(error_json),
-            ApiError::AuthenticationFailed => HttpResponse::Unauthorized().json(error_json),
+        match self {
+            ApiError::CommandNotInTimeIssuedBuffer => StatusCode::BAD_REQUEST,
+            ApiError::DatabaseConnFailed => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::HashingFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::LoginFailedUserNotExist => StatusCode::UNAUTHORIZED,
+            ApiError::LoginFailedPasswordIncorrect => StatusCode::UNAUTHORIZED,
+            ApiError::CmdInstructionNotSupported => StatusCode::CONFLICT,
+            ApiError::RobotInitializationFailed => StatusCode::BAD_REQUEST,
+            ApiError::SerializationError => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::AuthenticationFailed => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::CannotCancelCompleted => StatusCode::CONFLICT,
+            ApiError::SchemaMismatch(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::SerialMismatch => StatusCode::FORBIDDEN,
+            ApiError::InsufficientBatteryForPattern => StatusCode::CONFLICT,
+            ApiError::InvalidOpcode => StatusCode::BAD_REQUEST,
+            ApiError::VersionConflict => StatusCode::CONFLICT,
+            ApiError::InvalidCallbackUrl => StatusCode::BAD_REQUEST,
+            ApiError::InvalidSchedule => StatusCode::BAD_REQUEST,
+            ApiError::InvalidCsv(_) => StatusCode::BAD_REQUEST,
+            ApiError::ConnectionLost => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::QueryFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::ConstraintViolation(_) => StatusCode::CONFLICT,
+            ApiError::InvalidPagination => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NoCommandsForRobot => StatusCode::NOT_FOUND,
+            ApiError::CorruptInstruction { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InvalidSerial => StatusCode::BAD_REQUEST,
+            ApiError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InvalidOrganization => StatusCode::BAD_REQUEST,
+            ApiError::InvalidBatteryLevel => StatusCode::BAD_REQUEST,
         }
     }
+
+    fn error_response(&self) -> HttpResponse<Body> {
+        self.clone().into()
+    }
 }
 
-impl ResponseError for ApiError {
-    fn status_code(&self) -> StatusCode {
-        let error: ApiError = self.clone();
-        let res: HttpResponse = error.into();
+#[cfg(test)]
+mod tests {
+    use super::ApiError;
+    use actix_web::{http::StatusCode, ResponseError};
+    use serde_json::json;
+    use std::io;
 
-        res.status()
+    #[test]
+    fn status_codes() {
+        let statuses = [
+            (
+                ApiError::CommandNotInTimeIssuedBuffer,
+                StatusCode::BAD_REQUEST,
+            ),
+            (ApiError::CmdInstructionNotSupported, StatusCode::CONFLICT),
+            (
+                ApiError::SerializationError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                ApiError::DatabaseConnFailed,
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (ApiError::ConnectionLost, StatusCode::SERVICE_UNAVAILABLE),
+            (ApiError::NotFound, StatusCode::NOT_FOUND),
+            (ApiError::SerialMismatch, StatusCode::FORBIDDEN),
+            (ApiError::AuthenticationFailed, StatusCode::UNAUTHORIZED),
+            (ApiError::Unauthorized, StatusCode::UNAUTHORIZED),
+            (ApiError::NoCommandsForRobot, StatusCode::NOT_FOUND),
+            (ApiError::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (ApiError::InvalidSerial, StatusCode::BAD_REQUEST),
+            (ApiError::QueueFull, StatusCode::TOO_MANY_REQUESTS),
+            (ApiError::InvalidOrganization, StatusCode::BAD_REQUEST),
+            (ApiError::InvalidBatteryLevel, StatusCode::BAD_REQUEST),
+        ];
+
+        for (error, status) in &statuses {
+            assert_eq!(*status, error.status_code());
+            assert_eq!(*status, error.error_response().status());
+        }
     }
 
-    fn error_response(&self) -> HttpResponse<Body> {
-        let error: ApiError = self.clone();
-        let res: HttpResponse = error.into();
+    #[test]
+    fn error_body() {
+        assert_eq!(
+            json!({
+                "error": "InvalidCsv",
+                "message": "invalid CSV on line 3",
+            }),
+            ApiError::InvalidCsv(3).body()
+        );
+    }
+
+    #[test]
+    fn display_messages() {
+        let messages = [
+            (
+                ApiError::CommandNotInTimeIssuedBuffer,
+                "command issued outside the allowed time window",
+            ),
+            (ApiError::DatabaseConnFailed, "could not use the database"),
+            (ApiError::HashingFailed, "could not hash the password"),
+            (
+                ApiError::LoginFailedUserNotExist,
+                "no user with that name exists",
+            ),
+            (
+                ApiError::LoginFailedPasswordIncorrect,
+                "the password is incorrect",
+            ),
+            (
+                ApiError::CmdInstructionNotSupported,
+                "the instruction isn't supported from the robot's current state",
+            ),
+            (
+                ApiError::RobotInitializationFailed,
+                "could not register the robot",
+            ),
+            (ApiError::SerializationError, "could not serialize the data"),
+            (ApiError::AuthenticationFailed, "authentication failed"),
+            (ApiError::NotFound, "not found"),
+            (
+                ApiError::CannotCancelCompleted,
+                "the command has already been completed",
+            ),
+            (
+                ApiError::SchemaMismatch(vec!["missing column a".to_string(), "b".to_string()]),
+                "the database schema doesn't match: missing column a, b",
+            ),
+            (
+                ApiError::SerialMismatch,
+                "the command belongs to a different robot",
+            ),
+            (
+                ApiError::InsufficientBatteryForPattern,
+                "the battery is too low for the cleaning pattern",
+            ),
+            (ApiError::InvalidOpcode, "invalid instruction opcode"),
+            (
+                ApiError::VersionConflict,
+                "the command was changed by someone else",
+            ),
+            (ApiError::InvalidCallbackUrl, "invalid callback URL"),
+            (ApiError::InvalidSchedule, "unsupported schedule"),
+            (ApiError::InvalidCsv(4), "invalid CSV on line 4"),
+            (
+                ApiError::ConnectionLost,
+                "lost the connection to the database",
+            ),
+            (
+                ApiError::QueryFailed("syntax error".to_string()),
+                "database query failed: syntax error",
+            ),
+            (
+                ApiError::ConstraintViolation("duplicate key".to_string()),
+                "database constraint violated: duplicate key",
+            ),
+            (
+                ApiError::InvalidPagination,
+                "the page limit or offset is out of range",
+            ),
+        ];
+
+        for (error, message) in &messages {
+            assert_eq!(*message, error.to_string());
+        }
+    }
+
+    #[test]
+    fn boxed_as_error() {
+        let error: Box<dyn std::error::Error> = Box::new(ApiError::NotFound);
 
-        res.into_body()
+        assert_eq!("not found", error.to_string());
+    }
+
+    #[test]
+    fn connection_errors_are_lost_connections() {
+        let errors = vec![
+            sqlx::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+            sqlx::Error::PoolTimedOut,
+            sqlx::Error::PoolClosed,
+            sqlx::Error::WorkerCrashed,
+        ];
+
+        for error in errors {
+            let error = ApiError::from(error);
+            assert!(matches!(error, ApiError::ConnectionLost));
+            assert!(error.is_retryable());
+        }
+    }
+
+    #[test]
+    fn missing_rows_are_not_found() {
+        let error = ApiError::from(sqlx::Error::RowNotFound);
+
+        assert!(matches!(error, ApiError::NotFound));
+        assert_eq!(StatusCode::NOT_FOUND, error.status_code());
+    }
+
+    #[test]
+    fn other_errors_are_failed_queries() {
+        let errors = vec![
+            sqlx::Error::ColumnNotFound("missing".to_string()),
+            sqlx::Error::Protocol("unexpected message".to_string()),
+        ];
+
+        for error in errors {
+            let error = ApiError::from(error);
+            assert!(matches!(error, ApiError::QueryFailed(_)));
+            assert!(!error.is_retryable());
+        }
     }
 }
@@ -0,0 +1,130 @@
+use crate::command::{read_instruction, CommandConfig, Instruction};
+use crate::error::ApiError;
+use crate::robot::OrganizationId;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::postgres::PgPool;
+use tracing::error;
+
+// Most robots included in an export, the most recently seen are kept
+const MAX_EXPORTED_ROBOTS: i64 = 500;
+
+/// What a robot is doing as of its latest command
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RobotState {
+    pub robot_serial_number: String,
+    pub instruction: Option<Instruction>,
+    pub completed: Option<bool>,
+    pub battery_level: Option<i64>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// The state of every robot in the organization as one JSON document, for
+/// use on a status page
+///
+/// At most `MAX_EXPORTED_ROBOTS` are included, `robot_count` is always the
+/// size of the organization's whole fleet and `truncated` says whether any
+/// were left out.
+pub async fn export_state(conn: &PgPool, organization: &OrganizationId) -> Result<Value, ApiError> {
+    let rows = sqlx::query!(
+        r#"
+SELECT R.robot_serial_number,
+       R.battery_level,
+       C.command_id AS "command_id?",
+       C.instruction AS "instruction?",
+       C.completed AS "completed?",
+       S.last_seen,
+       COUNT(*) OVER () AS "robot_count!"
+FROM robot R
+LEFT JOIN LATERAL
+(SELECT C1.command_id, C1.instruction, C1.completed FROM Commands C1
+ WHERE C1.robot_serial_number = R.robot_serial_number
+ ORDER BY C1.time_issued DESC
+ LIMIT 1) C ON true
+LEFT JOIN LATERAL
+(SELECT MAX(C2.delivered_at) AS last_seen FROM Commands C2
+ WHERE C2.robot_serial_number = R.robot_serial_number) S ON true
+WHERE starts_with(R.robot_serial_number, $2 || ':')
+ORDER BY S.last_seen DESC NULLS LAST, R.robot_serial_number
+LIMIT $1
+        "#,
+        MAX_EXPORTED_ROBOTS,
+        organization.as_str()
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Fleet Export State");
+        ApiError::from(e)
+    })?;
+
+    let robot_count = rows.first().map(|r| r.robot_count).unwrap_or(0);
+    let robots = rows
+        .into_iter()
+        .map(|r| {
+            let instruction = match (r.command_id, r.instruction) {
+                (Some(command_id), Some(instruction)) => Some(read_instruction(
+                    command_id,
+                    instruction,
+                    CommandConfig::global(),
+                )?),
+                _ => None,
+            };
+
+            Ok(RobotState {
+                robot_serial_number: r.robot_serial_number,
+                instruction,
+                completed: r.completed,
+                battery_level: r.battery_level,
+                last_seen: r.last_seen,
+            })
+        })
+        .collect::<Result<_, ApiError>>()?;
+
+    Ok(fleet_document(robots, robot_count, Utc::now()))
+}
+
+// Wraps up the robots' states along with how many there are in total
+fn fleet_document(robots: Vec<RobotState>, robot_count: i64, exported_at: DateTime<Utc>) -> Value {
+    json!({
+        "exported_at": exported_at,
+        "robot_count": robot_count,
+        "truncated": (robots.len() as i64) < robot_count,
+        "robots": robots,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fleet_document, RobotState};
+    use crate::command::Instruction;
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn empty_fleet() {
+        let document = fleet_document(Vec::new(), 0, Utc::now());
+
+        assert_eq!(json!(0), document["robot_count"]);
+        assert_eq!(json!(false), document["truncated"]);
+        assert_eq!(json!([]), document["robots"]);
+    }
+
+    #[test]
+    fn truncated_fleet() {
+        let robot = RobotState {
+            robot_serial_number: "robot".to_string(),
+            instruction: Some(Instruction::Idle),
+            completed: Some(false),
+            battery_level: Some(90),
+            last_seen: None,
+        };
+        let document = fleet_document(vec![robot], 3, Utc::now());
+
+        assert_eq!(json!(3), document["robot_count"]);
+        assert_eq!(json!(true), document["truncated"]);
+        assert_eq!(json!("Idle"), document["robots"][0]["instruction"]);
+        assert_eq!(json!(90), document["robots"][0]["battery_level"]);
+    }
+}
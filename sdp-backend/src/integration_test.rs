@@ -1,42 +1,5474 @@
+use crate::api;
+use crate::auth::Token;
+use crate::battery;
 use crate::command::Command;
-use crate::command::Instruction::{Abort, Idle};
-use crate::poll::Poll;
+use crate::command::{
+    AbortReason, BatteryRequirements, CleaningPattern, CommandConfig, EnergyCoefficients,
+    ExportFormat, Instruction, Instruction::Abort, Instruction::Idle, InstructionFilter, QueueMode,
+    TaskStatus, LOSE_CONNECTION_AFTER_COMMIT,
+};
+use crate::config;
+use crate::db::RetryPolicy;
+use crate::error::ApiError;
+use crate::fleet;
+use crate::metrics;
+use crate::notify::{CompletionSink, Notification};
+use crate::poll::{Poll, TaskReport};
+use crate::push;
+use crate::retention::{self, RetentionPolicy};
+use crate::robot::{OrganizationId, Robot, RobotSerial};
+use crate::scheduler;
+use crate::shift::ShiftConfig;
+use crate::test_db::{db_connect, isolated_db_connect, missing_db_pool};
+use crate::transition::Transition;
+use crate::user::User;
 
+use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web_actors::ws;
+use chrono::{Duration, TimeZone, Utc};
+use futures::{SinkExt, StreamExt};
 use sqlx::postgres::PgPool;
-use std::env;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
-const TEST_SERIAL: &str = "testing1";
+// Gives each test its own robot so tests can run against a shared database
+fn unique_serial(name: &str) -> RobotSerial {
+    RobotSerial::parse(&format!("{}-{}", name, Utc::now().timestamp_nanos())).unwrap()
+}
+
+// Keeps hold of every notification it is sent
+#[derive(Default)]
+struct RecordingSink {
+    notifications: Mutex<Vec<Notification>>,
+}
+
+impl CompletionSink for RecordingSink {
+    fn notify(&self, notification: Notification) {
+        self.notifications.lock().unwrap().push(notification);
+    }
+}
+
+impl RecordingSink {
+    fn for_robot(&self, serial: &str) -> Vec<Notification> {
+        self.notifications
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|n| match n {
+                Notification::Unacknowledged(c) => c.robot_serial_number == serial,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[actix_rt::test]
+async fn set_idle_poll() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("set_idle_poll");
+
+    // Set the robot to the idle state
+    Command::idle(conn, &serial).await.unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial,
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    let result = Poll::poll(conn, &poll).await.unwrap();
+
+    assert_eq!(Idle, result.instruction);
+}
+
+#[actix_rt::test]
+async fn pending_large_backlog() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("pending_large_backlog");
+    let now = Utc::now();
+    // The backlog is filled faster than robots are normally issued commands
+    let config = CommandConfig {
+        rate_limit: i64::MAX,
+        max_queue_depth: i64::MAX,
+        ..CommandConfig::default()
+    };
+
+    // Fill the backlog, the last command has the latest time instruction
+    for i in 0..200 {
+        Command::new(
+            conn,
+            &serial,
+            now,
+            now + Duration::seconds(i),
+            &Instruction::task(CleaningPattern::ZigZag),
+            &config,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+    Command::new(
+        conn,
+        &serial,
+        now,
+        now + Duration::seconds(200),
+        &Instruction::task(CleaningPattern::Circular),
+        &config,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    // Deep in the backlog, only read if every command ahead of it is
+    // passed over
+    sqlx::query!(
+        r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
+VALUES ($1, $2, $2, $3)
+        "#,
+        serial.as_str(),
+        now,
+        serde_json::json!({"Task": "Diagonal"})
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    // Only the next command is fetched, so the unreadable one doesn't
+    // fail the poll even though stored commands are read strictly
+    assert!(!CommandConfig::global().lenient_instructions);
+    let pending = Command::pending(conn, &serial).await.unwrap();
+
+    assert_eq!(serial, pending.robot_serial_number);
+    assert_eq!(
+        Instruction::task(CleaningPattern::Circular),
+        pending.instruction
+    );
+}
+
+#[actix_rt::test]
+async fn escalate_unacknowledged_once() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("escalate_unacknowledged");
+    let issued = Utc::now() - Duration::seconds(600);
+
+    let command = Command::new(
+        conn,
+        &serial,
+        issued,
+        issued,
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let sink = RecordingSink::default();
+    let timeout = Duration::seconds(300);
+
+    // Issued long ago, but the robot hasn't been given it yet
+    Command::escalate_unacknowledged(conn, timeout, &sink)
+        .await
+        .unwrap();
+    assert!(sink.for_robot(&serial).is_empty());
+
+    // The timeout starts once it is handed over
+    command.deliver(conn).await.unwrap();
+    Command::escalate_unacknowledged(conn, timeout, &sink)
+        .await
+        .unwrap();
+    assert!(sink.for_robot(&serial).is_empty());
+
+    sqlx::query!(
+        "UPDATE Commands SET delivered_at = $2 WHERE command_id = $1",
+        command.id(),
+        Utc::now() - Duration::seconds(600)
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    Command::escalate_unacknowledged(conn, timeout, &sink)
+        .await
+        .unwrap();
+    Command::escalate_unacknowledged(conn, timeout, &sink)
+        .await
+        .unwrap();
+
+    let notifications = sink.for_robot(&serial);
+    assert_eq!(1, notifications.len());
+    match &notifications[0] {
+        Notification::Unacknowledged(c) => {
+            assert_eq!(Instruction::task(CleaningPattern::ZigZag), c.instruction)
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn poll_equivalent_task_keeps_current() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_equivalent_task");
+
+    let current = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Instruction::task(CleaningPattern::ZigZag),
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    let result = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(current.instruction, result.instruction);
+    assert_eq!(current.robot_serial_number, result.robot_serial_number);
+
+    let at_speed = |speed| Poll {
+        instruction: Instruction::Task {
+            pattern: CleaningPattern::ZigZag,
+            speed,
+            passes: 1,
+            zone: None,
+        },
+        ..poll.clone()
+    };
+
+    // A small slowdown is still the same task
+    let result = Poll::poll(conn, &at_speed(97)).await.unwrap();
+    assert_eq!(current.id(), result.id());
+    // but slowing right down is a new one, which takes over from it
+    let slowed = Poll::poll(conn, &at_speed(80)).await.unwrap();
+    assert_ne!(current.id(), slowed.id());
+    assert_eq!(at_speed(80).instruction, slowed.instruction);
+    assert!(
+        Command::get_including_deleted(conn, current.id())
+            .await
+            .unwrap()
+            .completed
+    );
+
+    let poll = Poll {
+        instruction: Instruction::task(CleaningPattern::Circular),
+        ..poll
+    };
+
+    let circular = Poll::poll(conn, &poll).await.unwrap();
+    assert_ne!(slowed.id(), circular.id());
+    assert_eq!(poll.instruction, circular.instruction);
+
+    // A task the robot hasn't been given yet is handed over rather than
+    // replaced with the one it is running
+    let queued = Command::task(conn, &serial, &CleaningPattern::Spot)
+        .await
+        .unwrap();
+    assert_eq!(queued.id(), Poll::poll(conn, &poll).await.unwrap().id());
+}
+
+#[actix_rt::test]
+async fn poll_reports_task_failure() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_reports_task_failure");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: Some(TaskReport {
+            command_id: task.id(),
+            status: TaskStatus::Failed,
+            detail: Some("brush jammed".to_string()),
+        }),
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    let next = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(Idle, next.instruction);
+
+    let failed = sqlx::query!(
+        "SELECT completed, status, status_detail FROM Commands WHERE command_id = $1",
+        task.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+
+    assert!(failed.completed);
+    assert_eq!(
+        Some(TaskStatus::Failed),
+        failed.status.and_then(|s| serde_json::from_str(&s).ok())
+    );
+    assert_eq!(Some("brush jammed".to_string()), failed.status_detail);
+}
+
+#[actix_rt::test]
+async fn report_on_missing_command() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("report_missing_command");
+
+    let result =
+        Command::complete_with_status(conn, &serial, -1, &TaskStatus::Succeeded, None).await;
+    assert!(matches!(result, Err(ApiError::NotFound)));
+}
+
+#[actix_rt::test]
+async fn report_on_other_robots_command() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("report_wrong_serial");
+    let other = unique_serial("report_wrong_serial_other");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    let result =
+        Command::complete_with_status(conn, &other, task.id(), &TaskStatus::Failed, None).await;
+    assert!(matches!(result, Err(ApiError::SerialMismatch)));
+
+    let untouched = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+    assert!(!untouched.completed);
+    assert_eq!(None, untouched.status);
+
+    // Polling with the bad report still gets the robot its next command
+    let poll = Poll {
+        robot_serial_number: other.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: Some(TaskReport {
+            command_id: task.id(),
+            status: TaskStatus::Failed,
+            detail: None,
+        }),
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    assert_eq!(Idle, Poll::poll(conn, &poll).await.unwrap().instruction);
+}
+
+#[actix_rt::test]
+async fn command_id_matches_stored_row() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("command_id");
+    let now = Utc::now();
+
+    let command = Command::new(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::Spot),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let stored = sqlx::query!(
+        "SELECT command_id FROM Commands WHERE robot_serial_number = $1",
+        serial.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert_eq!(stored.command_id, command.id());
+
+    // The same command read back twice is only kept once
+    let current = Command::current(conn, &serial).await.unwrap();
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    let commands: HashSet<Command> = vec![current, pending].into_iter().collect();
+    assert_eq!(1, commands.len());
+    assert!(commands.iter().all(|c| c.id() == command.id()));
+
+    // Including after it has changed
+    command.acknowledge(conn).await.unwrap();
+    let acknowledged = Command::get_including_deleted(conn, command.id())
+        .await
+        .unwrap();
+    assert_ne!(command.version, acknowledged.version);
+    let commands: HashSet<Command> = vec![command, acknowledged].into_iter().collect();
+    assert_eq!(1, commands.len());
+}
+
+#[actix_rt::test]
+async fn active_at_timeline() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("active_at_timeline");
+    let now = Utc::now();
+
+    let timeline = vec![
+        (
+            now - Duration::seconds(300),
+            Instruction::task(CleaningPattern::ZigZag),
+        ),
+        (now - Duration::seconds(200), Abort(AbortReason::Obstacle)),
+        (
+            now - Duration::seconds(100),
+            Instruction::task(CleaningPattern::Circular),
+        ),
+    ];
+    for (at, instruction) in &timeline {
+        Command::new(
+            conn,
+            &serial,
+            *at,
+            *at,
+            instruction,
+            &CommandConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    let active =
+        |seconds_ago| Command::active_at(conn, &serial, now - Duration::seconds(seconds_ago));
+
+    assert_eq!(None, active(350).await.unwrap());
+    assert_eq!(
+        Instruction::task(CleaningPattern::ZigZag),
+        active(250).await.unwrap().unwrap().instruction
+    );
+    assert_eq!(
+        Abort(AbortReason::Obstacle),
+        active(200).await.unwrap().unwrap().instruction
+    );
+    assert_eq!(
+        Instruction::task(CleaningPattern::Circular),
+        active(0).await.unwrap().unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn cancel_by_id_pending() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("cancel_by_id_pending");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    Command::cancel_by_id(conn, task.id(), "wrong room".to_string())
+        .await
+        .unwrap();
+
+    let cancelled = sqlx::query!(
+        "SELECT cancelled, cancel_reason FROM Commands WHERE command_id = $1",
+        task.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert!(cancelled.cancelled);
+    assert_eq!(Some("wrong room".to_string()), cancelled.cancel_reason);
+
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_eq!(Idle, pending.instruction);
+}
+
+#[actix_rt::test]
+async fn cancel_by_id_completed() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("cancel_by_id_completed");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    task.complete(conn).await.unwrap();
+
+    let result = Command::cancel_by_id(conn, task.id(), "too late".to_string()).await;
+    assert!(matches!(result, Err(ApiError::CannotCancelCompleted)));
+
+    let result = Command::cancel_by_id(conn, -1, "missing".to_string()).await;
+    assert!(matches!(result, Err(ApiError::NotFound)));
+}
+
+#[actix_rt::test]
+async fn clone_pending_queue() {
+    let conn = &db_connect().await;
+    let from = unique_serial("clone_pending_from");
+    let to = unique_serial("clone_pending_to");
+    let now = Utc::now();
+
+    Command::new(
+        conn,
+        &from,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    Command::new(
+        conn,
+        &from,
+        now,
+        now + Duration::seconds(10),
+        &Instruction::task(CleaningPattern::Circular),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let cloned = Command::clone_pending(conn, &from, &to).await.unwrap();
+    assert_eq!(2, cloned);
+
+    let queued = sqlx::query!(
+        "SELECT COUNT(*) AS count FROM Commands WHERE robot_serial_number = $1 AND completed = false",
+        to.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert_eq!(Some(2), queued.count);
+
+    for serial in &[&from, &to] {
+        let pending = Command::pending(conn, serial).await.unwrap();
+        assert_eq!(
+            Instruction::task(CleaningPattern::Circular),
+            pending.instruction
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn clone_pending_skips_deleted() {
+    let conn = &db_connect().await;
+    let from = unique_serial("clone_deleted_from");
+    let to = unique_serial("clone_deleted_to");
+    let now = Utc::now();
+
+    let deleted = Command::new(
+        conn,
+        &from,
+        now,
+        now,
+        &Instruction::Dock,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    deleted.soft_delete(conn).await.unwrap();
+    Command::new(
+        conn,
+        &from,
+        now,
+        now + Duration::seconds(1),
+        &Instruction::task(CleaningPattern::Circular),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let cloned = Command::clone_pending(conn, &from, &to).await.unwrap();
+    assert_eq!(1, cloned);
+
+    let copies = sqlx::query!(
+        "SELECT instruction FROM Commands WHERE robot_serial_number = $1",
+        to.as_str()
+    )
+    .fetch_all(conn)
+    .await
+    .unwrap();
+    assert_eq!(1, copies.len());
+    assert_eq!(
+        serde_json::to_value(Instruction::task(CleaningPattern::Circular)).unwrap(),
+        copies[0].instruction
+    );
+}
+
+#[actix_rt::test]
+async fn clone_pending_skips_delivered() {
+    let conn = &db_connect().await;
+    let from = unique_serial("clone_delivered_from");
+    let to = unique_serial("clone_delivered_to");
+    let now = Utc::now();
+
+    let running = Command::new(
+        conn,
+        &from,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    running.deliver(conn).await.unwrap();
+    Command::new(
+        conn,
+        &from,
+        now,
+        now + Duration::seconds(1),
+        &Instruction::ReturnToDock,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let cloned = Command::clone_pending(conn, &from, &to).await.unwrap();
+    assert_eq!(1, cloned);
+
+    let copies = sqlx::query!(
+        "SELECT instruction FROM Commands WHERE robot_serial_number = $1",
+        to.as_str()
+    )
+    .fetch_all(conn)
+    .await
+    .unwrap();
+    assert_eq!(1, copies.len());
+    assert_eq!(
+        serde_json::to_value(Instruction::ReturnToDock).unwrap(),
+        copies[0].instruction
+    );
+}
+
+#[actix_rt::test]
+async fn clone_pending_keeps_options() {
+    let conn = &db_connect().await;
+    let from = unique_serial("clone_options_from");
+    let to = unique_serial("clone_options_to");
+    let dock = format!("dock-{}", from);
+    let now = Utc::now();
+
+    Command::new_with_priority(conn, &from, now, now, &Instruction::Dock, 5)
+        .await
+        .unwrap();
+    Command::new_with_callback(
+        conn,
+        &from,
+        now,
+        now + Duration::seconds(1),
+        &Instruction::ReturnToDock,
+        "https://example.com/done",
+    )
+    .await
+    .unwrap();
+    Command::new_with_resource(
+        conn,
+        &from,
+        now,
+        now + Duration::seconds(2),
+        &Instruction::Dock,
+        &dock,
+    )
+    .await
+    .unwrap();
+
+    let cloned = Command::clone_pending(conn, &from, &to).await.unwrap();
+    assert_eq!(3, cloned);
+
+    let copies = sqlx::query!(
+        "SELECT priority, callback_url, resource FROM Commands WHERE robot_serial_number = $1 ORDER BY time_instruction",
+        to.as_str()
+    )
+    .fetch_all(conn)
+    .await
+    .unwrap();
+    assert_eq!(3, copies.len());
+    assert_eq!(5, copies[0].priority);
+    assert_eq!(
+        Some("https://example.com/done"),
+        copies[1].callback_url.as_deref()
+    );
+    assert_eq!(Some(dock.as_str()), copies[2].resource.as_deref());
+}
+
+#[actix_rt::test]
+async fn task_group_single_insert() {
+    let conn = &db_connect().await;
+    let serials: Vec<RobotSerial> = (0..10)
+        .map(|i| unique_serial(&format!("task_group_{}", i)))
+        .collect();
+
+    let commands = Command::task_group(conn, &serials, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    assert_eq!(10, commands.len());
+
+    for serial in &serials {
+        let current = Command::current(conn, serial).await.unwrap();
+        assert_eq!(
+            Instruction::task(CleaningPattern::ZigZag),
+            current.instruction
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn task_group_checks_rate_limit() {
+    let conn = &db_connect().await;
+    let busy = unique_serial("task_group_busy");
+    let free = unique_serial("task_group_free");
+
+    for _ in 0..CommandConfig::global().rate_limit {
+        let now = Utc::now();
+        Command::new(
+            conn,
+            &busy,
+            now,
+            now,
+            &Idle,
+            CommandConfig::global(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    let result = Command::task_group(
+        conn,
+        &[free.clone(), busy.clone()],
+        &CleaningPattern::ZigZag,
+    )
+    .await;
+    assert!(matches!(result, Err(ApiError::RateLimited)));
+    assert!(matches!(
+        Command::current(conn, &free).await,
+        Err(ApiError::NoCommandsForRobot)
+    ));
+    assert_eq!(
+        Idle,
+        Command::current(conn, &busy).await.unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn new_group_checks_each_time_issued() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("new_group_time_issued");
+    let now = Utc::now();
+    let stale = now - Duration::days(1);
+
+    let commands = vec![
+        (serial.clone(), now, now, Idle),
+        (serial.clone(), stale, stale, Idle),
+    ];
+
+    let result = Command::new_group(conn, &commands).await;
+    assert!(matches!(
+        result,
+        Err(ApiError::CommandNotInTimeIssuedBuffer)
+    ));
+    assert!(Command::active_at(conn, &serial, now)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_rt::test]
+async fn new_group_checks_rate_limit() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("new_group_rate_limit");
+    let other = unique_serial("new_group_rate_limit");
+    let now = Utc::now();
+    let limit = CommandConfig::global().rate_limit as usize;
+
+    // One more than the robot is allowed, alongside a robot that is fine
+    let mut commands = vec![(other.clone(), now, now, Idle)];
+    commands.extend((0..=limit).map(|_| (serial.clone(), now, now, Idle)));
+
+    let result = Command::new_group(conn, &commands).await;
+    assert!(matches!(result, Err(ApiError::RateLimited)));
+    for robot in &[&serial, &other] {
+        assert!(matches!(
+            Command::current(conn, robot).await,
+            Err(ApiError::NoCommandsForRobot)
+        ));
+    }
+
+    commands.pop();
+    assert_eq!(
+        limit + 1,
+        Command::new_group(conn, &commands).await.unwrap().len()
+    );
+}
+
+#[actix_rt::test]
+async fn verify_schema_matches() {
+    let conn = &db_connect().await;
+
+    crate::db::verify_schema(conn).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn robot_path_from_polls() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("robot_path_from_polls");
+    let start = Utc::now();
+
+    Command::idle(conn, &serial).await.unwrap();
+
+    for i in 0..3 {
+        let poll = Poll {
+            robot_serial_number: serial.clone(),
+            instruction: Idle,
+            battery_level: 90,
+            report: None,
+            x: Some(i as f64),
+            y: Some(2.0 * i as f64),
+            heading: None,
+            acknowledged: None,
+            config_version: None,
+            progress: None,
+            firmware_version: None,
+        };
+        Poll::poll(conn, &poll).await.unwrap();
+    }
+
+    let path = Robot::path(conn, &serial, start, Utc::now()).await.unwrap();
+    let points: Vec<_> = path.iter().map(|(_, x, y)| (*x, *y)).collect();
+    assert_eq!(vec![(0.0, 0.0), (1.0, 2.0), (2.0, 4.0)], points);
+}
+
+#[actix_rt::test]
+async fn robot_path_downsampled() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("robot_path_downsampled");
+    let now = Utc::now();
+
+    sqlx::query!(
+        r#"
+INSERT INTO path_points (robot_serial_number, recorded_at, x, y)
+SELECT $1, $2::TIMESTAMPTZ - make_interval(secs => i), i, i
+FROM generate_series(1, 2000) AS i
+        "#,
+        serial.as_str(),
+        now
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    let path = Robot::path(conn, &serial, now - Duration::hours(1), now)
+        .await
+        .unwrap();
+    assert_eq!(500, path.len());
+
+    // Oldest point first, newest last
+    assert_eq!(2000.0, path[0].1);
+    assert_eq!(1.0, path[499].1);
+    assert!(path.windows(2).all(|w| w[0].0 < w[1].0));
+}
+
+#[actix_rt::test]
+async fn poll_withholds_until_acknowledged() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_withholds_until_ack");
+    Robot::new(conn, &serial).await.unwrap();
+    Robot::set_require_ack(conn, &serial, true).await.unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Instruction::task(CleaningPattern::ZigZag),
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // The robot is handed a task but never acknowledges it
+    let first = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let delivered = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(first.id(), delivered.id());
+
+    // A newer task is held back while the first is unacknowledged
+    let second = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let withheld = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(first.id(), withheld.id());
+
+    // Once acknowledged the queued task is sent
+    let poll = Poll {
+        acknowledged: Some(first.id()),
+        ..poll
+    };
+    let next = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(second.id(), next.id());
+}
+
+#[actix_rt::test]
+async fn total_pending_across_fleet() {
+    let conn = &db_connect().await;
+
+    for i in 0..3 {
+        let serial = unique_serial(&format!("total_pending_{}", i));
+        Command::task(conn, &serial, &CleaningPattern::ZigZag)
+            .await
+            .unwrap();
+        Command::abort(conn, &serial, &AbortReason::Obstacle)
+            .await
+            .unwrap();
+        Command::idle(conn, &serial).await.unwrap();
+    }
+
+    // Other tests share the database, so there may be more than ours
+    let breakdown = Command::pending_breakdown(conn).await.unwrap();
+    assert!(breakdown["Task"] >= 3);
+    assert!(breakdown["Abort"] >= 3);
+    assert!(!breakdown.contains_key("Idle"));
+    assert!(Command::total_pending(conn).await.unwrap() >= 6);
+}
+
+#[actix_rt::test]
+async fn poll_low_battery_needs_consecutive_readings() {
+    let conn = &db_connect().await;
+
+    for readings in [1, 2, 4] {
+        let serial = unique_serial("poll_low_battery_readings");
+        let config = CommandConfig {
+            low_battery_readings: readings,
+            ..CommandConfig::default()
+        };
+        Command::idle(conn, &serial).await.unwrap();
+
+        let poll = |battery_level| Poll {
+            robot_serial_number: serial.clone(),
+            instruction: Idle,
+            battery_level,
+            report: None,
+            x: None,
+            y: None,
+            heading: None,
+            acknowledged: None,
+            config_version: None,
+            progress: None,
+            firmware_version: None,
+        };
+
+        // A glitch shorter than the limit is ignored, and a good reading
+        // resets the count
+        for _ in 1..readings {
+            assert_eq!(
+                Idle,
+                Poll::poll_using(conn, &poll(20), &config)
+                    .await
+                    .unwrap()
+                    .instruction
+            );
+        }
+        assert_eq!(
+            Idle,
+            Poll::poll_using(conn, &poll(90), &config)
+                .await
+                .unwrap()
+                .instruction
+        );
+
+        for _ in 1..readings {
+            assert_eq!(
+                Idle,
+                Poll::poll_using(conn, &poll(20), &config)
+                    .await
+                    .unwrap()
+                    .instruction
+            );
+        }
+        assert_eq!(
+            Abort(AbortReason::LowBattery),
+            Poll::poll_using(conn, &poll(20), &config)
+                .await
+                .unwrap()
+                .instruction
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn poll_answers_without_battery_history() {
+    // The history is broken on purpose, which would affect other tests
+    let conn = &isolated_db_connect("battery_history_fails").await;
+    let serial = unique_serial("battery_history_fails");
+    let config = CommandConfig {
+        low_battery_readings: 2,
+        ..CommandConfig::default()
+    };
+    Robot::new(conn, &serial).await.unwrap();
+    let poll = |battery_level| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // One low reading makes it into the history before it stops taking any
+    assert_eq!(
+        Idle,
+        Poll::poll_using(conn, &poll(20), &config)
+            .await
+            .unwrap()
+            .instruction
+    );
+    for statement in [
+        r#"
+CREATE FUNCTION reject_battery_reading() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'battery history unavailable';
+END
+$$ LANGUAGE plpgsql
+        "#,
+        r#"
+CREATE TRIGGER reject_battery_reading BEFORE INSERT ON battery_readings
+FOR EACH ROW EXECUTE FUNCTION reject_battery_reading()
+        "#,
+    ] {
+        sqlx::query(statement).execute(conn).await.unwrap();
+    }
+
+    // The poll still answers, counting the stored reading along with this one
+    assert_eq!(
+        Abort(AbortReason::LowBattery),
+        Poll::poll_using(conn, &poll(20), &config)
+            .await
+            .unwrap()
+            .instruction
+    );
+    assert_eq!(Some(20), Robot::battery_level(conn, &serial).await.unwrap());
+}
+
+#[actix_rt::test]
+async fn pattern_changes_history() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("pattern_changes_history");
+    let now = Utc::now();
+    let at = |seconds_ago| now - Duration::seconds(seconds_ago);
+
+    let history = vec![
+        (at(40), Instruction::task(CleaningPattern::ZigZag)),
+        (at(30), Idle),
+        (at(20), Instruction::task(CleaningPattern::Circular)),
+        (at(10), Instruction::task(CleaningPattern::ZigZag)),
+    ];
+    for (time, instruction) in &history {
+        Command::new(
+            conn,
+            &serial,
+            *time,
+            *time,
+            instruction,
+            &CommandConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
+    let changes = Command::pattern_changes(conn, &serial, at(60))
+        .await
+        .unwrap();
+    let changes: Vec<_> = changes
+        .into_iter()
+        .map(|(_, from, to)| (from, to))
+        .collect();
+
+    assert_eq!(
+        vec![
+            (CleaningPattern::ZigZag, CleaningPattern::Circular),
+            (CleaningPattern::Circular, CleaningPattern::ZigZag),
+        ],
+        changes
+    );
+}
+
+#[actix_rt::test]
+async fn pick_best_robot_by_battery_and_idle() {
+    let conn = &db_connect().await;
+    let now = Utc::now();
+    let rested = unique_serial("pick_best_rested");
+    let busy = unique_serial("pick_best_busy");
+    let flat = unique_serial("pick_best_flat");
+
+    // (serial, battery level, last task)
+    let fleet = vec![
+        (&rested, 90, now - Duration::minutes(15)),
+        (&busy, 95, now),
+        (&flat, 60, now - Duration::minutes(15)),
+    ];
+    for (serial, battery_level, last_task) in &fleet {
+        Robot::record_battery_reading(conn, serial, *battery_level, false)
+            .await
+            .unwrap();
+        Command::new(
+            conn,
+            serial,
+            *last_task,
+            *last_task,
+            &Instruction::task(CleaningPattern::ZigZag),
+            &CommandConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        Command::idle(conn, serial).await.unwrap();
+    }
+
+    let candidates = vec![busy.to_string(), flat.to_string(), rested.to_string()];
+    let best = crate::scheduler::pick_best_robot(conn, &candidates)
+        .await
+        .unwrap();
+    assert_eq!(rested, best);
+
+    let none = crate::scheduler::pick_best_robot(conn, &[]).await;
+    assert!(matches!(none, Err(ApiError::NotFound)));
+}
+
+#[actix_rt::test]
+async fn teleop_lifecycle() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("teleop_lifecycle");
+    let teleop = Instruction::Teleop {
+        session_id: "support-1".to_string(),
+    };
+
+    let poll = |instruction: &Instruction, battery_level| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: instruction.clone(),
+        battery_level,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // The idle robot is handed the teleop session
+    Command::idle(conn, &serial).await.unwrap();
+    Command::start_teleop(conn, &serial, "support-1")
+        .await
+        .unwrap();
+    let result = Poll::poll(conn, &poll(&Idle, 90)).await.unwrap();
+    assert_eq!(teleop, result.instruction);
+
+    // Pending tasks wait while the robot is being driven
+    Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let result = Poll::poll(conn, &poll(&teleop, 90)).await.unwrap();
+    assert_eq!(teleop, result.instruction);
+
+    // Ending the session sends the robot on to its next command
+    Command::end_teleop(conn, &serial).await.unwrap();
+    let result = Poll::poll(conn, &poll(&teleop, 90)).await.unwrap();
+    assert_ne!(teleop, result.instruction);
+}
+
+#[actix_rt::test]
+async fn teleop_battery_abort() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("teleop_battery_abort");
+    let teleop = Instruction::Teleop {
+        session_id: "support-2".to_string(),
+    };
+
+    Command::start_teleop(conn, &serial, "support-2")
+        .await
+        .unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: teleop.clone(),
+        battery_level: 10,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    assert_eq!(teleop, Poll::poll(conn, &poll).await.unwrap().instruction);
+    assert_eq!(teleop, Poll::poll(conn, &poll).await.unwrap().instruction);
+    assert_eq!(
+        Abort(AbortReason::LowBattery),
+        Poll::poll(conn, &poll).await.unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn orphaned_commands() {
+    let conn = &db_connect().await;
+    let registered = unique_serial("orphaned_registered");
+    let unregistered = unique_serial("orphaned_unregistered");
+    let polling = unique_serial("orphaned_polling");
+
+    Robot::new(conn, &registered).await.unwrap();
+    Command::task(conn, &registered, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::task(conn, &unregistered, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    // Polling doesn't register the robot
+    let poll = Poll {
+        robot_serial_number: polling.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: Some("1.0.0".to_string()),
+    };
+    Poll::poll(conn, &poll).await.unwrap();
+    // Nor does pausing part way through a task
+    let pausing = unique_serial("orphaned_pausing");
+    let task = Command::task(conn, &pausing, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    for instruction in [task.instruction, Instruction::Pause] {
+        let poll = Poll {
+            robot_serial_number: pausing.clone(),
+            instruction,
+            ..poll.clone()
+        };
+        Poll::poll(conn, &poll).await.unwrap();
+    }
+
+    let orphaned = Command::orphaned(conn).await.unwrap();
+    assert!(orphaned.contains(&unregistered.to_string()));
+    assert!(orphaned.contains(&polling.to_string()));
+    assert!(orphaned.contains(&pausing.to_string()));
+    assert!(!orphaned.contains(&registered.to_string()));
+
+    // Setting up a robot that isn't registered doesn't register it either
+    assert!(matches!(
+        Robot::set_require_ack(conn, &unregistered, true).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(matches!(
+        Robot::set_pending_fallback(conn, &unregistered, &Instruction::Dock).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(matches!(
+        Robot::rotate_key(conn, &unregistered).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(matches!(
+        Robot::set_timezone(conn, &unregistered, chrono_tz::Asia::Tokyo).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(matches!(
+        Robot::set_webhook_url(conn, &unregistered, None).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(Command::orphaned(conn)
+        .await
+        .unwrap()
+        .contains(&unregistered.to_string()));
+}
+
+#[actix_rt::test]
+async fn ack_command() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("ack_command");
+
+    let command = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::ack(conn, &serial, command.id()).await.unwrap();
+
+    let acked = Command::current(conn, &serial).await.unwrap();
+    assert!(acked.acknowledged_at.is_some());
+}
+
+#[actix_rt::test]
+async fn ack_command_wrong_serial() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("ack_wrong_serial");
+    let other = unique_serial("ack_wrong_serial_other");
+
+    let command = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    let result = Command::ack(conn, &other, command.id()).await;
+    assert!(matches!(result, Err(ApiError::SerialMismatch)));
+
+    let unacked = Command::current(conn, &serial).await.unwrap();
+    assert!(unacked.acknowledged_at.is_none());
+}
+
+#[actix_rt::test]
+async fn ack_missing_command() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("ack_missing");
+
+    let result = Command::ack(conn, &serial, -1).await;
+    assert!(matches!(result, Err(ApiError::NotFound)));
+}
+
+#[actix_rt::test]
+async fn poll_with_unknown_ack() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_unknown_ack");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: Some(-1),
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // The robot is still handed its task
+    assert_eq!(task.id(), Poll::poll(conn, &poll).await.unwrap().id());
+}
+
+#[actix_rt::test]
+async fn prune_old_path_points() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("prune_old_path_points");
+    let only_old = unique_serial("prune_only_old_path_points");
+    let now = Utc::now();
+
+    // Two old points and a recent one, then a robot with only old points
+    sqlx::query!(
+        r#"
+INSERT INTO path_points (robot_serial_number, recorded_at, x, y)
+VALUES ($1, $3::TIMESTAMPTZ - interval '10 days', 1, 1),
+       ($1, $3::TIMESTAMPTZ - interval '9 days', 2, 2),
+       ($1, $3::TIMESTAMPTZ, 3, 3),
+       ($2, $3::TIMESTAMPTZ - interval '10 days', 4, 4),
+       ($2, $3::TIMESTAMPTZ - interval '9 days', 5, 5)
+        "#,
+        serial.as_str(),
+        only_old.as_str(),
+        now
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    let policy = RetentionPolicy {
+        path_points: Duration::days(1),
+        ..RetentionPolicy::default()
+    };
+    let report = retention::prune(conn, policy).await.unwrap();
+    assert!(report.path_points >= 3);
+
+    let remaining = Robot::path(conn, &serial, now - Duration::days(30), now)
+        .await
+        .unwrap();
+    let points: Vec<_> = remaining.iter().map(|(_, x, y)| (*x, *y)).collect();
+    assert_eq!(vec![(3.0, 3.0)], points);
+
+    // The latest point is kept even though it is past the retention window
+    let remaining = Robot::path(conn, &only_old, now - Duration::days(30), now)
+        .await
+        .unwrap();
+    let points: Vec<_> = remaining.iter().map(|(_, x, y)| (*x, *y)).collect();
+    assert_eq!(vec![(5.0, 5.0)], points);
+}
+
+#[actix_rt::test]
+async fn new_if_current() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("new_if_current");
+    let task = Instruction::task(CleaningPattern::ZigZag);
+
+    Command::idle(conn, &serial).await.unwrap();
+
+    // The robot is idle so the task is issued
+    let issued = Command::new_if_current(conn, &serial, &Idle, &task)
+        .await
+        .unwrap()
+        .expect("the robot to be idle");
+    assert_eq!(task, issued.instruction);
+
+    // It is no longer idle so nothing changes
+    let skipped = Command::new_if_current(
+        conn,
+        &serial,
+        &Idle,
+        &Instruction::task(CleaningPattern::Circular),
+    )
+    .await
+    .unwrap();
+    assert!(skipped.is_none());
+
+    let current = Command::current(conn, &serial).await.unwrap();
+    assert_eq!(issued.id(), current.id());
+}
+
+#[actix_rt::test]
+async fn new_if_current_agrees_with_current() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("new_if_current_tiebreak");
+    let task = Instruction::task(CleaningPattern::ZigZag);
+    let now = Utc::now();
+
+    // Issued at the same time, so the task inserted last is current
+    Command::new_unbuffered(conn, &serial, now, now, &Idle)
+        .await
+        .unwrap();
+    Command::new_unbuffered(conn, &serial, now, now, &task)
+        .await
+        .unwrap();
+
+    let commands = push::subscribe(&serial);
+    futures::pin_mut!(commands);
+
+    let issued = Command::new_if_current(conn, &serial, &task, &Instruction::Pause)
+        .await
+        .unwrap()
+        .expect("the robot to be on the task");
+    assert!(Command::new_if_current(conn, &serial, &task, &Idle)
+        .await
+        .unwrap()
+        .is_none());
+
+    // It is pushed to the robot like any other command
+    assert_eq!(issued.id(), commands.next().await.unwrap().command_id);
+}
+
+#[actix_rt::test]
+async fn task_needs_battery_for_pattern() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("task_needs_battery");
+    let requirements = BatteryRequirements::default();
+
+    Robot::register(conn, &serial, "sweeper-s1", 30)
+        .await
+        .unwrap();
+    Robot::record_battery_reading(conn, &serial, 55, false)
+        .await
+        .unwrap();
+
+    let circular =
+        Command::task_with_battery(conn, &serial, &CleaningPattern::Circular, &requirements).await;
+    assert!(matches!(
+        circular,
+        Err(ApiError::InsufficientBatteryForPattern)
+    ));
+
+    let zigzag = Command::task_with_battery(conn, &serial, &CleaningPattern::ZigZag, &requirements)
+        .await
+        .unwrap();
+    assert_eq!(
+        Instruction::task(CleaningPattern::ZigZag),
+        zigzag.instruction
+    );
+}
+
+#[actix_rt::test]
+async fn instruction_intervals() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("instruction_intervals");
+    let now = Utc::now();
+    let minutes = |m| now - Duration::minutes(m);
+
+    Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(30),
+        &Idle,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(20),
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(10),
+        &Instruction::task(CleaningPattern::Circular),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let intervals = Command::intervals(conn, &serial, minutes(25), now)
+        .await
+        .unwrap();
+    let intervals: Vec<_> = intervals
+        .into_iter()
+        .map(|(i, start, end)| (i, start.timestamp(), end.timestamp()))
+        .collect();
+
+    assert_eq!(
+        vec![
+            (Idle, minutes(25).timestamp(), minutes(20).timestamp()),
+            (
+                Instruction::task(CleaningPattern::ZigZag),
+                minutes(20).timestamp(),
+                minutes(10).timestamp()
+            ),
+            (
+                Instruction::task(CleaningPattern::Circular),
+                minutes(10).timestamp(),
+                now.timestamp()
+            ),
+        ],
+        intervals
+    );
+}
+
+#[actix_rt::test]
+async fn safety_abort_skips_time_buffer() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("safety_abort_unbuffered");
+    let long_ago = Utc::now() - Duration::hours(2);
+    let abort = Abort(AbortReason::Saftey);
+
+    let buffered = Command::new(
+        conn,
+        &serial,
+        long_ago,
+        long_ago,
+        &abort,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await;
+    assert!(matches!(
+        buffered,
+        Err(ApiError::CommandNotInTimeIssuedBuffer)
+    ));
+
+    let unbuffered = Command::new_unbuffered(conn, &serial, long_ago, long_ago, &abort)
+        .await
+        .unwrap();
+    assert_eq!(abort, unbuffered.instruction);
+
+    let aborted = Command::abort(conn, &serial, &AbortReason::Saftey)
+        .await
+        .unwrap();
+    assert_eq!(abort, aborted.instruction);
+    assert_eq!(unbuffered.priority, aborted.priority);
+    assert!(aborted.priority > 0);
+}
+
+#[actix_rt::test]
+async fn commands_by_shift() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("commands_by_shift");
+    let shift_id = unique_serial("shift");
+
+    let mut tagged = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    tagged.tag_shift(conn, &shift_id).await.unwrap();
+    Command::idle(conn, &serial).await.unwrap();
+
+    let in_shift = Command::by_shift(conn, &shift_id).await.unwrap();
+    assert_eq!(
+        vec![tagged.id()],
+        in_shift.iter().map(|c| c.id()).collect::<Vec<_>>()
+    );
+    assert_eq!(Some(shift_id.to_string()), in_shift[0].shift_id);
+}
+
+#[actix_rt::test]
+async fn battery_non_recovering() {
+    // Flagging reads the history of every robot with readings
+    let conn = &isolated_db_connect("battery_non_recovering").await;
+    let healthy = unique_serial("battery_healthy");
+    let failing = unique_serial("battery_failing");
+    let working = unique_serial("battery_working");
+
+    for serial in &[&healthy, &failing, &working] {
+        Robot::register(conn, serial, "sweeper-s1", 30)
+            .await
+            .unwrap();
+    }
+    let now = Utc::now();
+    for serial in &[&healthy, &failing] {
+        Command::new(
+            conn,
+            serial,
+            now,
+            now,
+            &Instruction::Dock,
+            &CommandConfig::default(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+    Command::task(conn, &working, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    for level in &[30, 50, 70, 90] {
+        Robot::record_battery_reading(conn, &healthy, *level, false)
+            .await
+            .unwrap();
+    }
+    for level in &[30, 40, 45, 44] {
+        Robot::record_battery_reading(conn, &failing, *level, false)
+            .await
+            .unwrap();
+        // Draining while it works says nothing about the battery
+        Robot::record_battery_reading(conn, &working, *level, false)
+            .await
+            .unwrap();
+    }
+
+    let flagged = battery::non_recovering(conn, Duration::hours(1))
+        .await
+        .unwrap();
+    assert!(flagged.contains(&failing.to_string()));
+    assert!(!flagged.contains(&healthy.to_string()));
+    assert!(!flagged.contains(&working.to_string()));
+}
+
+#[actix_rt::test]
+async fn pending_uses_robot_fallback() {
+    let conn = &db_connect().await;
+    let docking = unique_serial("pending_fallback_dock");
+    let idling = unique_serial("pending_fallback_idle");
+
+    Robot::new(conn, &docking).await.unwrap();
+    Robot::set_pending_fallback(conn, &docking, &Instruction::Dock)
+        .await
+        .unwrap();
+    // Registering starts the robot off idle, which has to be done with
+    // before there is nothing waiting
+    Command::current(conn, &docking)
+        .await
+        .unwrap()
+        .complete(conn)
+        .await
+        .unwrap();
+
+    let pending = Command::pending(conn, &docking).await.unwrap();
+    assert_eq!(Instruction::Dock, pending.instruction);
+
+    let pending = Command::pending(conn, &idling).await.unwrap();
+    assert_eq!(Idle, pending.instruction);
+}
+
+#[actix_rt::test]
+async fn total_energy_of_completed_tasks() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("total_energy");
+    let now = Utc::now();
+    let minutes = |m| now - Duration::minutes(m);
+
+    let zigzag = Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(60),
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let circular = Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(30),
+        &Instruction::task(CleaningPattern::Circular),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    Command::new(
+        conn,
+        &serial,
+        now,
+        minutes(10),
+        &Idle,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    zigzag.complete(conn).await.unwrap();
+    circular.complete(conn).await.unwrap();
+
+    // 30 minutes at 30W and 20 minutes at 45W
+    let energy = Command::total_energy(conn, &serial, minutes(90), &EnergyCoefficients::default())
+        .await
+        .unwrap();
+    assert!((energy - 30.0).abs() < 0.01);
+}
+
+#[actix_rt::test]
+async fn idle_robots_for_dispatch() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let idle = unique_serial("idle_robots_idle").in_organization(&acme);
+    let tasking = unique_serial("idle_robots_tasking").in_organization(&acme);
+    let aborted = unique_serial("idle_robots_aborted").in_organization(&acme);
+    let elsewhere = unique_serial("idle_robots_elsewhere").in_organization(&globex);
+
+    Command::task(conn, &idle, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::idle(conn, &idle).await.unwrap();
+    Command::idle(conn, &tasking).await.unwrap();
+    Command::task(conn, &tasking, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::idle(conn, &aborted).await.unwrap();
+    Command::abort(conn, &aborted, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+
+    Command::idle(conn, &elsewhere).await.unwrap();
+
+    // Only the organization's own robots are offered
+    let robots = Command::idle_robots(conn, &acme).await.unwrap();
+    assert_eq!(vec![idle.to_string()], robots);
+    assert_eq!(
+        vec![elsewhere.to_string()],
+        Command::idle_robots(conn, &globex).await.unwrap()
+    );
+}
+
+#[actix_rt::test]
+async fn stale_version_rejected() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("stale_version");
+
+    let mut task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let stale_version = task.version;
+
+    // Someone else changes the command first
+    task.tag_shift(conn, "morning").await.unwrap();
+
+    let result =
+        Command::cancel_at_version(conn, task.id(), stale_version, "stale".to_string()).await;
+    assert!(matches!(result, Err(ApiError::VersionConflict)));
+
+    Command::cancel_at_version(conn, task.id(), task.version, "current".to_string())
+        .await
+        .unwrap();
+    let cancelled = Command::current(conn, &serial).await.unwrap();
+    assert!(cancelled.cancelled);
+    assert_eq!(task.version + 1, cancelled.version);
+}
+
+#[actix_rt::test]
+async fn poll_refreshes_stale_config() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_refreshes_config");
+
+    Command::idle(conn, &serial).await.unwrap();
+    let latest_version = config::bump(conn).await.unwrap();
+
+    let mut poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: Some(latest_version - 1),
+        progress: None,
+        firmware_version: None,
+    };
+
+    let result = Poll::poll(conn, &poll).await.unwrap();
+    assert!(matches!(
+        result.instruction,
+        Instruction::RefreshConfig { config_version } if config_version >= latest_version
+    ));
+
+    // Once the robot has refreshed it goes back to its usual commands
+    poll.config_version = Some(config::version(conn).await.unwrap());
+    let result = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(Idle, result.instruction);
+}
+
+#[actix_rt::test]
+async fn transition_frequency_matrix() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("transition_matrix");
+    let since = Utc::now();
+    let task = Instruction::task(CleaningPattern::ZigZag);
+    let teleop = Instruction::Teleop {
+        session_id: "matrix".to_string(),
+    };
+
+    Transition::record(conn, &serial, &teleop, &Instruction::Dock)
+        .await
+        .unwrap();
+    Transition::record(conn, &serial, &teleop, &Instruction::Dock)
+        .await
+        .unwrap();
+    Transition::record(conn, &serial, &Instruction::Dock, &task)
+        .await
+        .unwrap();
+
+    let matrix = Transition::frequency_matrix(conn, since).await.unwrap();
+    assert!(matrix[&("Teleop".to_string(), "Dock".to_string())] >= 2);
+    assert!(matrix[&("Dock".to_string(), "Task".to_string())] >= 1);
+}
+
+#[actix_rt::test]
+async fn delivery_records_transition() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("delivery_transition");
+    let since = Utc::now();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    Poll::poll(conn, &poll).await.unwrap();
+    Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+    let poll = Poll {
+        instruction: Instruction::task(CleaningPattern::Circular),
+        ..poll
+    };
+    Poll::poll(conn, &poll).await.unwrap();
+
+    let matrix = Transition::frequency_matrix(conn, since).await.unwrap();
+    assert!(matrix[&("Idle".to_string(), "Task".to_string())] >= 1);
+}
+
+#[actix_rt::test]
+async fn queue_drained_once() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("queue_drained");
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    assert!(Robot::last_drained(conn, &serial).await.unwrap().is_none());
+
+    // The robot picks up the task
+    Poll::poll(conn, &poll).await.unwrap();
+    Poll::poll(
+        conn,
+        &Poll {
+            instruction: Instruction::task(CleaningPattern::ZigZag),
+            ..poll.clone()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(Robot::last_drained(conn, &serial).await.unwrap().is_none());
+
+    // Finishing the task empties the queue, staying idle doesn't count again
+    Poll::poll(conn, &poll).await.unwrap();
+    assert!(Robot::last_drained(conn, &serial).await.unwrap().is_some());
+    Poll::poll(conn, &poll).await.unwrap();
+    Poll::poll(conn, &poll).await.unwrap();
+
+    let events = sqlx::query!(
+        r#"
+SELECT COUNT(*) AS "count!" FROM robot_events E
+WHERE E.robot_serial_number = $1 AND E.event = 'queue_drained'
+        "#,
+        serial.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert_eq!(1, events.count);
+}
+
+#[actix_rt::test]
+async fn robots_contend_for_resource() {
+    let conn = &db_connect().await;
+    let first = unique_serial("resource_first");
+    let second = unique_serial("resource_second");
+    let dock = unique_serial("dock");
+
+    Command::idle(conn, &first).await.unwrap();
+    Command::idle(conn, &second).await.unwrap();
+    let now = Utc::now();
+    let holder = Command::new_with_resource(conn, &first, now, now, &Instruction::Dock, &dock)
+        .await
+        .unwrap();
+    let waiting = Command::new_with_resource(conn, &second, now, now, &Instruction::Dock, &dock)
+        .await
+        .unwrap();
+
+    // Only the robot holding the dock is sent there
+    assert_eq!(
+        holder.id(),
+        Command::pending(conn, &first).await.unwrap().id()
+    );
+    assert_ne!(
+        waiting.id(),
+        Command::pending(conn, &second).await.unwrap().id()
+    );
+
+    // Once it is done the other robot gets its turn
+    holder.complete(conn).await.unwrap();
+    assert_eq!(
+        waiting.id(),
+        Command::pending(conn, &second).await.unwrap().id()
+    );
+}
+
+#[actix_rt::test]
+async fn locked_out_robot_gets_next_command() {
+    let conn = &db_connect().await;
+    let first = unique_serial("locked_out_first");
+    let second = unique_serial("locked_out_second");
+    let dock = unique_serial("locked_out_dock");
+    let now = Utc::now();
+
+    let queued = Command::new(
+        conn,
+        &second,
+        now - Duration::seconds(1),
+        now - Duration::seconds(1),
+        &Instruction::task(CleaningPattern::Spot),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    Command::new_with_resource(conn, &first, now, now, &Instruction::Dock, &dock)
+        .await
+        .unwrap();
+    let waiting = Command::new_with_resource(conn, &second, now, now, &Instruction::Dock, &dock)
+        .await
+        .unwrap();
+    Command::pending(conn, &first).await.unwrap();
+
+    // The dock is taken, so the queued task is sent rather than idle
+    let pending = Command::pending(conn, &second).await.unwrap();
+    assert_ne!(waiting.id(), pending.id());
+    assert_eq!(queued.id(), pending.id());
+}
+
+#[actix_rt::test]
+async fn poll_latency_recorded() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_latency");
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    for _ in 0..5 {
+        Poll::poll(conn, &poll).await.unwrap();
+    }
+
+    let stats = metrics::poll_latency_percentiles();
+    assert!(stats.samples >= 5);
+    assert!(stats.p50 > std::time::Duration::from_secs(0));
+    assert!(stats.p50 <= stats.p95);
+    assert!(stats.p95 <= stats.p99);
+}
+
+#[actix_rt::test]
+async fn poll_response_includes_stale_config() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_response_config");
+
+    Command::idle(conn, &serial).await.unwrap();
+    let latest_version = config::bump(conn).await.unwrap();
+
+    let mut poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: Some(latest_version - 1),
+        progress: None,
+        firmware_version: None,
+    };
+
+    let response = Poll::poll_with_config(conn, &poll).await.unwrap();
+    let config = response
+        .config
+        .expect("the stale robot to be sent its config");
+    assert!(config.config_version >= latest_version);
+    assert_eq!(
+        vec![
+            CleaningPattern::ZigZag,
+            CleaningPattern::Circular,
+            CleaningPattern::Spiral,
+            CleaningPattern::Spot,
+            CleaningPattern::Edge,
+        ],
+        config.supported_patterns
+    );
+
+    poll.config_version = Some(config::version(conn).await.unwrap());
+    let response = Poll::poll_with_config(conn, &poll).await.unwrap();
+    assert!(response.config.is_none());
+}
+
+#[actix_rt::test]
+async fn replace_whole_queue() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("replace_queue");
+
+    let old = [
+        Command::task(conn, &serial, &CleaningPattern::ZigZag)
+            .await
+            .unwrap(),
+        Command::task(conn, &serial, &CleaningPattern::Circular)
+            .await
+            .unwrap(),
+    ];
+    // The robot is already running the first task
+    old[0].deliver(conn).await.unwrap();
+
+    let instructions = vec![
+        Instruction::task(CleaningPattern::Circular),
+        Instruction::Dock,
+        Instruction::task(CleaningPattern::ZigZag),
+    ];
+    let queue = Command::replace_queue(conn, &serial, &instructions)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        instructions,
+        queue
+            .iter()
+            .map(|c| c.instruction.clone())
+            .collect::<Vec<_>>()
+    );
+
+    // The waiting command is replaced, the running one isn't
+    assert!(
+        Command::get_including_deleted(conn, old[1].id())
+            .await
+            .unwrap()
+            .cancelled
+    );
+    assert!(
+        !Command::get_including_deleted(conn, old[0].id())
+            .await
+            .unwrap()
+            .cancelled
+    );
+
+    // The robot is given the new commands in the order they were given
+    old[0].complete(conn).await.unwrap();
+    for queued in &queue {
+        let pending = Command::pending(conn, &serial).await.unwrap();
+        assert_eq!(queued.id(), pending.id());
+        pending.complete(conn).await.unwrap();
+    }
+}
+
+#[actix_rt::test]
+async fn replace_queue_checks_rate_limit() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("replace_queue_rate_limit");
+
+    let waiting = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    // The waiting task already counts towards the limit
+    let instructions = vec![Idle; CommandConfig::global().rate_limit as usize];
+    let result = Command::replace_queue(conn, &serial, &instructions).await;
+    assert!(matches!(result, Err(ApiError::RateLimited)));
+
+    // Nothing was replaced
+    assert!(
+        !Command::get_including_deleted(conn, waiting.id())
+            .await
+            .unwrap()
+            .cancelled
+    );
+    assert_eq!(
+        waiting.id(),
+        Command::pending(conn, &serial).await.unwrap().id()
+    );
+}
+
+// Keeps hold of every command POSTed to it
+async fn record_callback(
+    received: web::Data<Mutex<Vec<serde_json::Value>>>,
+    body: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    received.lock().unwrap().push(body.into_inner());
+    HttpResponse::Ok().finish()
+}
+
+// A server that keeps hold of every command POSTed to it, along with the
+// URL to POST them to
+fn callback_server() -> (
+    web::Data<Mutex<Vec<serde_json::Value>>>,
+    String,
+    actix_web::dev::Server,
+) {
+    let received = web::Data::new(Mutex::new(Vec::<serde_json::Value>::new()));
+    let app_received = received.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_received.clone())
+            .route("/callback", web::post().to(record_callback))
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let callback_url = format!("http://{}/callback", server.addrs()[0]);
+
+    (received, callback_url, server.run())
+}
+
+// Callbacks are sent in the background, so give them time to arrive
+async fn callbacks_received(
+    received: &web::Data<Mutex<Vec<serde_json::Value>>>,
+) -> Vec<serde_json::Value> {
+    for _ in 0..50 {
+        if !received.lock().unwrap().is_empty() {
+            break;
+        }
+        actix_web::rt::time::delay_for(std::time::Duration::from_millis(50)).await;
+    }
+    actix_web::rt::time::delay_for(std::time::Duration::from_millis(200)).await;
+
+    let received = received.lock().unwrap().clone();
+    received
+}
+
+#[actix_rt::test]
+async fn callback_on_completion() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("callback_on_completion");
+    let now = Utc::now();
+    let (received, callback_url, server) = callback_server();
+
+    let with_callback = Command::new_with_callback(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::ZigZag),
+        &callback_url,
+    )
+    .await
+    .unwrap();
+    let without_callback = Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+
+    // The URL is written with the command rather than added afterwards
+    let stored = Command::get_including_deleted(conn, with_callback.id())
+        .await
+        .unwrap();
+    assert_eq!(Some(callback_url.clone()), stored.callback_url);
+    assert_eq!(0, stored.version);
+
+    without_callback.complete(conn).await.unwrap();
+    with_callback.complete(conn).await.unwrap();
+
+    let received = callbacks_received(&received).await;
+    assert_eq!(1, received.len());
+    assert_eq!(with_callback.id(), received[0]["command_id"]);
+    assert_eq!(true, received[0]["completed"]);
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn robot_webhook_on_completion() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("robot_webhook");
+    let (received, webhook_url, server) = callback_server();
+
+    Robot::new(conn, &serial).await.unwrap();
+    Robot::set_webhook_url(conn, &serial, Some(&webhook_url))
+        .await
+        .unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    task.complete(conn).await.unwrap();
+
+    let received = callbacks_received(&received).await;
+    assert_eq!(1, received.len());
+    assert_eq!(task.id(), received[0]["command_id"]);
+    assert_eq!(serial.as_str(), received[0]["robot_serial_number"]);
+    assert_eq!(true, received[0]["completed"]);
+
+    // Turned off again, later completions aren't sent anywhere
+    Robot::set_webhook_url(conn, &serial, None).await.unwrap();
+    assert_eq!(None, Robot::webhook_url(conn, &serial).await.unwrap());
+    assert!(matches!(
+        Robot::set_webhook_url(conn, &serial, Some("nowhere")).await,
+        Err(ApiError::InvalidCallbackUrl)
+    ));
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn recorded_progress_changes_version() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("progress_version");
+
+    let task = Command::task(conn, &serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    task.deliver(conn).await.unwrap();
+    let delivered = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+
+    Command::record_progress(conn, &serial, 0.5).await.unwrap();
+    let progressed = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+    assert_eq!(Some(0.5), progressed.progress);
+    assert_eq!(delivered.version + 1, progressed.version);
+}
+
+#[actix_rt::test]
+async fn callback_url_validated() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("callback_url_validated");
+    let now = Utc::now();
+
+    let result = Command::new_with_callback(conn, &serial, now, now, &Idle, "nowhere").await;
+    assert!(matches!(result, Err(ApiError::InvalidCallbackUrl)));
+}
+
+#[actix_rt::test]
+async fn commands_issued_during_outage() {
+    // Reads every robot's commands, which could include unreadable rows
+    // other tests leave behind
+    let conn = &isolated_db_connect("issued_during").await;
+    let serial = unique_serial("issued_during");
+    let now = Utc::now();
+    let minutes = |m| now - Duration::minutes(m);
+
+    let before = Command::new(
+        conn,
+        &serial,
+        minutes(15),
+        minutes(15),
+        &Idle,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let during = Command::new(
+        conn,
+        &serial,
+        minutes(8),
+        minutes(8),
+        &Instruction::task(CleaningPattern::ZigZag),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+INSERT INTO battery_readings (robot_serial_number, recorded_at, battery_level)
+VALUES ($1, $2::TIMESTAMPTZ - interval '9 minutes', 80),
+       ($1, $2::TIMESTAMPTZ - interval '6 minutes', 79)
+        "#,
+        serial.as_str(),
+        now
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    let issued: Vec<_> = Command::issued_during(conn, minutes(10), minutes(5))
+        .await
+        .unwrap()
+        .iter()
+        .map(|c| c.id())
+        .collect();
+    assert!(issued.contains(&during.id()));
+    assert!(!issued.contains(&before.id()));
+
+    // 5 minutes should have been 60 polls
+    let gaps = Command::gap_analysis(conn, minutes(10), minutes(5))
+        .await
+        .unwrap();
+    let gap = gaps
+        .iter()
+        .find(|g| g.robot_serial_number == serial.as_str())
+        .unwrap();
+    assert_eq!(60, gap.expected_polls);
+    assert_eq!(2, gap.recorded_polls);
+    assert_eq!(58, gap.missing_polls);
+}
+
+#[actix_rt::test]
+async fn schedule_in_robot_timezone() {
+    let conn = &db_connect().await;
+    let london = unique_serial("schedule_london");
+    let tokyo = unique_serial("schedule_tokyo");
+
+    Robot::new(conn, &london).await.unwrap();
+    Robot::new(conn, &tokyo).await.unwrap();
+    Robot::set_timezone(conn, &london, chrono_tz::Europe::London)
+        .await
+        .unwrap();
+    Robot::set_timezone(conn, &tokyo, chrono_tz::Asia::Tokyo)
+        .await
+        .unwrap();
+
+    let task = Instruction::task(CleaningPattern::ZigZag);
+    let london_job = scheduler::schedule(conn, &london, "0 2 * * *", &task)
+        .await
+        .unwrap();
+    let tokyo_job = scheduler::schedule(conn, &tokyo, "0 2 * * *", &task)
+        .await
+        .unwrap();
+
+    let run_at = |serial: &str| {
+        let serial = serial.to_string();
+        async move {
+            sqlx::query!(
+                r#"
+SELECT time_instruction FROM Commands C
+WHERE C.robot_serial_number = $1
+                "#,
+                serial
+            )
+            .fetch_one(conn)
+            .await
+            .unwrap()
+            .time_instruction
+        }
+    };
+    let london_at = run_at(&london_job.robot_serial_number).await;
+    let tokyo_at = run_at(&tokyo_job.robot_serial_number).await;
+
+    // Both run at 2am local time, which are different times in UTC
+    assert_ne!(london_at, tokyo_at);
+    let local_time = |at: chrono::DateTime<Utc>, tz: chrono_tz::Tz| {
+        at.with_timezone(&tz).format("%H:%M").to_string()
+    };
+    assert_eq!("02:00", local_time(london_at, chrono_tz::Europe::London));
+    assert_eq!("02:00", local_time(tokyo_at, chrono_tz::Asia::Tokyo));
+}
+
+#[actix_rt::test]
+async fn export_fleet_state() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let idle = unique_serial("export_idle").in_organization(&acme);
+    let tasked = unique_serial("export_tasked").in_organization(&acme);
+    let elsewhere = unique_serial("export_elsewhere").in_organization(&globex);
+
+    Robot::new(conn, &idle).await.unwrap();
+    Robot::new(conn, &tasked).await.unwrap();
+    Robot::new(conn, &elsewhere).await.unwrap();
+    Command::task(conn, &tasked, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    // The tasked robot is already under way on its task
+    for (serial, instruction) in [
+        (&idle, Idle),
+        (&tasked, Instruction::task(CleaningPattern::ZigZag)),
+    ] {
+        let poll = Poll {
+            robot_serial_number: (*serial).clone(),
+            instruction,
+            battery_level: 80,
+            report: None,
+            x: None,
+            y: None,
+            heading: None,
+            acknowledged: None,
+            config_version: None,
+            progress: None,
+            firmware_version: None,
+        };
+        Poll::poll(conn, &poll).await.unwrap();
+    }
+
+    let document = fleet::export_state(conn, &acme).await.unwrap();
+
+    // The other organization's robot is neither listed nor counted
+    assert_eq!(serde_json::json!(2), document["robot_count"]);
+    assert!(document["exported_at"].is_string());
+    let robots = document["robots"].as_array().unwrap();
+    assert_eq!(2, robots.len());
+    let robot = |serial: &str| {
+        robots
+            .iter()
+            .find(|r| r["robot_serial_number"] == serial)
+            .unwrap()
+            .clone()
+    };
+
+    let idle_state = robot(&idle);
+    assert_eq!(serde_json::json!("Idle"), idle_state["instruction"]);
+    assert_eq!(serde_json::json!(80), idle_state["battery_level"]);
+    assert!(idle_state["last_seen"].is_string());
+
+    let tasked_state = robot(&tasked);
+    assert_eq!(
+        serde_json::json!({ "Task": { "pattern": "ZigZag", "speed": 100, "passes": 1 } }),
+        tasked_state["instruction"]
+    );
+    assert_eq!(serde_json::json!(false), tasked_state["completed"]);
+}
+
+#[actix_rt::test]
+async fn import_robots_from_csv() {
+    let conn = &db_connect().await;
+    let existing = unique_serial("import_existing");
+    let new = unique_serial("import_new");
+
+    Robot::new(conn, &existing).await.unwrap();
+
+    let csv = format!(
+        "robot_serial_number,timezone,capabilities\n{},America/New_York\n{},,ZigZag\n{}\n",
+        existing, new, new
+    );
+    let imported = Robot::import_csv(conn, csv.as_bytes()).await.unwrap();
+
+    assert_eq!(2, imported);
+    assert_eq!(
+        chrono_tz::America::New_York,
+        Robot::timezone(conn, &existing).await.unwrap()
+    );
+    assert_eq!(
+        Some(vec![CleaningPattern::ZigZag]),
+        Robot::capabilities(conn, &new).await.unwrap()
+    );
+    // The new robot starts off idle
+    assert_eq!(
+        Idle,
+        Command::current(conn, &new).await.unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn import_invalid_csv_registers_nothing() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("import_invalid");
+
+    let csv = format!("{}\nnot a serial\n", serial);
+    let result = Robot::import_csv(conn, csv.as_bytes()).await;
+
+    assert!(matches!(result, Err(ApiError::InvalidCsv(2))));
+    assert_eq!(None, Robot::capabilities(conn, &serial).await.unwrap());
+    assert_eq!(
+        chrono_tz::UTC,
+        Robot::timezone(conn, &serial).await.unwrap()
+    );
+}
+
+#[actix_rt::test]
+async fn preempted_task_resumes_from_progress() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("preempt");
+    let poll = |instruction, progress| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task_a = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let running = Poll::poll(
+        conn,
+        &poll(Instruction::task(CleaningPattern::ZigZag), None),
+    )
+    .await
+    .unwrap();
+    assert_eq!(task_a.id(), running.id());
+
+    let now = Utc::now();
+    let task_b = Command::new_with_priority(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::Circular),
+        5,
+    )
+    .await
+    .unwrap();
+
+    // B takes over part way through A
+    let preempting = Poll::poll(
+        conn,
+        &poll(Instruction::task(CleaningPattern::ZigZag), Some(0.4)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(task_b.id(), preempting.id());
+
+    let still_b = Poll::poll(
+        conn,
+        &poll(Instruction::task(CleaningPattern::Circular), None),
+    )
+    .await
+    .unwrap();
+    assert_eq!(task_b.id(), still_b.id());
+
+    // Once B is done A carries on from where it was
+    let resumed = Poll::poll(conn, &poll(Idle, None)).await.unwrap();
+    assert_eq!(
+        Instruction::task(CleaningPattern::ZigZag),
+        resumed.instruction
+    );
+    assert_eq!(Some(0.4), resumed.progress);
+    assert_ne!(task_a.id(), resumed.id());
+
+    let carrying_on = Poll::poll(
+        conn,
+        &poll(Instruction::task(CleaningPattern::ZigZag), Some(0.6)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resumed.id(), carrying_on.id());
+    assert!(Command::resume_preempted(conn, task_b.id())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_rt::test]
+async fn database_errors_by_kind() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("database_errors");
+    let insert = || {
+        sqlx::query("INSERT INTO robot (robot_serial_number) VALUES ($1)")
+            .bind(serial.as_str())
+            .execute(conn)
+    };
+
+    insert().await.unwrap();
+    let duplicate = ApiError::from(insert().await.unwrap_err());
+    assert!(matches!(duplicate, ApiError::ConstraintViolation(_)));
+    assert!(!duplicate.is_retryable());
+
+    let bad_query = sqlx::query("SELECT no_such_column FROM robot")
+        .execute(conn)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        ApiError::from(bad_query),
+        ApiError::QueryFailed(_)
+    ));
+}
+
+#[actix_rt::test]
+async fn return_to_dock_after_task() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("return_to_dock");
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Poll::poll(conn, &poll(Instruction::task(CleaningPattern::ZigZag)))
+        .await
+        .unwrap();
+
+    // Finishing the run completes the task and heads back
+    let docking = Poll::poll(conn, &poll(Instruction::ReturnToDock))
+        .await
+        .unwrap();
+    assert_eq!(Instruction::ReturnToDock, docking.instruction);
+    let completed = sqlx::query!(
+        "SELECT completed FROM Commands C WHERE C.command_id = $1",
+        task.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .completed;
+    assert!(completed);
+
+    let still_docking = Poll::poll(conn, &poll(Instruction::ReturnToDock))
+        .await
+        .unwrap();
+    assert_eq!(docking.id(), still_docking.id());
+
+    // Once docked it goes on to whatever is next
+    let next = Poll::poll(conn, &poll(Idle)).await.unwrap();
+    assert_eq!(Idle, next.instruction);
+    assert_eq!(
+        Instruction::ReturnToDock,
+        Command::current(conn, &serial).await.unwrap().instruction
+    );
+    assert!(Command::current(conn, &serial).await.unwrap().completed);
+}
+
+#[actix_rt::test]
+async fn returning_to_dock_on_low_battery() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("return_to_dock_low");
+
+    let docking = Command::return_to_dock(conn, &serial).await.unwrap();
+    assert_eq!(
+        Instruction::ReturnToDock,
+        Command::current(conn, &serial).await.unwrap().instruction
+    );
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Instruction::ReturnToDock,
+        battery_level: 20,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // Low readings don't abort a robot that is going to charge
+    for _ in 0..4 {
+        let result = Poll::poll(conn, &poll).await.unwrap();
+        assert_eq!(docking.id(), result.id());
+    }
+}
+
+#[actix_rt::test]
+async fn every_cleaning_pattern_round_trips() {
+    let conn = &db_connect().await;
+    let patterns = [
+        CleaningPattern::ZigZag,
+        CleaningPattern::Circular,
+        CleaningPattern::Spiral,
+        CleaningPattern::Spot,
+        CleaningPattern::Edge,
+    ];
+
+    for pattern in &patterns {
+        let serial = unique_serial("pattern_round_trip");
+        Command::task(conn, &serial, pattern).await.unwrap();
+
+        let current = Command::current(conn, &serial).await.unwrap();
+        assert_eq!(Instruction::task(pattern.clone()), current.instruction);
+    }
+}
+
+#[actix_rt::test]
+async fn stored_patterns_still_read() {
+    let conn = &db_connect().await;
+
+    // Rows written before the new patterns were added
+    for (json, pattern) in &[
+        (r#"{"Task":"ZigZag"}"#, CleaningPattern::ZigZag),
+        (r#"{"Task":"Circular"}"#, CleaningPattern::Circular),
+    ] {
+        let serial = unique_serial("stored_pattern");
+        sqlx::query!(
+            r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
+VALUES ($1, now(), now(), $2)
+            "#,
+            serial.as_str(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        )
+        .execute(conn)
+        .await
+        .unwrap();
+
+        let current = Command::current(conn, &serial).await.unwrap();
+        assert_eq!(Instruction::task(pattern.clone()), current.instruction);
+        let pending = Command::pending(conn, &serial).await.unwrap();
+        assert_eq!(Instruction::task(pattern.clone()), pending.instruction);
+    }
+}
+
+#[actix_rt::test]
+async fn corrupt_instruction_reported() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("corrupt_instruction");
+
+    let command_id = sqlx::query!(
+        r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
+VALUES ($1, now(), now(), $2)
+RETURNING command_id
+        "#,
+        serial.as_str(),
+        serde_json::json!({"Task": "Diagonal"})
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .command_id;
+
+    match Command::current(conn, &serial).await {
+        Err(ApiError::CorruptInstruction { command_id: id }) => assert_eq!(command_id, id),
+        other => panic!("expected a corrupt instruction, got {:?}", other),
+    }
+    match Command::pending(conn, &serial).await {
+        Err(ApiError::CorruptInstruction { command_id: id }) => assert_eq!(command_id, id),
+        other => panic!("expected a corrupt instruction, got {:?}", other),
+    }
+    match Command::history(conn, &serial, 10, 0).await {
+        Err(ApiError::CorruptInstruction { command_id: id }) => assert_eq!(command_id, id),
+        other => panic!("expected a corrupt instruction, got {:?}", other),
+    }
+    let since = Utc::now() - Duration::seconds(60);
+    match Command::intervals(conn, &serial, since, Utc::now()).await {
+        Err(ApiError::CorruptInstruction { command_id: id }) => assert_eq!(command_id, id),
+        other => panic!("expected a corrupt instruction, got {:?}", other),
+    }
+}
+
+#[actix_rt::test]
+async fn register_robot_model() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("register_model");
+
+    assert_eq!(None, Robot::get(conn, &serial).await.unwrap());
+
+    Robot::register(conn, &serial, "sweeper-s1", 30)
+        .await
+        .unwrap();
+    let robot = Robot::register(conn, &serial, "sweeper-s2", 35)
+        .await
+        .unwrap();
+
+    assert_eq!(Some(robot), Robot::get(conn, &serial).await.unwrap());
+    assert_eq!(
+        Idle,
+        Command::current(conn, &serial).await.unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn battery_threshold_per_robot() {
+    let conn = &db_connect().await;
+    let hardy = unique_serial("battery_threshold_hardy");
+    let unregistered = unique_serial("battery_threshold_default");
+
+    Robot::register(conn, &hardy, "long-range", 30)
+        .await
+        .unwrap();
+    Command::idle(conn, &unregistered).await.unwrap();
+
+    let poll = |serial: &RobotSerial| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 40,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // 40% is fine for a robot rated down to 30%, but below the default
+    for _ in 0..3 {
+        let result = Poll::poll(conn, &poll(&hardy)).await.unwrap();
+        assert_eq!(Idle, result.instruction);
+    }
+    let mut aborted = false;
+    for _ in 0..3 {
+        let result = Poll::poll(conn, &poll(&unregistered)).await.unwrap();
+        aborted = result.instruction == Abort(AbortReason::LowBattery);
+    }
+    assert!(aborted);
+}
+
+#[actix_rt::test]
+async fn scheduled_command_hidden_until_due() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("scheduled");
+    let task = Instruction::task(CleaningPattern::Spiral);
+
+    Command::idle(conn, &serial).await.unwrap();
+    let due = Command::task(conn, &serial, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+    let scheduled = Command::schedule(conn, &serial, &task, Utc::now() + Duration::hours(2))
+        .await
+        .unwrap();
+
+    // The later command doesn't hold up the one that is already due
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_eq!(due.id(), pending.id());
+
+    // Move the schedule along to now, as if the time had arrived
+    sqlx::query!(
+        "UPDATE Commands C SET time_instruction = now() WHERE C.command_id = $1",
+        scheduled.id()
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_eq!(scheduled.id(), pending.id());
+    assert_eq!(task, pending.instruction);
+}
+
+#[actix_rt::test]
+async fn cancel_task_before_pickup() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("cancel_task");
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    task.cancel(conn).await.unwrap();
+
+    assert_eq!(
+        Idle,
+        Command::pending(conn, &serial).await.unwrap().instruction
+    );
+
+    // Cancelling it again, or once it is completed, is fine
+    task.cancel(conn).await.unwrap();
+    let idle = Command::pending(conn, &serial).await.unwrap();
+    idle.complete(conn).await.unwrap();
+    idle.cancel(conn).await.unwrap();
+    let cancelled = sqlx::query!(
+        "SELECT cancelled FROM Commands C WHERE C.command_id = $1",
+        idle.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .cancelled;
+    assert!(!cancelled);
+}
+
+#[actix_rt::test]
+async fn cancel_all_pending() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("cancel_pending");
+
+    Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+
+    assert_eq!(2, Command::cancel_pending(conn, &serial).await.unwrap());
+    assert_eq!(0, Command::cancel_pending(conn, &serial).await.unwrap());
+    assert_eq!(
+        Idle,
+        Command::pending(conn, &serial).await.unwrap().instruction
+    );
+}
+
+#[actix_rt::test]
+async fn higher_priority_pending_first() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("priority_pending");
+
+    let abort = Command::abort(conn, &serial, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+    let now = Utc::now();
+    let task = Command::new_with_priority(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::ZigZag),
+        1,
+    )
+    .await
+    .unwrap();
+    assert_eq!(1, task.priority);
+
+    // Both were written with their priority, rather than updated after
+    // they could already have been handed out
+    for command in &[&abort, &task] {
+        let stored = Command::get_including_deleted(conn, command.id())
+            .await
+            .unwrap();
+        assert_eq!(command.priority, stored.priority);
+        assert_eq!(0, stored.version);
+    }
+
+    // The abort wins even though the task is newer
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_eq!(abort.id(), pending.id());
+}
+
+#[actix_rt::test]
+async fn carried_out_abort_lets_queue_continue() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("abort_carried_out");
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let abort = Command::abort(conn, &serial, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+    let delivered = Poll::poll(conn, &poll(Idle)).await.unwrap();
+    assert_eq!(abort.id(), delivered.id());
+
+    // Stopping completes the abort so it doesn't hold up the queue
+    let next = Poll::poll(conn, &poll(Idle)).await.unwrap();
+    assert_eq!(Idle, next.instruction);
+    let completed = sqlx::query!(
+        "SELECT completed FROM Commands C WHERE C.command_id = $1",
+        abort.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .completed;
+    assert!(completed);
+
+    let task = Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+    assert_eq!(
+        task.id(),
+        Command::pending(conn, &serial).await.unwrap().id()
+    );
+}
+
+#[actix_rt::test]
+async fn issue_batch_to_fleet() {
+    let conn = &db_connect().await;
+    let serials: Vec<RobotSerial> = (0..3)
+        .map(|i| unique_serial(&format!("batch_{}", i)))
+        .collect();
+    let patterns = [
+        CleaningPattern::ZigZag,
+        CleaningPattern::Spot,
+        CleaningPattern::Edge,
+    ];
+
+    let batch: Vec<(&RobotSerial, Instruction)> = serials
+        .iter()
+        .zip(&patterns)
+        .map(|(serial, pattern)| (serial, Instruction::task(pattern.clone())))
+        .collect();
+    let commands = Command::new_batch(conn, &batch).await.unwrap();
+
+    assert_eq!(3, commands.len());
+    for ((serial, instruction), command) in batch.iter().zip(&commands) {
+        let current = Command::current(conn, serial).await.unwrap();
+        assert_eq!(command.id(), current.id());
+        assert_eq!(instruction, &current.instruction);
+    }
+}
+
+#[actix_rt::test]
+async fn paginated_command_history() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("history");
+    let now = Utc::now();
+
+    // Two commands issued at the same time are ordered by id
+    let first = Command::new(
+        conn,
+        &serial,
+        now,
+        now,
+        &Idle,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let second = Command::new(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::Spot),
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    second.complete(conn).await.unwrap();
+    let third = Command::task(conn, &serial, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+
+    let history = Command::history(conn, &serial, 10, 0).await.unwrap();
+    assert_eq!(
+        vec![third.id(), second.id(), first.id()],
+        history.iter().map(|c| c.id()).collect::<Vec<_>>()
+    );
+    assert!(history[1].completed);
+    assert_eq!(
+        Instruction::task(CleaningPattern::Spot),
+        history[1].instruction
+    );
+
+    let page = Command::history(conn, &serial, 1, 1).await.unwrap();
+    assert_eq!(
+        vec![second.id()],
+        page.iter().map(|c| c.id()).collect::<Vec<_>>()
+    );
+    assert!(Command::history(conn, &serial, 10, 3)
+        .await
+        .unwrap()
+        .is_empty());
+
+    assert!(matches!(
+        Command::history(conn, &serial, -1, 0).await,
+        Err(ApiError::InvalidPagination)
+    ));
+    assert!(matches!(
+        Command::history(conn, &serial, 1_000_000, 0).await,
+        Err(ApiError::InvalidPagination)
+    ));
+}
+
+#[actix_rt::test]
+async fn instruction_time_kept_on_read() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("instruction_time");
+    let issued = Utc::now() - Duration::seconds(100);
+    let instruction_at = Utc::now() + Duration::seconds(300);
+
+    Command::new(
+        conn,
+        &serial,
+        issued,
+        instruction_at,
+        &Idle,
+        &CommandConfig::default(),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let current = Command::current(conn, &serial).await.unwrap();
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_eq!(current.id(), pending.id());
+
+    for command in &[current, pending] {
+        let json = serde_json::to_value(command).unwrap();
+        assert_eq!(serde_json::json!(issued.timestamp()), json["time_issued"]);
+        assert_eq!(
+            serde_json::json!(instruction_at.timestamp()),
+            json["time_instruction"]
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn pause_then_continue_task() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("pause_continue");
+    Robot::new(conn, &serial).await.unwrap();
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    Command::task(conn, &serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    Poll::poll(conn, &poll(Instruction::task(CleaningPattern::Spiral)))
+        .await
+        .unwrap();
+
+    let paused = Poll::poll(conn, &poll(Instruction::Pause)).await.unwrap();
+    assert_eq!(Instruction::Pause, paused.instruction);
+    let still_paused = Poll::poll(conn, &poll(Instruction::Pause)).await.unwrap();
+    assert_eq!(paused.id(), still_paused.id());
+
+    // Continuing picks the same pattern back up
+    let resumed = Poll::poll(conn, &poll(Instruction::Continue))
+        .await
+        .unwrap();
+    assert_eq!(
+        Instruction::task(CleaningPattern::Spiral),
+        resumed.instruction
+    );
+
+    let carrying_on = Poll::poll(conn, &poll(Instruction::task(CleaningPattern::Spiral)))
+        .await
+        .unwrap();
+    assert_eq!(resumed.id(), carrying_on.id());
+    assert_eq!(None, Robot::take_paused(conn, &serial).await.unwrap());
+}
+
+#[actix_rt::test]
+async fn continue_without_pause() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("continue_unpaused");
+
+    Command::idle(conn, &serial).await.unwrap();
+    Command::pause(conn, &serial).await.unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Instruction::Continue,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    // Nothing was paused, so the robot gets whatever is next
+    let result = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(Idle, result.instruction);
+}
+
+#[actix_rt::test]
+async fn poll_response_suggests_next_poll() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("next_poll");
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let idle = Poll::poll_with_config(conn, &poll(Idle)).await.unwrap();
+
+    Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let working = Poll::poll_with_config(conn, &poll(Instruction::task(CleaningPattern::ZigZag)))
+        .await
+        .unwrap();
+
+    assert_eq!(Idle, idle.command.instruction);
+    assert_eq!(
+        Instruction::task(CleaningPattern::ZigZag),
+        working.command.instruction
+    );
+    assert!(idle.next_poll_secs > working.next_poll_secs);
+
+    let json = serde_json::to_value(&working).unwrap();
+    assert_eq!(
+        serde_json::json!(working.next_poll_secs),
+        json["next_poll_secs"]
+    );
+}
+
+#[actix_rt::test]
+async fn poll_marks_robot_seen() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("last_seen");
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    let stale_after = Duration::minutes(5);
+
+    Robot::register(conn, &serial, "sweeper-s1", 30)
+        .await
+        .unwrap();
+    Command::idle(conn, &serial).await.unwrap();
+    assert!(!Robot::is_online(conn, &serial, stale_after).await.unwrap());
+
+    let (first, second) = futures::join!(Poll::poll(conn, &poll), Poll::poll(conn, &poll));
+    first.unwrap();
+    second.unwrap();
+
+    assert!(Robot::is_online(conn, &serial, stale_after).await.unwrap());
+    assert!(!Robot::offline_robots(conn, stale_after)
+        .await
+        .unwrap()
+        .contains(&serial.to_string()));
+
+    // Nothing has been seen in the future, so every robot is offline
+    assert!(!Robot::is_online(conn, &serial, Duration::minutes(-1))
+        .await
+        .unwrap());
+    assert!(Robot::offline_robots(conn, Duration::minutes(-1))
+        .await
+        .unwrap()
+        .contains(&serial.to_string()));
+}
+
+#[actix_rt::test]
+async fn stale_commands_expired() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("expire_stale");
+    let long_ago = Utc::now() - Duration::hours(2);
+
+    Command::idle(conn, &serial).await.unwrap();
+    let stale = Command::new_unbuffered(
+        conn,
+        &serial,
+        long_ago,
+        long_ago,
+        &Instruction::task(CleaningPattern::ZigZag),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(1, Command::expire_stale(conn, &serial).await.unwrap());
+    let completed = sqlx::query!(
+        "SELECT completed FROM Commands C WHERE C.command_id = $1",
+        stale.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .completed;
+    assert!(completed);
+    assert_eq!(0, Command::expire_stale(conn, &serial).await.unwrap());
+
+    // Expired while looking for pending commands too
+    let stale = Command::new_unbuffered(
+        conn,
+        &serial,
+        long_ago,
+        long_ago,
+        &Instruction::task(CleaningPattern::Circular),
+    )
+    .await
+    .unwrap();
+    let pending = Command::pending(conn, &serial).await.unwrap();
+    assert_ne!(stale.id(), pending.id());
+    assert_eq!(0, Command::expire_stale(conn, &serial).await.unwrap());
+    assert_ne!(
+        stale.id(),
+        Command::pending(conn, &serial).await.unwrap().id()
+    );
+}
+
+#[actix_rt::test]
+async fn commands_counted_by_type() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("count_by_type");
+
+    Command::idle(conn, &serial).await.unwrap();
+    Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::task(conn, &serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    Command::pause(conn, &serial).await.unwrap();
+    Command::abort(conn, &serial, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+
+    let counts = Command::count_by_type(conn, &serial).await.unwrap();
+
+    let mut expected = HashMap::new();
+    expected.insert("Idle".to_string(), 1);
+    expected.insert("Task".to_string(), 2);
+    expected.insert("Pause".to_string(), 1);
+    expected.insert("Abort".to_string(), 1);
+    assert_eq!(expected, counts);
+
+    let json: serde_json::Value = sqlx::query!(
+        "SELECT C.instruction FROM Commands C WHERE C.robot_serial_number = $1 AND C.instruction ? 'Task' LIMIT 1",
+        serial.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .instruction;
+    assert!(json["Task"]["pattern"].is_string());
+}
+
+#[actix_rt::test]
+async fn poll_records_battery_history() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("battery_history");
+    let poll = |battery_level| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    let since = Utc::now() - Duration::seconds(1);
+
+    Command::idle(conn, &serial).await.unwrap();
+    for battery_level in &[90, 85, 80] {
+        Poll::poll(conn, &poll(*battery_level)).await.unwrap();
+    }
+
+    let history = Poll::battery_history(conn, &serial, since).await.unwrap();
+    let levels: Vec<i64> = history.iter().map(|(_, level)| *level).collect();
+    assert_eq!(vec![90, 85, 80], levels);
+    assert!(history.windows(2).all(|w| w[0].0 <= w[1].0));
+
+    let later = Utc::now() + Duration::seconds(1);
+    assert!(Poll::battery_history(conn, &serial, later)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_rt::test]
+async fn poll_keeps_last_position() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("last_position");
+    let poll = |x, y, heading| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x,
+        y,
+        heading,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    assert_eq!(None, Poll::last_position(conn, &serial).await.unwrap());
+
+    Poll::poll(conn, &poll(Some(1.5), Some(2.5), Some(90.0)))
+        .await
+        .unwrap();
+    assert_eq!(
+        Some((1.5, 2.5, 90.0)),
+        Poll::last_position(conn, &serial).await.unwrap()
+    );
+
+    // Not reporting a position leaves the last one alone
+    Poll::poll(conn, &poll(None, None, None)).await.unwrap();
+    assert_eq!(
+        Some((1.5, 2.5, 90.0)),
+        Poll::last_position(conn, &serial).await.unwrap()
+    );
+
+    // Older robots don't send a heading at all
+    let old_client: Poll = serde_json::from_value(serde_json::json!({
+        "robot_serial_number": serial,
+        "instruction": "Idle",
+        "battery_level": 90,
+        "x": 3.0,
+        "y": 4.0,
+    }))
+    .unwrap();
+    assert_eq!(None, old_client.heading);
+    Poll::poll(conn, &old_client).await.unwrap();
+    assert_eq!(
+        Some((3.0, 4.0, 0.0)),
+        Poll::last_position(conn, &serial).await.unwrap()
+    );
+}
+
+#[actix_rt::test]
+async fn new_commands_published() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("push_subscribe");
+    let other = unique_serial("push_subscribe_other");
+
+    let commands = push::subscribe(&serial);
+    futures::pin_mut!(commands);
+
+    Command::idle(conn, &other).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+
+    let pushed = commands.next().await.unwrap();
+    assert_eq!(serial, pushed.robot_serial_number);
+    assert_eq!(task.id(), pushed.command_id);
+}
+
+// Reads the next command the socket sends, giving up after a few seconds
+async fn next_socket_command<S>(socket: &mut S) -> serde_json::Value
+where
+    S: futures::Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin,
+{
+    loop {
+        let frame = actix_web::rt::time::timeout(std::time::Duration::from_secs(5), socket.next())
+            .await
+            .expect("a frame from the socket")
+            .unwrap()
+            .unwrap();
+        if let ws::Frame::Text(text) = frame {
+            return serde_json::from_slice(&text).unwrap();
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn socket_pushes_new_commands() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("push_socket");
+
+    let app_conn = conn.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(app_conn.clone())
+            .service(api::poll::robot_socket)
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/ws/{}", server.addrs()[0], serial);
+    let server = server.run();
+
+    Robot::new(conn, &serial).await.unwrap();
+    Command::idle(conn, &serial).await.unwrap();
+    let api_key = Robot::rotate_key(conn, &serial).await.unwrap();
+    let (_, mut socket) = actix_web::client::Client::new()
+        .ws(url)
+        .header("X-Api-Key", api_key)
+        .connect()
+        .await
+        .unwrap();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    socket
+        .send(ws::Message::Text(serde_json::to_string(&poll).unwrap()))
+        .await
+        .unwrap();
+    let polled = next_socket_command(&mut socket).await;
+    assert_eq!(serde_json::json!("Idle"), polled["instruction"]);
+
+    // Sent without the robot polling again
+    let task = Command::task(conn, &serial, &CleaningPattern::Circular)
+        .await
+        .unwrap();
+    let pushed = next_socket_command(&mut socket).await;
+    assert_eq!(task.id(), pushed["command_id"]);
+    assert_eq!(
+        serde_json::json!({"Task": {"pattern": "Circular", "speed": 100, "passes": 1}}),
+        pushed["instruction"]
+    );
+
+    // Ordinary polls still work once the socket has gone
+    socket.close().await.unwrap();
+    let result = Poll::poll(
+        conn,
+        &Poll {
+            instruction: Instruction::task(CleaningPattern::Circular),
+            ..poll
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(task.id(), result.id());
+
+    server.stop(true).await;
+}
+
+// Serves every route on a free port, returning where to reach them along
+// with the database they use
+async fn spawn_app() -> (String, PgPool, actix_web::dev::Server) {
+    let conn = db_connect().await;
+    let app_conn = conn.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(app_conn.clone())
+            .data(BatteryRequirements::default())
+            .data(ShiftConfig::default())
+            .data(CommandConfig::default())
+            .configure(api::configure)
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let address = format!("http://{}", server.addrs()[0]);
+
+    (address, conn, server.run())
+}
+
+#[actix_rt::test]
+async fn health_check() {
+    let (address, _conn, server) = spawn_app().await;
+
+    let mut response = actix_web::client::Client::new()
+        .get(format!("{}/health", address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!({"status": "ok"}), body);
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn app_serves_every_route() {
+    let (address, _conn, server) = spawn_app().await;
+    let client = actix_web::client::Client::new();
+
+    // Without credentials each route turns the request away, rather than
+    // not being found
+    let command = client
+        .post(format!("{}/command", address))
+        .send_json(&serde_json::json!({}))
+        .await
+        .unwrap();
+    assert_eq!(401, command.status().as_u16());
+    let robot = serde_json::json!({"robot_serial_number": "no-key", "instruction": "Idle", "battery_level": 90});
+    let poll = client
+        .get(format!("{}/poll", address))
+        .send_json(&robot)
+        .await
+        .unwrap();
+    assert_eq!(401, poll.status().as_u16());
+    let ack = client
+        .post(format!("{}/ack", address))
+        .send_json(&serde_json::json!({"robot_serial_number": "no-key", "command_id": 1}))
+        .await
+        .unwrap();
+    assert_eq!(401, ack.status().as_u16());
+    let socket = client
+        .get(format!("{}/ws/no-key", address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(401, socket.status().as_u16());
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn ready_once_migrated() {
+    let (address, _conn, server) = spawn_app().await;
+
+    let mut response = actix_web::client::Client::new()
+        .get(format!("{}/ready", address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!({"status": "ready"}), body);
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn not_ready_without_database() {
+    let app_conn = missing_db_pool();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(app_conn.clone())
+            .service(api::health::ready)
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/ready", server.addrs()[0]);
+    let server = server.run();
+
+    let mut response = actix_web::client::Client::new()
+        .get(&url)
+        .timeout(std::time::Duration::from_secs(30))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(503, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["message"].is_string());
+
+    server.stop(true).await;
+}
+
+// Signs up an operator whose own robot is the one given, which puts them in
+// that robot's organization, returning the token their requests carry
+async fn operator_token(conn: &PgPool, robot_serial_number: &RobotSerial) -> String {
+    let user_name = unique_serial("operator");
+    let user = User::new(conn, &user_name, "password", robot_serial_number)
+        .await
+        .unwrap();
+
+    Token::new(&user).await.unwrap().token
+}
+
+#[actix_rt::test]
+async fn commands_issued_over_http() {
+    let (address, conn, server) = spawn_app().await;
+    let conn = &conn;
+    let serial = unique_serial("http_commands");
+    let token = operator_token(conn, &unique_serial("http_commands_operator")).await;
+    let client = actix_web::client::Client::new();
+    let robot_url = format!("{}/robots/{}", address, serial);
+
+    let mut response = client
+        .post(format!("{}/task", robot_url))
+        .header("Authorization", token.as_str())
+        .send_json(&CleaningPattern::Circular)
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let task: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        serde_json::json!({"Task": {"pattern": "Circular", "speed": 100, "passes": 1}}),
+        task["instruction"]
+    );
+
+    let mut response = client
+        .get(format!("{}/current", robot_url))
+        .header("Authorization", token.as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let current: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(task["command_id"], current["command_id"]);
+
+    let mut response = client
+        .post(format!("{}/abort", robot_url))
+        .header("Authorization", token.as_str())
+        .send_json(&AbortReason::Obstacle)
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let abort: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        serde_json::json!({"Abort": "Obstacle"}),
+        abort["instruction"]
+    );
+
+    let mut response = client
+        .post(format!("{}/idle", robot_url))
+        .header("Authorization", token.as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let idle: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!("Idle"), idle["instruction"]);
+    assert_eq!(
+        idle["command_id"].as_i64(),
+        Some(Command::current(conn, &serial).await.unwrap().id() as i64)
+    );
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn http_command_errors() {
+    let (address, conn, server) = spawn_app().await;
+    let serial = unique_serial("http_errors");
+    let token = operator_token(&conn, &unique_serial("http_errors_operator")).await;
+    let client = actix_web::client::Client::new();
+    let robot_url = format!("{}/robots/{}", address, serial);
+
+    // Nothing has been issued yet
+    let response = client
+        .get(format!("{}/current", robot_url))
+        .header("Authorization", token.as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(404, response.status().as_u16());
+
+    let response = client
+        .post(format!("{}/task", robot_url))
+        .header("Authorization", token.as_str())
+        .send_json(&serde_json::json!("Diagonal"))
+        .await
+        .unwrap();
+    assert_eq!(400, response.status().as_u16());
+
+    // Nothing is stored for a malformed serial number
+    let mut response = client
+        .post(format!("{}/robots/not.a.serial/idle", address))
+        .header("Authorization", token.as_str())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(400, response.status().as_u16());
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!("InvalidSerial"), error["error"]);
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn robot_routes_need_operator() {
+    let (address, conn, server) = spawn_app().await;
+    let conn = &conn;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let in_acme = Robot::add_to_organization(conn, &acme, &unique_serial("http_acme"))
+        .await
+        .unwrap();
+    Command::idle(conn, &in_acme).await.unwrap();
+    let client = actix_web::client::Client::new();
+    let robot_url = format!("{}/robots/{}", address, in_acme.unqualified());
+
+    // Without credentials nothing is issued or read
+    let routes = [
+        client.post(format!("{}/task", robot_url)),
+        client.post(format!("{}/abort", robot_url)),
+        client.post(format!("{}/idle", robot_url)),
+        client.get(format!("{}/current", robot_url)),
+    ];
+    for route in routes {
+        let response = route.send().await.unwrap();
+        assert_eq!(401, response.status().as_u16());
+    }
 
-// fn setup_test() {
-//     println!("SETTING UP TESTS");
-// }
+    // Another organization is told the robot doesn't exist
+    let globex_token =
+        operator_token(conn, &unique_serial("operator").in_organization(&globex)).await;
+    let routes = [
+        client
+            .post(format!("{}/task", robot_url))
+            .header("Authorization", globex_token.as_str())
+            .send_json(&CleaningPattern::ZigZag),
+        client
+            .post(format!("{}/abort", robot_url))
+            .header("Authorization", globex_token.as_str())
+            .send_json(&AbortReason::Saftey),
+        client
+            .post(format!("{}/idle", robot_url))
+            .header("Authorization", globex_token.as_str())
+            .send(),
+        client
+            .get(format!("{}/current", robot_url))
+            .header("Authorization", globex_token.as_str())
+            .send(),
+    ];
+    for response in routes {
+        assert_eq!(404, response.await.unwrap().status().as_u16());
+    }
+    assert_eq!(
+        Idle,
+        Command::current(conn, &in_acme).await.unwrap().instruction
+    );
 
-async fn db_connect() -> PgPool {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL to be set");
-    let database_pool = PgPool::connect(&database_url)
+    // Its own organization reaches it by the serial it polls with
+    let acme_token = operator_token(conn, &unique_serial("operator").in_organization(&acme)).await;
+    let response = client
+        .get(format!("{}/current", robot_url))
+        .header("Authorization", acme_token.as_str())
+        .send()
         .await
-        .expect("to get database pool");
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
 
-    database_pool
+    server.stop(true).await;
 }
 
-#[tokio::test]
-async fn set_idle_poll() {
+#[actix_rt::test]
+async fn robot_api_keys() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("api_key");
+    Robot::new(conn, &serial).await.unwrap();
+
+    // No key has been given out yet
+    assert!(!Robot::authenticate(conn, &serial, "").await.unwrap());
+
+    let api_key = Robot::rotate_key(conn, &serial).await.unwrap();
+    assert!(Robot::authenticate(conn, &serial, &api_key).await.unwrap());
+    assert!(!Robot::authenticate(conn, &serial, "wrong").await.unwrap());
+    assert!(
+        !Robot::authenticate(conn, &unique_serial("api_key"), &api_key)
+            .await
+            .unwrap()
+    );
+
+    let rotated = Robot::rotate_key(conn, &serial).await.unwrap();
+    assert!(Robot::authenticate(conn, &serial, &rotated).await.unwrap());
+    assert!(!Robot::authenticate(conn, &serial, &api_key).await.unwrap());
+}
+
+#[actix_rt::test]
+async fn serials_reused_across_organizations() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let serial = unique_serial("shared");
+
+    let in_acme = Robot::add_to_organization(conn, &acme, &serial)
+        .await
+        .unwrap();
+    let in_globex = Robot::add_to_organization(conn, &globex, &serial)
+        .await
+        .unwrap();
+    assert_ne!(in_acme, in_globex);
+
+    let acme_key = Robot::rotate_key(conn, &in_acme).await.unwrap();
+    let globex_key = Robot::rotate_key(conn, &in_globex).await.unwrap();
+    Command::task(conn, &in_acme, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    Command::task(conn, &in_globex, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+
+    // Each robot polls with the same serial, its key says which it is
+    assert_eq!(
+        Some(in_acme.clone()),
+        Robot::authenticate_key(conn, &serial, &acme_key)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        Some(in_globex.clone()),
+        Robot::authenticate_key(conn, &serial, &globex_key)
+            .await
+            .unwrap()
+    );
+
+    assert_eq!(
+        Instruction::task(CleaningPattern::ZigZag),
+        Command::current_in(conn, &acme, &serial)
+            .await
+            .unwrap()
+            .instruction
+    );
+    assert_eq!(
+        Instruction::task(CleaningPattern::Spiral),
+        Command::pending_in(conn, &globex, &serial)
+            .await
+            .unwrap()
+            .instruction
+    );
+
+    let fleet = Command::current_fleet(conn, &acme).await.unwrap();
+    assert_eq!(1, fleet.len());
+    assert!(fleet.contains_key(in_acme.as_str()));
+}
+
+#[actix_rt::test]
+async fn cross_organization_reads_blocked() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let serial = unique_serial("acme_only");
+    let unscoped = unique_serial("no_organization");
+
+    let in_acme = Robot::add_to_organization(conn, &acme, &serial)
+        .await
+        .unwrap();
+    Command::task(conn, &in_acme, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+    Command::task(conn, &unscoped, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+
+    // Another organization can't tell the robot exists, even when asking
+    // for it by its qualified serial
+    for serial in &[&serial, &in_acme] {
+        assert!(matches!(
+            Command::current_in(conn, &globex, serial).await,
+            Err(ApiError::NotFound)
+        ));
+        assert!(matches!(
+            Command::pending_in(conn, &globex, serial).await,
+            Err(ApiError::NotFound)
+        ));
+    }
+    assert!(matches!(
+        Command::current_in(conn, &acme, &unscoped).await,
+        Err(ApiError::NotFound)
+    ));
+    assert!(Command::current_fleet(conn, &globex)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Nor can a robot outside the organization use its own key for it
+    Robot::new(conn, &unscoped).await.unwrap();
+    let api_key = Robot::rotate_key(conn, &unscoped).await.unwrap();
+    assert_eq!(
+        None,
+        Robot::authenticate_key(conn, &serial, &api_key)
+            .await
+            .unwrap()
+    );
+}
+
+#[actix_rt::test]
+async fn poll_requires_api_key() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_api_key");
+    Robot::new(conn, &serial).await.unwrap();
+    Command::idle(conn, &serial).await.unwrap();
+    let api_key = Robot::rotate_key(conn, &serial).await.unwrap();
+
+    let app_conn = conn.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(app_conn.clone())
+            .service(api::poll::robot_poll)
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/poll", server.addrs()[0]);
+    let server = server.run();
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    let client = actix_web::client::Client::new();
+
+    let mut response = client
+        .get(&url)
+        .header("X-Api-Key", api_key)
+        .send_json(&poll)
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let polled: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!("Idle"), polled["instruction"]);
+
+    let mut response = client
+        .get(&url)
+        .header("X-Api-Key", "wrong")
+        .send_json(&poll)
+        .await
+        .unwrap();
+    assert_eq!(401, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(serde_json::json!("Unauthorized"), body["error"]);
+
+    let response = client.get(&url).send_json(&poll).await.unwrap();
+    assert_eq!(401, response.status().as_u16());
+
+    server.stop(true).await;
+}
+
+#[actix_rt::test]
+async fn first_poll_starts_idle() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("first_contact");
+
+    assert!(matches!(
+        Command::current(conn, &serial).await,
+        Err(ApiError::NoCommandsForRobot)
+    ));
+
+    let poll = Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    let command = Poll::poll(conn, &poll).await.unwrap();
+    assert_eq!(Idle, command.instruction);
+    assert_eq!(
+        command.id(),
+        Command::current(conn, &serial).await.unwrap().id()
+    );
+}
+
+// The value of a metric from a Prometheus scrape, zero if it isn't there
+fn scraped_value(scrape: &str, metric: &str) -> f64 {
+    scrape
+        .lines()
+        .find_map(|line| line.strip_prefix(metric)?.strip_prefix(' '))
+        .map_or(0.0, |value| value.parse().unwrap())
+}
+
+#[actix_rt::test]
+async fn metrics_count_commands() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("metrics");
+
+    let app_conn = conn.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(app_conn.clone())
+            .service(api::metrics::scrape_metrics)
+    })
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/metrics", server.addrs()[0]);
+    let server = server.run();
+    let client = actix_web::client::Client::new();
+
+    let scrape = |url: String| {
+        let request = client.get(url).send();
+        async move {
+            let mut response = request.await.unwrap();
+            assert_eq!(200, response.status().as_u16());
+            String::from_utf8(response.body().await.unwrap().to_vec()).unwrap()
+        }
+    };
+
+    let before = scrape(url.clone()).await;
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::Spot)
+        .await
+        .unwrap();
+    task.complete(conn).await.unwrap();
+    let after = scrape(url).await;
+
+    // Other tests issue commands at the same time so the counters can only
+    // be relied on to have gone up by at least as much
+    for metric in &[
+        "sdp_commands_issued_total{instruction=\"Idle\"}",
+        "sdp_commands_issued_total{instruction=\"Task\"}",
+        "sdp_commands_completed_total",
+    ] {
+        assert!(scraped_value(&after, metric) >= scraped_value(&before, metric) + 1.0);
+    }
+    assert!(after.contains("sdp_pending_commands "));
+
+    server.stop(true).await;
+}
+
+// Runs a task until the robot aborts it, then reports idle
+async fn poll_through_abort(conn: &PgPool, serial: &RobotSerial, reason: AbortReason) -> Command {
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, serial).await.unwrap();
+    let task = Command::task(conn, serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    let running = Poll::poll(conn, &poll(Instruction::task(CleaningPattern::Spiral)))
+        .await
+        .unwrap();
+    assert_eq!(task.id(), running.id());
+
+    let abort = Poll::poll(conn, &poll(Abort(reason.clone())))
+        .await
+        .unwrap();
+    assert_eq!(Abort(reason), abort.instruction);
+
+    Poll::poll(conn, &poll(Idle)).await.unwrap()
+}
+
+#[actix_rt::test]
+async fn task_resumed_after_obstacle() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("obstacle_resume");
+
+    let resumed = poll_through_abort(conn, &serial, AbortReason::Obstacle).await;
+    assert_eq!(
+        Instruction::task(CleaningPattern::Spiral),
+        resumed.instruction
+    );
+    assert!(!resumed.completed);
+}
+
+#[actix_rt::test]
+async fn task_resumed_after_reported_low_battery() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("low_battery_report_resume");
+
+    let resumed = poll_through_abort(conn, &serial, AbortReason::LowBattery).await;
+    assert_eq!(
+        Instruction::task(CleaningPattern::Spiral),
+        resumed.instruction
+    );
+}
+
+#[actix_rt::test]
+async fn task_not_resumed_after_other_aborts() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("abort_no_resume");
+
+    let next = poll_through_abort(conn, &serial, AbortReason::Saftey).await;
+    assert_eq!(Idle, next.instruction);
+}
+
+#[actix_rt::test]
+async fn task_resumed_after_charging() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("low_battery_resume");
+    let zig_zag = Instruction::task(CleaningPattern::ZigZag);
+    let poll = |instruction, battery_level| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::ZigZag)
+        .await
+        .unwrap();
+    let running = Poll::poll(conn, &poll(zig_zag.clone(), 90)).await.unwrap();
+    assert_eq!(task.id(), running.id());
+
+    let mut abort = running;
+    for _ in 0..3 {
+        abort = Poll::poll(conn, &poll(zig_zag.clone(), 20)).await.unwrap();
+    }
+    assert_eq!(Abort(AbortReason::LowBattery), abort.instruction);
+
+    // The task is put aside, not completed
+    let interrupted = sqlx::query!(
+        "SELECT cancelled, cancel_reason FROM Commands WHERE command_id = $1",
+        task.id()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert!(interrupted.cancelled);
+    assert_eq!(Some("low_battery".to_string()), interrupted.cancel_reason);
+
+    // Charging, the robot is kept on the same abort until it has recovered
+    let charging = Poll::poll(conn, &poll(Idle, 30)).await.unwrap();
+    assert_eq!(abort.id(), charging.id());
+
+    let resumed = Poll::poll(conn, &poll(Idle, 90)).await.unwrap();
+    assert_eq!(zig_zag, resumed.instruction);
+    assert_ne!(task.id(), resumed.id());
+    assert!(!resumed.completed);
+    assert!(
+        Command::get_including_deleted(conn, abort.id())
+            .await
+            .unwrap()
+            .completed
+    );
+}
+
+#[actix_rt::test]
+async fn task_parameters_must_match() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("task_params");
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+    let slow_task = |speed| Instruction::Task {
+        pattern: CleaningPattern::Spiral,
+        speed,
+        passes: 2,
+        zone: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task_with_params(conn, &serial, &CleaningPattern::Spiral, 50, 2)
+        .await
+        .unwrap();
+    assert_eq!(slow_task(50), task.instruction);
+
+    let running = Poll::poll(conn, &poll(slow_task(50))).await.unwrap();
+    assert_eq!(task.id(), running.id());
+    assert_eq!(slow_task(50), running.instruction);
+
+    // The same pattern run at another speed isn't the task that was
+    // issued, so it is issued as a new one
+    let faster = Poll::poll(conn, &poll(slow_task(80))).await.unwrap();
+    assert_ne!(task.id(), faster.id());
+    assert_eq!(slow_task(80), faster.instruction);
+    assert_eq!(
+        faster.id(),
+        Command::current(conn, &serial).await.unwrap().id()
+    );
+    assert!(
+        Command::get_including_deleted(conn, task.id())
+            .await
+            .unwrap()
+            .completed
+    );
+}
+
+#[actix_rt::test]
+async fn poll_acknowledges_handed_over_command() {
+    let conn = &db_connect().await;
+    let poll = |serial: &RobotSerial| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    let serial = unique_serial("poll_acknowledges");
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task(conn, &serial, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+    assert!(Command::current(conn, &serial)
+        .await
+        .unwrap()
+        .acknowledged_at
+        .is_none());
+
+    let delivered = Poll::poll(conn, &poll(&serial)).await.unwrap();
+    assert_eq!(task.id(), delivered.id());
+    let current = Command::current(conn, &serial).await.unwrap();
+    assert!(current.acknowledged_at.is_some());
+    assert!(!current.completed);
+
+    // Robots that acknowledge commands themselves are left to do so
+    let serial = unique_serial("poll_acknowledges_self");
+    Robot::new(conn, &serial).await.unwrap();
+    Robot::set_require_ack(conn, &serial, true).await.unwrap();
+    Command::idle(conn, &serial).await.unwrap();
+    Command::task(conn, &serial, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+    Poll::poll(conn, &poll(&serial)).await.unwrap();
+    assert!(Command::current(conn, &serial)
+        .await
+        .unwrap()
+        .acknowledged_at
+        .is_none());
+}
+
+#[actix_rt::test]
+async fn current_command_of_whole_fleet() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let idle = unique_serial("fleet_current_idle").in_organization(&acme);
+    let task = unique_serial("fleet_current_task").in_organization(&acme);
+    let abort = unique_serial("fleet_current_abort").in_organization(&acme);
+    let corrupt = unique_serial("fleet_current_corrupt").in_organization(&acme);
+
+    Command::task(conn, &idle, &CleaningPattern::Spot)
+        .await
+        .unwrap();
+    let latest_idle = Command::idle(conn, &idle).await.unwrap();
+    Command::idle(conn, &task).await.unwrap();
+    let latest_task = Command::task(conn, &task, &CleaningPattern::Edge)
+        .await
+        .unwrap();
+    let latest_abort = Command::abort(conn, &abort, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+    sqlx::query!(
+        r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
+VALUES ($1, now(), now(), $2)
+        "#,
+        corrupt.as_str(),
+        serde_json::json!({"Task": "Diagonal"})
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+
+    let fleet = Command::current_fleet(conn, &acme).await.unwrap();
+    for latest in &[latest_idle, latest_task, latest_abort] {
+        let current = &fleet[&latest.robot_serial_number];
+        assert_eq!(latest.id(), current.id());
+        assert_eq!(latest.instruction, current.instruction);
+    }
+    assert!(!fleet.contains_key(corrupt.as_str()));
+}
+
+#[actix_rt::test]
+async fn soft_deleted_delivered_command_not_followed() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("soft_delete_delivered");
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    let task = Command::task(conn, &serial, &CleaningPattern::Spiral)
+        .await
+        .unwrap();
+    let delivered = Poll::poll(conn, &poll(Idle)).await.unwrap();
+    assert_eq!(task.id(), delivered.id());
+
+    task.soft_delete(conn).await.unwrap();
+    assert_eq!(None, Command::last_delivered(conn, &serial).await.unwrap());
+    assert_eq!(
+        None,
+        Command::active_at(conn, &serial, Utc::now()).await.unwrap()
+    );
+
+    let next = Poll::poll(conn, &poll(Idle)).await.unwrap();
+    assert_ne!(task.id(), next.id());
+    assert_eq!(
+        next.id(),
+        Command::last_delivered(conn, &serial)
+            .await
+            .unwrap()
+            .unwrap()
+            .id()
+    );
+}
+
+#[actix_rt::test]
+async fn soft_deleted_command_kept_for_audit() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("soft_delete");
+
+    let idle = Command::idle(conn, &serial).await.unwrap();
+    assert_eq!(None, idle.issued_by);
+    let now = Utc::now();
+    let task = Command::new(
+        conn,
+        &serial,
+        now,
+        now,
+        &Instruction::task(CleaningPattern::Circular),
+        &CommandConfig::default(),
+        Some("operator"),
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(Some("operator".to_string()), task.issued_by);
+    assert_eq!(
+        task.id(),
+        Command::pending(conn, &serial).await.unwrap().id()
+    );
+
+    task.soft_delete(conn).await.unwrap();
+    assert_ne!(
+        task.id(),
+        Command::pending(conn, &serial).await.unwrap().id()
+    );
+    assert_eq!(
+        idle.id(),
+        Command::current(conn, &serial).await.unwrap().id()
+    );
+    let history = Command::history(conn, &serial, 10, 0).await.unwrap();
+    assert!(history.iter().all(|c| c.id() != task.id()));
+
+    let deleted = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert_eq!(Some("operator".to_string()), deleted.issued_by);
+    assert_eq!(
+        Instruction::task(CleaningPattern::Circular),
+        deleted.instruction
+    );
+}
+
+#[actix_rt::test]
+async fn poll_past_rate_limit() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("poll_past_rate_limit");
+    Robot::new(conn, &serial).await.unwrap();
+
+    // The operator uses up the robot's commands for the window, then
+    // changes their mind
+    let rate_limit = CommandConfig::global().rate_limit;
+    for _ in 1..rate_limit {
+        Command::task(conn, &serial, &CleaningPattern::Spot)
+            .await
+            .unwrap();
+    }
+    Command::cancel_pending(conn, &serial).await.unwrap();
+
+    // The robot is still answered, with its fallback when there is
+    // nothing left to do
+    for _ in 0..=rate_limit {
+        let command = Poll::poll(
+            conn,
+            &Poll {
+                robot_serial_number: serial.clone(),
+                instruction: Idle,
+                battery_level: 80,
+                report: None,
+                x: None,
+                y: None,
+                heading: None,
+                acknowledged: None,
+                config_version: None,
+                progress: None,
+                firmware_version: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(Idle, command.instruction);
+    }
+    Command::idle(conn, &serial).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn commands_rate_limited() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("rate_limited");
+    let config = CommandConfig {
+        rate_limit: 3,
+        ..CommandConfig::default()
+    };
+    let issue = |instruction| {
+        let now = Utc::now();
+        let serial = serial.clone();
+        async move { Command::new(conn, &serial, now, now, &instruction, &config, None, None).await }
+    };
+
+    for _ in 0..3 {
+        issue(Instruction::task(CleaningPattern::Spot))
+            .await
+            .unwrap();
+    }
+    assert!(matches!(
+        issue(Instruction::task(CleaningPattern::Spot)).await,
+        Err(ApiError::RateLimited)
+    ));
+
+    // Aborts are for safety so are never turned away
+    issue(Abort(AbortReason::Saftey)).await.unwrap();
+    Command::abort(conn, &serial, &AbortReason::LowBattery)
+        .await
+        .unwrap();
+
+    // Other robots aren't held up
+    let other = unique_serial("rate_limited");
+    let now = Utc::now();
+    Command::new(conn, &other, now, now, &Idle, &config, None, None)
+        .await
+        .unwrap();
+}
+
+#[actix_rt::test]
+async fn idempotency_key_issues_once() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("idempotent");
+    let config = CommandConfig::default();
+    let issue = |serial: RobotSerial, key: &'static str| {
+        let now = Utc::now();
+        let instruction = Instruction::task(CleaningPattern::Edge);
+        let config = &config;
+        async move {
+            Command::new(
+                conn,
+                &serial,
+                now,
+                now,
+                &instruction,
+                config,
+                None,
+                Some(key),
+            )
+            .await
+        }
+    };
+
+    // Requests racing with the same key all get the one command back
+    let racing = futures::future::join_all((0..8).map(|_| issue(serial.clone(), "retry-1"))).await;
+    let first = racing[0].as_ref().unwrap().id();
+    for command in &racing {
+        assert_eq!(first, command.as_ref().unwrap().id());
+    }
+
+    let stored = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM Commands WHERE robot_serial_number = $1"#,
+        serial.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap();
+    assert_eq!(1, stored.count);
+
+    // A late retry still gets the command even though its time is now
+    // outside of the buffer
+    let late = Utc::now() - Duration::hours(1);
+    let retried = Command::new(
+        conn,
+        &serial,
+        late,
+        late,
+        &Instruction::task(CleaningPattern::Edge),
+        &config,
+        None,
+        Some("retry-1"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(first, retried.id());
+
+    // Keys are only unique for each robot, and a new key is a new command
+    let other = issue(unique_serial("idempotent"), "retry-1").await.unwrap();
+    assert_ne!(first, other.id());
+    let next = issue(serial.clone(), "retry-2").await.unwrap();
+    assert_ne!(first, next.id());
+}
+
+// Issues each instruction to the robot an hour apart, oldest first
+async fn issue_hourly(
+    conn: &PgPool,
+    serial: &RobotSerial,
+    instructions: &[Instruction],
+) -> Vec<Command> {
+    let start = Utc::now() - Duration::hours(instructions.len() as i64);
+    let mut commands = Vec::new();
+
+    for (hour, instruction) in instructions.iter().enumerate() {
+        let time = start + Duration::hours(hour as i64);
+        let command = Command::new_unbuffered(conn, serial, time, time, instruction)
+            .await
+            .unwrap();
+        commands.push(command);
+    }
+
+    commands
+}
+
+#[actix_rt::test]
+async fn search_aborts() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("search_aborts");
+    let issued = issue_hourly(
+        conn,
+        &serial,
+        &[
+            Abort(AbortReason::Obstacle),
+            Instruction::task(CleaningPattern::Spot),
+            Idle,
+            Abort(AbortReason::LowBattery),
+        ],
+    )
+    .await;
+
+    let aborts = Command::search(
+        conn,
+        &serial,
+        &InstructionFilter {
+            kind: Some("Abort".to_string()),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![issued[3].id(), issued[0].id()],
+        aborts.iter().map(Command::id).collect::<Vec<_>>()
+    );
+
+    // Instructions without parameters are stored as a plain string
+    let idle = Command::search(
+        conn,
+        &serial,
+        &InstructionFilter {
+            kind: Some("Idle".to_string()),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![issued[2].id()],
+        idle.iter().map(Command::id).collect::<Vec<_>>()
+    );
+
+    // Only the aborts within the time range
+    let recent = Command::search(
+        conn,
+        &serial,
+        &InstructionFilter {
+            kind: Some("Abort".to_string()),
+            from: Some(Utc::now() - Duration::minutes(150)),
+            to: Some(Utc::now()),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![issued[3].id()],
+        recent.iter().map(Command::id).collect::<Vec<_>>()
+    );
+}
+
+#[actix_rt::test]
+async fn search_tasks_by_pattern() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("search_tasks");
+    let issued = issue_hourly(
+        conn,
+        &serial,
+        &[
+            Instruction::task(CleaningPattern::Edge),
+            Instruction::task(CleaningPattern::Spiral),
+            Instruction::Task {
+                pattern: CleaningPattern::Edge,
+                speed: 40,
+                passes: 2,
+                zone: None,
+            },
+        ],
+    )
+    .await;
+
+    // A task written before tasks had parameters
+    let legacy = sqlx::query!(
+        r#"
+INSERT INTO Commands (robot_serial_number, time_issued, time_instruction, instruction)
+VALUES ( $1, now(), now(), '{"Task": "Edge"}' )
+RETURNING command_id
+        "#,
+        serial.as_str()
+    )
+    .fetch_one(conn)
+    .await
+    .unwrap()
+    .command_id;
+
+    let edge = Command::search(
+        conn,
+        &serial,
+        &InstructionFilter {
+            pattern: Some(CleaningPattern::Edge),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        vec![legacy, issued[2].id(), issued[0].id()],
+        edge.iter().map(Command::id).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        Instruction::task(CleaningPattern::Edge),
+        edge[0].instruction
+    );
+
+    let tasks = Command::search(
+        conn,
+        &serial,
+        &InstructionFilter {
+            kind: Some("Task".to_string()),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(4, tasks.len());
+}
+
+#[actix_rt::test]
+async fn current_breaks_ties_by_insert_order() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("current_ties");
+    let issued = Utc::now();
+
+    let first = Command::new_unbuffered(conn, &serial, issued, issued, &Idle)
+        .await
+        .unwrap();
+    let second = Command::new_unbuffered(
+        conn,
+        &serial,
+        issued,
+        issued,
+        &Instruction::task(CleaningPattern::Spot),
+    )
+    .await
+    .unwrap();
+    assert!(second.id() > first.id());
+
+    for _ in 0..3 {
+        assert_eq!(
+            second.id(),
+            Command::current(conn, &serial).await.unwrap().id()
+        );
+    }
+
+    // A deleted command is passed over for the one issued with it
+    second.soft_delete(conn).await.unwrap();
+    assert_eq!(
+        first.id(),
+        Command::current(conn, &serial).await.unwrap().id()
+    );
+}
+
+#[actix_rt::test]
+async fn completion_time_kept() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("completed_at");
+    let issued = Utc::now() - Duration::seconds(5);
+
+    let command = Command::new_unbuffered(conn, &serial, issued, issued, &Idle)
+        .await
+        .unwrap();
+    assert_eq!(None, command.completed_at);
+
+    command.complete(conn).await.unwrap();
+    let completed_at = Command::get_including_deleted(conn, command.id())
+        .await
+        .unwrap()
+        .completed_at
+        .unwrap();
+    assert!(completed_at >= issued);
+
+    // Completing it again, however that happens, keeps the first time
+    command.complete(conn).await.unwrap();
+    Command::complete_with_status(conn, &serial, command.id(), &TaskStatus::Failed, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(completed_at),
+        Command::get_including_deleted(conn, command.id())
+            .await
+            .unwrap()
+            .completed_at
+    );
+}
+
+#[actix_rt::test]
+async fn abort_all_active_robots() {
+    let conn = &db_connect().await;
+    let acme = OrganizationId::parse(&unique_serial("acme")).unwrap();
+    let globex = OrganizationId::parse(&unique_serial("globex")).unwrap();
+    let issued = Utc::now();
+    let task = Instruction::task(CleaningPattern::ZigZag);
+
+    let mut cleaning = vec![
+        unique_serial("abort_all_1").in_organization(&acme),
+        unique_serial("abort_all_2").in_organization(&acme),
+        unique_serial("abort_all_3").in_organization(&acme),
+    ];
+    cleaning.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    for serial in &cleaning {
+        Command::new_unbuffered(conn, serial, issued, issued, &task)
+            .await
+            .unwrap();
+    }
+    let aborting = unique_serial("abort_all_aborting").in_organization(&acme);
+    Command::new_unbuffered(conn, &aborting, issued, issued, &task)
+        .await
+        .unwrap();
+    Command::abort(conn, &aborting, &AbortReason::Obstacle)
+        .await
+        .unwrap();
+    let idle = unique_serial("abort_all_idle").in_organization(&acme);
+    Command::new_unbuffered(conn, &idle, issued, issued, &Idle)
+        .await
+        .unwrap();
+    let elsewhere = unique_serial("abort_all_elsewhere").in_organization(&globex);
+    Command::new_unbuffered(conn, &elsewhere, issued, issued, &task)
+        .await
+        .unwrap();
+
+    let aborts = Command::abort_all(conn, &acme, &AbortReason::Saftey)
+        .await
+        .unwrap();
+    let mut aborted = aborts
+        .iter()
+        .map(|c| c.robot_serial_number.clone())
+        .collect::<Vec<_>>();
+    aborted.sort();
+    assert_eq!(
+        cleaning.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+        aborted
+    );
+
+    for serial in &cleaning {
+        let current = Command::current(conn, serial).await.unwrap();
+        assert_eq!(Abort(AbortReason::Saftey), current.instruction);
+        assert!(!current.completed);
+    }
+
+    // The robot already aborting keeps the one abort it had
+    let pending = Command::search(
+        conn,
+        &aborting,
+        &InstructionFilter {
+            kind: Some("Abort".to_string()),
+            ..InstructionFilter::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(1, pending.len());
+
+    // Another organization's robots carry on with their tasks
+    assert_eq!(
+        task,
+        Command::current(conn, &elsewhere)
+            .await
+            .unwrap()
+            .instruction
+    );
+
+    // Each robot now has an abort waiting, so a second broadcast adds none
+    assert!(Command::abort_all(conn, &acme, &AbortReason::Saftey)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_rt::test]
+async fn full_queue_rejects_commands() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("queue_reject");
+    let config = CommandConfig {
+        max_queue_depth: 3,
+        ..CommandConfig::default()
+    };
+    let issue = |instruction| {
+        let now = Utc::now();
+        let serial = serial.clone();
+        async move { Command::new(conn, &serial, now, now, &instruction, &config, None, None).await }
+    };
+
+    let first = issue(Instruction::task(CleaningPattern::Spot))
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        issue(Idle).await.unwrap();
+    }
+    assert!(matches!(issue(Idle).await, Err(ApiError::QueueFull)));
+
+    // Aborts still get through, and completing commands makes room
+    let abort = issue(Abort(AbortReason::Saftey)).await.unwrap();
+    first.complete(conn).await.unwrap();
+    assert!(matches!(issue(Idle).await, Err(ApiError::QueueFull)));
+    abort.complete(conn).await.unwrap();
+    issue(Idle).await.unwrap();
+}
+
+#[actix_rt::test]
+async fn full_queue_replaces_oldest() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("queue_replace");
+    let config = CommandConfig {
+        max_queue_depth: 3,
+        queue_mode: QueueMode::Replace,
+        ..CommandConfig::default()
+    };
+    let issue = |instruction, issued| {
+        let serial = serial.clone();
+        async move {
+            Command::new(
+                conn,
+                &serial,
+                issued,
+                issued,
+                &instruction,
+                &config,
+                None,
+                None,
+            )
+            .await
+        }
+    };
+
+    let now = Utc::now();
+    let abort = issue(Abort(AbortReason::Obstacle), now - Duration::seconds(30))
+        .await
+        .unwrap();
+    let task = issue(
+        Instruction::task(CleaningPattern::Spot),
+        now - Duration::seconds(20),
+    )
+    .await
+    .unwrap();
+    let idle = issue(Idle, now - Duration::seconds(10)).await.unwrap();
+
+    // The oldest command that isn't an abort is cancelled for the new one
+    issue(Instruction::task(CleaningPattern::ZigZag), now)
+        .await
+        .unwrap();
+    let task = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+    assert!(task.completed && task.cancelled);
+    for kept in &[abort.id(), idle.id()] {
+        assert!(
+            !Command::get_including_deleted(conn, *kept)
+                .await
+                .unwrap()
+                .completed
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn full_queue_keeps_running_command() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("queue_running");
+    let config = CommandConfig {
+        max_queue_depth: 2,
+        queue_mode: QueueMode::Replace,
+        ..CommandConfig::default()
+    };
+    let issue = |instruction, issued| {
+        let serial = serial.clone();
+        async move {
+            Command::new(
+                conn,
+                &serial,
+                issued,
+                issued,
+                &instruction,
+                &config,
+                None,
+                None,
+            )
+            .await
+        }
+    };
+
+    let now = Utc::now();
+    let running = issue(
+        Instruction::task(CleaningPattern::Spot),
+        now - Duration::seconds(30),
+    )
+    .await
+    .unwrap();
+    running.deliver(conn).await.unwrap();
+    let waiting = issue(Idle, now - Duration::seconds(20)).await.unwrap();
+
+    // The running task isn't queued, so there is still room
+    issue(Idle, now - Duration::seconds(10)).await.unwrap();
+    assert!(
+        !Command::get_including_deleted(conn, waiting.id())
+            .await
+            .unwrap()
+            .completed
+    );
+
+    // Once the queue is full the waiting command makes way, not the task
+    issue(Instruction::task(CleaningPattern::ZigZag), now)
+        .await
+        .unwrap();
+    let waiting = Command::get_including_deleted(conn, waiting.id())
+        .await
+        .unwrap();
+    assert!(waiting.completed && waiting.cancelled);
+    assert!(
+        !Command::get_including_deleted(conn, running.id())
+            .await
+            .unwrap()
+            .completed
+    );
+}
+
+#[actix_rt::test]
+async fn task_zones() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("task_zones");
+    // Zones are shared by the fleet so each run uses its own
+    let zone = format!("aisle-{}", Utc::now().timestamp_nanos());
+    let other_zone = format!("dock-{}", Utc::now().timestamp_nanos());
+    let poll = |instruction| Poll {
+        robot_serial_number: serial.clone(),
+        instruction,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version: None,
+    };
+
+    Command::idle(conn, &serial).await.unwrap();
+    let task = Command::task_in_zone(conn, &serial, &CleaningPattern::Spot, &zone)
+        .await
+        .unwrap();
+    let stored = Command::get_including_deleted(conn, task.id())
+        .await
+        .unwrap();
+    assert_eq!(task.instruction, stored.instruction);
+
+    let running = Poll::poll(conn, &poll(task.instruction.clone()))
+        .await
+        .unwrap();
+    assert_eq!(task.id(), running.id());
+
+    // The same pattern somewhere else isn't the task that was issued, so
+    // it is issued as a new one the same as a change of parameters
+    let elsewhere = Instruction::Task {
+        pattern: CleaningPattern::Spot,
+        speed: 100,
+        passes: 1,
+        zone: Some(other_zone.clone()),
+    };
+    let moved = Poll::poll(conn, &poll(elsewhere.clone())).await.unwrap();
+    assert_ne!(task.id(), moved.id());
+    assert_eq!(elsewhere, moved.instruction);
+    assert!(
+        Command::get_including_deleted(conn, task.id())
+            .await
+            .unwrap()
+            .completed
+    );
+
+    Command::task(conn, &serial, &CleaningPattern::Spot)
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![task.id()],
+        Command::tasks_for_zone(conn, &zone)
+            .await
+            .unwrap()
+            .iter()
+            .map(Command::id)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![moved.id()],
+        Command::tasks_for_zone(conn, &other_zone)
+            .await
+            .unwrap()
+            .iter()
+            .map(Command::id)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[actix_rt::test]
+async fn poll_records_firmware_version() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("firmware");
+    // Versions are counted across the fleet so each run reports its own
+    let version = format!("2.4.{}", Utc::now().timestamp_nanos());
+    Robot::register(conn, &serial, "sweeper-s1", 30)
+        .await
+        .unwrap();
+    let poll = |firmware_version| Poll {
+        robot_serial_number: serial.clone(),
+        instruction: Idle,
+        battery_level: 90,
+        report: None,
+        x: None,
+        y: None,
+        heading: None,
+        acknowledged: None,
+        config_version: None,
+        progress: None,
+        firmware_version,
+    };
+
+    Poll::poll(conn, &poll(Some(version.clone())))
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(&1),
+        Robot::firmware_distribution(conn)
+            .await
+            .unwrap()
+            .get(&version)
+    );
+
+    // A poll that doesn't say which firmware it's running keeps the last one
+    Poll::poll(conn, &poll(None)).await.unwrap();
+    assert_eq!(
+        Some(&1),
+        Robot::firmware_distribution(conn)
+            .await
+            .unwrap()
+            .get(&version)
+    );
+}
+
+#[actix_rt::test]
+async fn command_issued_after_connection_lost() {
     let conn = &db_connect().await;
-    assert_eq!(1, 1);
+    let serial = unique_serial("write_retried");
+    let config = CommandConfig {
+        retry: RetryPolicy {
+            attempts: 3,
+            base_delay: std::time::Duration::from_millis(1),
+        },
+        ..CommandConfig::default()
+    };
+    let issued = || async {
+        sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM Commands WHERE robot_serial_number = $1"#,
+            serial.as_str()
+        )
+        .fetch_one(conn)
+        .await
+        .unwrap()
+        .count
+    };
+
+    // The commit goes through but the reply is lost, the retry finds the
+    // command by its key rather than inserting it again
+    let now = Utc::now();
+    LOSE_CONNECTION_AFTER_COMMIT.with(|lose| lose.set(1));
+    let command = Command::new(conn, &serial, now, now, &Idle, &config, None, Some("lost"))
+        .await
+        .unwrap();
+    assert_eq!(1, issued().await);
+    assert_eq!(
+        command.id(),
+        Command::current(conn, &serial).await.unwrap().id()
+    );
+
+    // Without a key it can't tell, so the error is returned instead
+    LOSE_CONNECTION_AFTER_COMMIT.with(|lose| lose.set(1));
+    let unkeyed = Command::new(conn, &serial, now, now, &Idle, &config, None, None).await;
+    assert!(matches!(unkeyed, Err(ApiError::ConnectionLost)));
+    assert_eq!(2, issued().await);
 }
 
-// spawn_app().await.expect("Failed to spawn our app.");
+#[actix_rt::test]
+async fn command_log_exported() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("export");
+    issue_hourly(
+        conn,
+        &serial,
+        &[
+            Instruction::task(CleaningPattern::Spiral),
+            Abort(AbortReason::LowBattery),
+            Idle,
+        ],
+    )
+    .await;
+
+    let csv = Command::export(conn, &serial, ExportFormat::Csv)
+        .await
+        .unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        "command_id,time_issued,time_instruction,instruction,completed",
+        rows[0]
+    );
+    assert_eq!(4, rows.len());
+    assert!(rows[2].ends_with(",Abort LowBattery,false"));
+    assert!(rows[3].ends_with(",Task Spiral speed=100 passes=1,false"));
+
+    let json = Command::export(conn, &serial, ExportFormat::Json)
+        .await
+        .unwrap();
+    let commands: Vec<Command> = serde_json::from_str(&json).unwrap();
+    assert_eq!(
+        Command::history(conn, &serial, 10, 0)
+            .await
+            .unwrap()
+            .iter()
+            .map(Command::id)
+            .collect::<Vec<_>>(),
+        commands.iter().map(Command::id).collect::<Vec<_>>()
+    );
+    assert_eq!(json, serde_json::to_string(&commands).unwrap());
+}
 
-// Set the robot to the idle state
-// Command::idle(conn, TEST_SERIAL).await.unwrap();
+#[actix_rt::test]
+async fn times_unchanged_by_session_timezone() {
+    let conn = &db_connect().await;
+    let serial = unique_serial("timezone");
+    let issued = Utc.ymd(2021, 3, 28).and_hms(1, 30, 0);
+    let command = Command::new_unbuffered(conn, &serial, issued, issued, &Idle)
+        .await
+        .unwrap();
 
-// let poll = Poll {
-//     robot_serial_number: TEST_SERIAL.to_string(),
-//     instruction: Idle,
-//     battery_level: 90,
-// };
+    // A session on the other side of the world sees the same instant
+    let mut session = conn.acquire().await.unwrap();
+    sqlx::query("SET TIME ZONE 'Pacific/Auckland'")
+        .execute(&mut session)
+        .await
+        .unwrap();
+    let stored = sqlx::query!(
+        r#"
+SELECT time_issued, time_instruction, time_issued::TEXT AS "local!" FROM Commands
+WHERE command_id = $1
+        "#,
+        command.id()
+    )
+    .fetch_one(&mut session)
+    .await
+    .unwrap();
+    assert_eq!("2021-03-28 14:30:00+13", stored.local);
+    assert_eq!(issued, stored.time_issued);
+    assert_eq!(issued, stored.time_instruction);
 
-// let result = Poll::poll(conn, &poll).await.unwrap();
+    // And what it writes is read back unchanged everywhere else
+    let instruction = issued + Duration::hours(2);
+    sqlx::query!(
+        "UPDATE Commands SET time_instruction = $2 WHERE command_id = $1",
+        command.id(),
+        instruction
+    )
+    .execute(&mut session)
+    .await
+    .unwrap();
+    sqlx::query("RESET TIME ZONE")
+        .execute(&mut session)
+        .await
+        .unwrap();
+    drop(session);
 
-// assert_eq!(Idle, result.instruction);
+    let read_back = Command::get_including_deleted(conn, command.id())
+        .await
+        .unwrap();
+    assert_eq!(
+        serde_json::json!(instruction.timestamp()),
+        serde_json::to_value(&read_back).unwrap()["time_instruction"]
+    );
+}
@@ -0,0 +1,26 @@
+pub mod api;
+pub mod auth;
+pub mod battery;
+pub mod command;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod fleet;
+pub mod maintenance;
+pub mod metrics;
+pub mod notify;
+pub mod poll;
+pub mod push;
+pub mod retention;
+pub mod robot;
+pub mod scheduler;
+pub mod shift;
+#[cfg(feature = "subscriber")]
+pub mod telemetry;
+pub mod transition;
+pub mod user;
+
+#[cfg(test)]
+mod integration_test;
+#[cfg(test)]
+mod test_db;
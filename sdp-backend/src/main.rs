@@ -1,21 +1,23 @@
 use actix_cors::Cors;
 use actix_web::{middleware::Logger, App, HttpServer};
+use chrono::Duration;
+use sdp_backend::{
+    api,
+    command::{BatteryRequirements, CleaningPattern, Command, CommandConfig},
+    db, maintenance,
+    notify::LogSink,
+    retention::RetentionPolicy,
+    shift::ShiftConfig,
+};
 use sqlx::postgres::PgPool;
 use std::env;
 
-mod api;
-mod auth;
-mod command;
-mod error;
-mod integration_test;
-mod poll;
-mod robot;
-mod user;
-
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=debug");
     env_logger::init();
+    #[cfg(feature = "subscriber")]
+    sdp_backend::telemetry::init();
 
     // Default port for the webserver is 8080, this can be overwritten
     // by an envirnment variable.
@@ -28,16 +30,68 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("to get database pool");
 
+    db::verify_schema(&database_pool)
+        .await
+        .expect("the database schema to match");
+
+    // Commands not acknowledged within this many seconds are escalated
+    let unacknowledged_timeout = env::var("UNACKNOWLEDGED_TIMEOUT")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .map(Duration::seconds)
+        .unwrap_or_else(|| Duration::seconds(300));
+
+    // The battery needed to start each cleaning pattern can be raised or
+    // lowered with MIN_BATTERY_ZIGZAG, MIN_BATTERY_CIRCULAR and so on
+    let mut battery_requirements = BatteryRequirements::default();
+    for (var, pattern) in &[
+        ("MIN_BATTERY_ZIGZAG", CleaningPattern::ZigZag),
+        ("MIN_BATTERY_CIRCULAR", CleaningPattern::Circular),
+        ("MIN_BATTERY_SPIRAL", CleaningPattern::Spiral),
+        ("MIN_BATTERY_SPOT", CleaningPattern::Spot),
+        ("MIN_BATTERY_EDGE", CleaningPattern::Edge),
+    ] {
+        if let Some(minimum) = env::var(var).ok().and_then(|m| m.parse().ok()) {
+            battery_requirements.0.insert(pattern.clone(), minimum);
+        }
+    }
+
+    // How far off a command's times may be, set in seconds with
+    // TIME_ISSUED_BUFFER_SECS and TIME_INSTRUCTION_BUFFER_SECS
+    let command_config = CommandConfig::from_env();
+    command_config.install();
+
+    // Completed commands are POSTed to COMMAND_WEBHOOK_URL when neither the
+    // command nor its robot has a callback
+    if let Ok(webhook_url) = env::var("COMMAND_WEBHOOK_URL") {
+        Command::install_default_webhook(&webhook_url).expect("COMMAND_WEBHOOK_URL to be valid");
+    }
+
+    // Shift windows as `id=HH:MM-HH:MM,...` in UTC, commands aren't put in
+    // a shift unless these are set
+    let shifts = env::var("SHIFTS")
+        .map(|s| ShiftConfig::parse(&s).expect("SHIFTS to be valid"))
+        .unwrap_or_default();
+
+    // History is kept for the number of days set with the RETENTION_*_DAYS
+    // variables, see `RetentionPolicy::from_env`
+    actix_web::rt::spawn(maintenance::run(
+        database_pool.clone(),
+        unacknowledged_timeout,
+        RetentionPolicy::from_env(),
+        LogSink,
+    ));
+
     HttpServer::new(move || {
         App::new()
             .wrap(Cors::default().allow_any_origin())
             .wrap(Logger::default())
             .data(database_pool.clone())
+            .data(battery_requirements.clone())
+            .data(shifts.clone())
+            .data(command_config)
             .service(actix_files::Files::new("/static", "static/").show_files_listing())
-            .service(api::user::create_user)
-            .service(api::command::create_command)
-            .service(api::poll::robot_poll)
-            .service(api::auth::auth)
+            .configure(api::configure)
     })
     .bind(address)?
     .run()
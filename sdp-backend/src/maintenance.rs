@@ -0,0 +1,33 @@
+use crate::command::Command;
+use crate::notify::CompletionSink;
+use crate::retention::{self, RetentionPolicy};
+use actix_web::rt::time;
+use chrono::Duration;
+use sqlx::postgres::PgPool;
+use tracing::error;
+
+// How often the maintenance tasks are run, in seconds
+const MAINTENANCE_INTERVAL: u64 = 60;
+
+/// Periodically runs the housekeeping tasks against the database
+pub async fn run<S: CompletionSink>(
+    conn: PgPool,
+    unacknowledged_timeout: Duration,
+    retention: RetentionPolicy,
+    sink: S,
+) {
+    let mut interval = time::interval(std::time::Duration::from_secs(MAINTENANCE_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = Command::escalate_unacknowledged(&conn, unacknowledged_timeout, &sink).await
+        {
+            error!(error = ?e, "Maintenance Escalate");
+        }
+
+        if let Err(e) = retention::prune(&conn, retention).await {
+            error!(error = ?e, "Maintenance Prune");
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::ApiError;
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::error;
+
+// Number of recent polls the latency percentiles are worked out over
+const POLL_LATENCY_WINDOW: usize = 1000;
+
+static POLL_LATENCIES: Mutex<VecDeque<Duration>> = Mutex::new(VecDeque::new());
+
+static COMMAND_METRICS: OnceLock<CommandMetrics> = OnceLock::new();
+
+// The counters exposed for scraping, kept in their own registry so only
+// these end up on the endpoint
+struct CommandMetrics {
+    registry: Registry,
+    issued: IntCounterVec,
+    completed: IntCounter,
+    low_battery_aborts: IntCounter,
+    pending: IntGauge,
+}
+
+fn command_metrics() -> &'static CommandMetrics {
+    // The metrics are fixed so creating and registering them can't fail
+    COMMAND_METRICS.get_or_init(|| {
+        let registry = Registry::new();
+        let issued = IntCounterVec::new(
+            Opts::new("sdp_commands_issued_total", "Commands issued to robots"),
+            &["instruction"],
+        )
+        .expect("valid issued counter");
+        let completed = IntCounter::new(
+            "sdp_commands_completed_total",
+            "Commands marked as completed",
+        )
+        .expect("valid completed counter");
+        let low_battery_aborts = IntCounter::new(
+            "sdp_low_battery_aborts_total",
+            "Robots told to abort by a poll because of a low battery",
+        )
+        .expect("valid low battery counter");
+        let pending = IntGauge::new(
+            "sdp_pending_commands",
+            "Commands that haven't been delivered or completed",
+        )
+        .expect("valid pending gauge");
+
+        registry
+            .register(Box::new(issued.clone()))
+            .expect("issued counter registered");
+        registry
+            .register(Box::new(completed.clone()))
+            .expect("completed counter registered");
+        registry
+            .register(Box::new(low_battery_aborts.clone()))
+            .expect("low battery counter registered");
+        registry
+            .register(Box::new(pending.clone()))
+            .expect("pending gauge registered");
+
+        CommandMetrics {
+            registry,
+            issued,
+            completed,
+            low_battery_aborts,
+            pending,
+        }
+    })
+}
+
+/// Counts a command that has been stored, by the kind of instruction
+pub fn record_command_issued(kind: &str) {
+    command_metrics().issued.with_label_values(&[kind]).inc();
+}
+
+/// Counts a command that has just been marked as completed
+pub fn record_command_completed() {
+    command_metrics().completed.inc();
+}
+
+/// Counts a robot being told to abort because its battery is low
+pub fn record_low_battery_abort() {
+    command_metrics().low_battery_aborts.inc();
+}
+
+/// The command metrics in the Prometheus text format, along with how many
+/// commands are pending right now
+pub fn render(pending_commands: i64) -> Result<String, ApiError> {
+    let metrics = command_metrics();
+    metrics.pending.set(pending_commands);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .map_err(|e| {
+            error!(error = ?e, "Metrics Encode");
+            ApiError::SerializationError
+        })?;
+
+    String::from_utf8(buffer).map_err(|e| {
+        error!(error = ?e, "Metrics Encode");
+        ApiError::SerializationError
+    })
+}
+
+/// Percentiles of how long recent polls took to process
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub samples: usize,
+}
+
+/// Records how long a poll took, only the most recent polls are kept
+pub fn record_poll_latency(latency: Duration) {
+    let mut latencies = POLL_LATENCIES.lock().unwrap_or_else(|e| e.into_inner());
+
+    if latencies.len() == POLL_LATENCY_WINDOW {
+        latencies.pop_front();
+    }
+    latencies.push_back(latency);
+}
+
+/// The latency percentiles over the recent polls
+pub fn poll_latency_percentiles() -> LatencyStats {
+    let latencies = POLL_LATENCIES.lock().unwrap_or_else(|e| e.into_inner());
+
+    latency_stats(latencies.iter().copied().collect())
+}
+
+// Nearest rank percentiles, all zero if there aren't any samples
+fn latency_stats(mut latencies: Vec<Duration>) -> LatencyStats {
+    if latencies.is_empty() {
+        return LatencyStats::default();
+    }
+    latencies.sort();
+
+    let percentile = |p: usize| {
+        let rank = (p * latencies.len()).div_ceil(100);
+        latencies[rank.max(1) - 1]
+    };
+
+    LatencyStats {
+        p50: percentile(50),
+        p95: percentile(95),
+        p99: percentile(99),
+        samples: latencies.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{latency_stats, record_command_issued, render, LatencyStats};
+    use std::time::Duration;
+
+    #[test]
+    fn percentiles_of_samples() {
+        let latencies = (1..=100).rev().map(Duration::from_millis).collect();
+
+        assert_eq!(
+            LatencyStats {
+                p50: Duration::from_millis(50),
+                p95: Duration::from_millis(95),
+                p99: Duration::from_millis(99),
+                samples: 100,
+            },
+            latency_stats(latencies)
+        );
+    }
+
+    #[test]
+    fn single_sample() {
+        let stats = latency_stats(vec![Duration::from_millis(7)]);
+
+        assert_eq!(Duration::from_millis(7), stats.p50);
+        assert_eq!(Duration::from_millis(7), stats.p99);
+    }
+
+    #[test]
+    fn rendered_for_prometheus() {
+        record_command_issued("Dock");
+        let rendered = render(0).unwrap();
+
+        assert!(rendered.contains("# TYPE sdp_commands_issued_total counter"));
+        assert!(rendered.contains("sdp_commands_issued_total{instruction=\"Dock\"}"));
+        assert!(rendered.contains("# TYPE sdp_pending_commands gauge"));
+    }
+
+    #[test]
+    fn no_samples() {
+        assert_eq!(LatencyStats::default(), latency_stats(Vec::new()));
+    }
+}
@@ -0,0 +1,23 @@
+use crate::command::Command;
+use tracing::info;
+
+/// Events sent to operators about commands
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// The robot never acknowledged the command within the timeout
+    Unacknowledged(Command),
+}
+
+/// Somewhere notifications about commands are delivered
+pub trait CompletionSink {
+    fn notify(&self, notification: Notification);
+}
+
+/// Default sink, writes the notifications to the log
+pub struct LogSink;
+
+impl CompletionSink for LogSink {
+    fn notify(&self, notification: Notification) {
+        info!(?notification, "Notification");
+    }
+}
@@ -1,37 +1,326 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
 
 use crate::command::{
-    AbortReason, Command, Instruction,
-    Instruction::{Abort, Idle, Task},
+    AbortReason, CleaningPattern, Command, CommandConfig, Instruction,
+    Instruction::{Abort, Continue, Dock, Idle, Pause, RefreshConfig, ReturnToDock, Task, Teleop},
+    TaskStatus,
 };
+use crate::config;
 use crate::error::ApiError;
+use crate::metrics;
+use crate::robot::{Robot, RobotSerial};
 
+// Used for robots that haven't been registered with their own minimum
 const MINIMUM_BATTERY_LEVEL: i64 = 50;
+// How far a task's parameters can drift before it is treated as a new task
+const TASK_PARAM_TOLERANCE: f64 = 0.05;
+// How often robots are asked to poll, in seconds
+pub(crate) const POLL_INTERVAL_SECS: u64 = 5;
+// Robots with nothing to do can check in less often
+const IDLE_POLL_INTERVAL_SECS: u64 = 30;
+// Paused robots may be continued at any moment but aren't moving
+const PAUSED_POLL_INTERVAL_SECS: u64 = 10;
+// Robots stopping or heading back to charge are checked on closely
+const URGENT_POLL_INTERVAL_SECS: u64 = 2;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Poll {
-    pub robot_serial_number: String,
+    pub robot_serial_number: RobotSerial,
     pub instruction: Instruction,
     pub battery_level: i64,
+    pub report: Option<TaskReport>,
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    // Which way the robot is facing, in degrees
+    pub heading: Option<f64>,
+    pub acknowledged: Option<i64>,
+    pub config_version: Option<u64>,
+    // How far through its current task the robot is, from 0 to 1
+    pub progress: Option<f64>,
+    pub firmware_version: Option<String>,
+}
+
+/// The command sent back to the robot, with the settings it should be
+/// using if the ones it has are out of date and how long it should wait
+/// before polling again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PollResponse {
+    #[serde(flatten)]
+    pub command: Command,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<EffectiveConfig>,
+    pub next_poll_secs: u64,
+}
+
+/// The settings a robot should be running with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EffectiveConfig {
+    pub config_version: u64,
+    pub battery_threshold: i64,
+    pub poll_interval_secs: u64,
+    pub supported_patterns: Vec<CleaningPattern>,
+}
+
+/// The robot reporting how a command it was given went
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskReport {
+    pub command_id: i64,
+    pub status: TaskStatus,
+    pub detail: Option<String>,
 }
 
 impl Poll {
     pub async fn poll(conn: &PgPool, next_command: &Self) -> Result<Command, ApiError> {
-        // Check the battery of the robot
-        if !next_command.check_battery().await {
-            return Ok(Command::abort(
+        Poll::poll_using(conn, next_command, CommandConfig::global()).await
+    }
+
+    /// Polls with the given config rather than the installed one
+    pub async fn poll_using(
+        conn: &PgPool,
+        next_command: &Self,
+        config: &CommandConfig,
+    ) -> Result<Command, ApiError> {
+        let start = Instant::now();
+        Robot::record_seen(
+            conn,
+            &next_command.robot_serial_number,
+            next_command.firmware_version.as_deref(),
+        )
+        .await?;
+        let command = Poll::respond(conn, next_command, config).await;
+        metrics::record_poll_latency(start.elapsed());
+
+        command
+    }
+
+    /// Polls as usual, also sending the robot its settings when the
+    /// version it reported is out of date and when to poll next
+    pub async fn poll_with_config(
+        conn: &PgPool,
+        next_command: &Self,
+    ) -> Result<PollResponse, ApiError> {
+        let command = Poll::poll(conn, next_command).await?;
+
+        let latest_version = config::version(conn).await?;
+        let config = match next_command.config_version {
+            Some(robot_version) if robot_version < latest_version => Some(EffectiveConfig {
+                config_version: latest_version,
+                battery_threshold: Poll::battery_threshold(conn, &next_command.robot_serial_number)
+                    .await?,
+                poll_interval_secs: POLL_INTERVAL_SECS,
+                supported_patterns: vec![
+                    CleaningPattern::ZigZag,
+                    CleaningPattern::Circular,
+                    CleaningPattern::Spiral,
+                    CleaningPattern::Spot,
+                    CleaningPattern::Edge,
+                ],
+            }),
+            _ => None,
+        };
+
+        let next_poll_secs = next_poll_secs(&command.instruction);
+
+        Ok(PollResponse {
+            command,
+            config,
+            next_poll_secs,
+        })
+    }
+
+    // Handles everything the robot reported and works out its next command
+    async fn respond(
+        conn: &PgPool,
+        next_command: &Self,
+        config: &CommandConfig,
+    ) -> Result<Command, ApiError> {
+        // Nothing is recorded from a poll with an impossible battery reading
+        let threshold = Poll::battery_threshold(conn, &next_command.robot_serial_number).await?;
+        let low_battery = !next_command.check_battery(threshold)?;
+
+        // Record the outcome of the command the robot is reporting on, a
+        // report on a command that isn't the robot's is logged and the robot
+        // still gets its next command
+        if let Some(report) = &next_command.report {
+            if let Err(e) = Command::complete_with_status(
+                conn,
+                &next_command.robot_serial_number,
+                report.command_id,
+                &report.status,
+                report.detail.as_deref(),
+            )
+            .await
+            {
+                warn!(
+                    robot_serial_number = %next_command.robot_serial_number,
+                    command_id = report.command_id,
+                    error = ?e,
+                    "Poll Report"
+                );
+            }
+        }
+
+        // Keep track of where the robot has been, this shouldn't stop
+        // the robot getting its next command
+        if let (Some(x), Some(y)) = (next_command.x, next_command.y) {
+            Robot::record_position(
+                conn,
+                &next_command.robot_serial_number,
+                x,
+                y,
+                next_command.heading,
+            )
+            .await
+            .ok();
+        }
+
+        // Keep track of how far the robot has got with its task, so it can
+        // carry on from there if the task is preempted
+        if let Some(progress) = next_command.progress {
+            Command::record_progress(conn, &next_command.robot_serial_number, progress)
+                .await
+                .ok();
+        }
+
+        // The robot confirming it has received a command, an id that is
+        // stale or isn't the robot's is logged rather than failing the poll
+        if let Some(command_id) = next_command.acknowledged {
+            if let Err(e) = Command::ack(conn, &next_command.robot_serial_number, command_id).await
+            {
+                warn!(
+                    robot_serial_number = %next_command.robot_serial_number,
+                    command_id,
+                    error = ?e,
+                    "Poll Ack"
+                );
+            }
+        }
+
+        // Keep track of the robot's battery
+        let low_readings = Robot::record_battery_reading(
+            conn,
+            &next_command.robot_serial_number,
+            next_command.battery_level,
+            low_battery,
+        )
+        .await?;
+
+        // A robot on its way back to charge is left to get there
+        if low_battery
+            && low_readings >= config.low_battery_readings
+            && !next_command.returning_to_dock(conn).await
+        {
+            let interrupted = Command::current(conn, &next_command.robot_serial_number)
+                .await
+                .ok();
+
+            // Still charging after being told to abort, so it is told again
+            // rather than being sent another abort
+            if let Some(current) = interrupted
+                .as_ref()
+                .filter(|c| c.instruction == Abort(AbortReason::LowBattery) && !c.completed)
+            {
+                Poll::hand_over(conn, current).await?;
+                return Ok(current.clone());
+            }
+
+            let abort = Command::abort(
                 conn,
                 &next_command.robot_serial_number,
                 &AbortReason::LowBattery,
             )
-            .await?);
+            .await?;
+
+            // The task is picked back up once the robot has charged, it is
+            // cancelled rather than completed so nothing is told it finished
+            if let Some(task) = interrupted.filter(|c| matches!(c.instruction, Task { .. })) {
+                if !task.completed {
+                    Command::cancel_by_id(conn, task.id(), "low_battery".to_string())
+                        .await
+                        .ok();
+                    Command::stash_interrupted(conn, task.id(), abort.id()).await?;
+                }
+            }
+
+            Poll::hand_over(conn, &abort).await?;
+            metrics::record_low_battery_abort();
+
+            return Ok(abort);
+        }
+
+        // Robots on old settings are told to refresh before anything else
+        if let Some(robot_version) = next_command.config_version {
+            let latest_version = config::version(conn).await?;
+            if robot_version < latest_version {
+                let refresh = Command::refresh_config(
+                    conn,
+                    &next_command.robot_serial_number,
+                    latest_version,
+                )
+                .await?;
+                Poll::hand_over(conn, &refresh).await?;
+
+                return Ok(refresh);
+            }
+        }
+
+        let command = Poll::next_instruction(conn, next_command, low_battery).await?;
+        let command =
+            Poll::hold_until_acknowledged(conn, &next_command.robot_serial_number, command).await?;
+        Poll::hand_over(conn, &command).await?;
+
+        Ok(command)
+    }
+
+    // Records the command as handed to the robot. Robots that don't have to
+    // acknowledge commands are taken to have received it as soon as it's
+    // handed over, the others acknowledge it themselves.
+    async fn hand_over(conn: &PgPool, command: &Command) -> Result<(), ApiError> {
+        command.deliver(conn).await?;
+
+        if !Robot::requires_ack(conn, &command.robot_serial_number).await? {
+            command.acknowledge(conn).await?;
         }
 
-        // Get the previous command the robot was doing
-        let prev_command = Command::current(conn, &next_command.robot_serial_number).await?;
+        Ok(())
+    }
 
-        println!("Latest Command: {:?}", prev_command);
+    // Works out what the robot should do next from what it was doing
+    async fn next_instruction(
+        conn: &PgPool,
+        next_command: &Self,
+        low_battery: bool,
+    ) -> Result<Command, ApiError> {
+        // A higher priority command takes over from the task the robot is
+        // running
+        if let Task { .. } = next_command.instruction {
+            if let Some(preempting) =
+                Command::preempt(conn, &next_command.robot_serial_number).await?
+            {
+                return Ok(preempting);
+            }
+        }
+
+        // Get the previous command the robot was doing, a robot polling for
+        // the first time starts off idle
+        let prev_command = match Command::current(conn, &next_command.robot_serial_number).await {
+            Err(ApiError::NoCommandsForRobot) => {
+                Command::idle(conn, &next_command.robot_serial_number).await?
+            }
+            prev_command => prev_command?,
+        };
+
+        debug!(?prev_command, "Latest Command");
+        info!(
+            robot_serial_number = %next_command.robot_serial_number,
+            prev = ?prev_command.instruction,
+            next = ?next_command.instruction,
+            "Poll decision"
+        );
 
         // Determine the response based on the robots state
         match (&prev_command.instruction, &next_command.instruction) {
@@ -39,33 +328,367 @@ impl Poll {
             // and the robot will abort
             (_, Abort(reason)) => {
                 prev_command.complete(conn).await.ok();
-                Command::abort(conn, &next_command.robot_serial_number, reason).await
+                let abort = Command::abort(conn, &next_command.robot_serial_number, reason).await?;
+
+                // A task stopped by an obstacle is carried on once it has
+                // cleared, and one stopped by a low battery once the robot
+                // has charged, other aborts need an operator to restart it
+                if let (Task { .. }, AbortReason::Obstacle | AbortReason::LowBattery) =
+                    (&prev_command.instruction, reason)
+                {
+                    if !prev_command.completed {
+                        Command::stash_interrupted(conn, prev_command.id(), abort.id()).await?;
+                    }
+                }
+
+                Ok(abort)
             }
 
             // If the old task is the same as the new one, keep doing it.
-            (Task(prev), Task(new)) if prev == new => Ok(prev_command),
+            (prev @ Task { .. }, new @ Task { .. })
+                if prev.equivalent(new, TASK_PARAM_TOLERANCE) =>
+            {
+                Ok(prev_command)
+            }
+
+            // A task the robot hasn't been given yet, whatever it is running
+            // now it is handed over with everything else queued
+            (Task { .. }, Task { .. }) if prev_command.delivered_at.is_none() => {
+                Command::pending(conn, &next_command.robot_serial_number).await
+            }
+
+            // The robot has moved on to another task, or the same one run
+            // differently or somewhere else, so the old task is done with and
+            // the one it is running is issued in its place
+            (Task { .. }, new @ Task { .. }) => {
+                prev_command.complete(conn).await.ok();
+                let now = chrono::Utc::now();
+                Command::new(
+                    conn,
+                    &RobotSerial::parse(&next_command.robot_serial_number)?,
+                    now,
+                    now,
+                    new,
+                    CommandConfig::global(),
+                    None,
+                    None,
+                )
+                .await
+            }
+
+            // While the teleop session is going the robot stays under remote
+            // control and pending tasks are ignored, once it has ended the
+            // robot is given its next command
+            (_, Teleop { session_id }) => {
+                match Command::teleop_session(conn, &next_command.robot_serial_number, session_id)
+                    .await?
+                {
+                    Some(session) => Ok(session),
+                    None => Command::pending(conn, &next_command.robot_serial_number).await,
+                }
+            }
+
+            // The robot has paused part way through its task, which is kept
+            // so it can be picked back up
+            (Task { .. }, Pause) => {
+                Robot::set_paused(
+                    conn,
+                    &next_command.robot_serial_number,
+                    &prev_command.instruction,
+                )
+                .await?;
+                prev_command.complete(conn).await.ok();
+                Command::pause(conn, &next_command.robot_serial_number).await
+            }
+
+            // Still paused
+            (Pause, Pause) => Ok(prev_command),
+
+            // Carry on with the task that was paused, if there was one
+            (Pause, Continue) => {
+                prev_command.complete(conn).await.ok();
+                match Robot::take_paused(conn, &next_command.robot_serial_number).await? {
+                    Some(paused) => {
+                        let now = chrono::Utc::now();
+                        Command::new(
+                            conn,
+                            &RobotSerial::parse(&next_command.robot_serial_number)?,
+                            now,
+                            now,
+                            &paused,
+                            CommandConfig::global(),
+                            None,
+                            None,
+                        )
+                        .await
+                    }
+                    None => Command::pending(conn, &next_command.robot_serial_number).await,
+                }
+            }
+
+            // Aborted for a low battery and still charging
+            (Abort(AbortReason::LowBattery), Idle) if low_battery => Ok(prev_command),
+
+            // The robot has stopped after being told to abort, which is the
+            // abort carried out
+            (Abort(_), Idle) if prev_command.delivered_at.is_some() => {
+                prev_command.complete(conn).await.ok();
+
+                // Back to the task the abort got in the way of
+                if let Some(resumed) = Command::resume_preempted(conn, prev_command.id()).await? {
+                    return Ok(resumed);
+                }
+
+                Command::pending(conn, &next_command.robot_serial_number).await
+            }
+
+            // The robot has finished its run and is heading back to charge
+            (Task { .. }, ReturnToDock) => {
+                prev_command.complete(conn).await.ok();
+                Command::return_to_dock(conn, &next_command.robot_serial_number).await
+            }
+
+            // Still on its way back to the dock
+            (ReturnToDock, ReturnToDock) => Ok(prev_command),
+
+            // Sent back to the dock but the robot hasn't picked it up yet
+            (ReturnToDock, Idle) if prev_command.delivered_at.is_none() => Ok(prev_command),
+
+            // The robot has docked, so it can be given its next command
+            (ReturnToDock, Idle) => {
+                prev_command.complete(conn).await.ok();
+                Command::pending(conn, &next_command.robot_serial_number).await
+            }
+
+            // A teleop session the robot hasn't picked up yet
+            (Teleop { .. }, Idle) if prev_command.delivered_at.is_none() => Ok(prev_command),
+
+            // A task the robot hasn't been given yet, rather than one it has
+            // finished, so it is handed over with everything else queued
+            (Task { .. }, Idle)
+                if prev_command.delivered_at.is_none() && !prev_command.completed =>
+            {
+                Command::pending(conn, &next_command.robot_serial_number).await
+            }
 
             // The previous task completed, mark it as complete and look for other tasks
-            (Task(_), Idle) => {
+            (Task { .. }, Idle) | (Teleop { .. }, Idle) => {
                 prev_command.complete(conn).await.ok();
-                Command::pending(conn, &prev_command.robot_serial_number).await
+
+                // Go back to the task this one took over from
+                if let Some(resumed) = Command::resume_preempted(conn, prev_command.id()).await? {
+                    return Ok(resumed);
+                }
+
+                let pending = Command::pending(conn, &next_command.robot_serial_number).await?;
+
+                // Nothing else to do, the robot has just worked through its queue
+                let drained = matches!(
+                    (&prev_command.instruction, &pending.instruction),
+                    (Task { .. }, Idle) | (Task { .. }, Dock)
+                );
+                if drained && !prev_command.completed {
+                    Robot::record_drained(conn, &prev_command.robot_serial_number)
+                        .await
+                        .ok();
+                }
+
+                Ok(pending)
             }
 
             // If we are now idle, check for pending commands, otherwise stay idle
-            (_, Idle) => Command::pending(conn, &prev_command.robot_serial_number).await,
+            (_, Idle) | (_, Dock) | (_, RefreshConfig { .. }) => {
+                Command::pending(conn, &next_command.robot_serial_number).await
+            }
 
             // Any other instructions order is not supported
             _unsupported_instruction => Err(ApiError::CmdInstructionNotSupported),
         }
     }
 
-    /// Checks the current battery level of the Robot
+    /// Robots that require acknowledgement keep being sent the last
+    /// command they were given until they acknowledge it, the new
+    /// command stays queued until then. Aborts are never held back.
+    async fn hold_until_acknowledged(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        command: Command,
+    ) -> Result<Command, ApiError> {
+        if let Abort(_) = command.instruction {
+            return Ok(command);
+        }
+
+        if !Robot::requires_ack(conn, robot_serial_number).await? {
+            return Ok(command);
+        }
+
+        match Command::last_delivered(conn, robot_serial_number).await? {
+            Some(last)
+                if last.acknowledged_at.is_none()
+                    && !last.completed
+                    && last.id() != command.id() =>
+            {
+                info!(robot_serial_number = %robot_serial_number, command_id = command.id(), withheld_for = last.id(), "Withholding Command");
+                Ok(last)
+            }
+            _ => Ok(command),
+        }
+    }
+
+    // Whether the robot is heading back to its dock, either because it has
+    // said so or it has been told to
+    async fn returning_to_dock(&self, conn: &PgPool) -> bool {
+        if self.instruction == ReturnToDock {
+            return true;
+        }
+
+        Command::current(conn, &self.robot_serial_number)
+            .await
+            .map(|c| c.instruction == ReturnToDock && !c.completed)
+            .unwrap_or(false)
+    }
+
+    /// Checks the current battery level of the Robot is above the threshold
     ///
     /// If the battery level is not sufficent the robot will
-    /// be told to abort due to low battery.
-    async fn check_battery(&self) -> bool {
-        self.battery_level >= 0
-            && self.battery_level > MINIMUM_BATTERY_LEVEL
-            && self.battery_level <= 100
+    /// be told to abort due to low battery. A reading outside 0 to 100
+    /// can't be right, so it is refused rather than taken as low.
+    fn check_battery(&self, threshold: i64) -> Result<bool, ApiError> {
+        if !(0..=100).contains(&self.battery_level) {
+            return Err(ApiError::InvalidBatteryLevel);
+        }
+
+        Ok(self.battery_level > threshold)
+    }
+
+    /// Where the robot last reported it was, as `(x, y, heading)`, the
+    /// heading is 0 if the robot didn't give one with that position
+    pub async fn last_position(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<(f64, f64, f64)>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT P.x, P.y, P.heading FROM path_points P
+WHERE P.robot_serial_number = $1
+ORDER BY P.recorded_at DESC, P.path_point_id DESC
+LIMIT 1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|point| point.map(|p| (p.x, p.y, p.heading.unwrap_or(0.0))))
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Poll Last Position");
+            ApiError::from(e)
+        })
+    }
+
+    /// The battery levels the robot has reported since the given time,
+    /// oldest first
+    pub async fn battery_history(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, i64)>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT B.recorded_at, B.battery_level FROM battery_readings B
+WHERE B.robot_serial_number = $1 AND
+      B.recorded_at >= $2
+ORDER BY B.recorded_at, B.battery_reading_id
+        "#,
+            robot_serial_number,
+            since
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|r| (r.recorded_at, r.battery_level))
+                .collect()
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Poll Battery History");
+            ApiError::from(e)
+        })
+    }
+
+    // The lowest battery level the robot can carry on at, its model's own
+    // level if it has been registered with one
+    async fn battery_threshold(conn: &PgPool, robot_serial_number: &str) -> Result<i64, ApiError> {
+        Ok(Robot::get(conn, robot_serial_number)
+            .await?
+            .and_then(|r| r.min_battery_level)
+            .unwrap_or(MINIMUM_BATTERY_LEVEL))
+    }
+}
+
+// How long the robot can wait before polling again given what it has been
+// told to do
+fn next_poll_secs(instruction: &Instruction) -> u64 {
+    match instruction {
+        Idle | Dock => IDLE_POLL_INTERVAL_SECS,
+        Pause => PAUSED_POLL_INTERVAL_SECS,
+        Abort(_) | ReturnToDock => URGENT_POLL_INTERVAL_SECS,
+        _ => POLL_INTERVAL_SECS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_poll_secs, Poll, MINIMUM_BATTERY_LEVEL};
+    use crate::command::{AbortReason, CleaningPattern, Instruction};
+    use crate::error::ApiError;
+    use crate::robot::RobotSerial;
+
+    fn reading(battery_level: i64) -> Poll {
+        Poll {
+            robot_serial_number: RobotSerial::parse("battery").unwrap(),
+            instruction: Instruction::Idle,
+            battery_level,
+            report: None,
+            x: None,
+            y: None,
+            heading: None,
+            acknowledged: None,
+            config_version: None,
+            progress: None,
+            firmware_version: None,
+        }
+    }
+
+    #[test]
+    fn battery_levels() {
+        assert!(!reading(40).check_battery(MINIMUM_BATTERY_LEVEL).unwrap());
+        assert!(reading(80).check_battery(MINIMUM_BATTERY_LEVEL).unwrap());
+        assert!(reading(100).check_battery(MINIMUM_BATTERY_LEVEL).unwrap());
+        assert!(!reading(0).check_battery(MINIMUM_BATTERY_LEVEL).unwrap());
+
+        for impossible in &[150, 101, -5] {
+            assert!(matches!(
+                reading(*impossible).check_battery(MINIMUM_BATTERY_LEVEL),
+                Err(ApiError::InvalidBatteryLevel)
+            ));
+        }
+    }
+
+    #[test]
+    fn poll_less_often_when_idle() {
+        let idle = next_poll_secs(&Instruction::Idle);
+        let task = next_poll_secs(&Instruction::task(CleaningPattern::ZigZag));
+
+        assert!(idle > task);
+        assert_eq!(idle, next_poll_secs(&Instruction::Dock));
+    }
+
+    #[test]
+    fn poll_more_often_when_stopping() {
+        let task = next_poll_secs(&Instruction::task(CleaningPattern::ZigZag));
+        let low_battery = next_poll_secs(&Instruction::Abort(AbortReason::LowBattery));
+
+        assert!(low_battery < task);
+        assert_eq!(low_battery, next_poll_secs(&Instruction::ReturnToDock));
     }
 }
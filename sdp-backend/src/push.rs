@@ -0,0 +1,53 @@
+use futures::stream::{self, Stream};
+use std::sync::OnceLock;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// How many new commands can queue up for a slow listener before it starts
+// missing them
+const PUSH_CHANNEL_CAPACITY: usize = 256;
+
+static NEW_COMMANDS: OnceLock<broadcast::Sender<NewCommand>> = OnceLock::new();
+
+/// A command that has just been issued to a robot
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewCommand {
+    pub robot_serial_number: String,
+    pub command_id: i64,
+}
+
+fn sender() -> &'static broadcast::Sender<NewCommand> {
+    NEW_COMMANDS.get_or_init(|| broadcast::channel(PUSH_CHANNEL_CAPACITY).0)
+}
+
+/// Lets anything listening know a command was issued to the robot
+pub(crate) fn publish(robot_serial_number: &str, command_id: i64) {
+    // Nobody listening isn't an error, the robot will get it when it polls
+    let _ = sender().send(NewCommand {
+        robot_serial_number: robot_serial_number.to_string(),
+        command_id,
+    });
+}
+
+/// The commands issued to the robot from now on
+///
+/// A listener that falls too far behind skips the commands it missed
+/// rather than stopping, the robot is given whatever is current when it
+/// next hears about one.
+pub fn subscribe(robot_serial_number: &str) -> impl Stream<Item = NewCommand> {
+    let robot_serial_number = robot_serial_number.to_string();
+
+    stream::unfold(sender().subscribe(), move |mut receiver| {
+        let robot_serial_number = robot_serial_number.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(command) if command.robot_serial_number == robot_serial_number => {
+                        return Some((command, receiver))
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,156 @@
+use crate::error::ApiError;
+use chrono::{Duration, Utc};
+use sqlx::{postgres::PgPool, Done};
+use std::env;
+use tracing::error;
+
+/// How long rows are kept in each of the history tables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    pub path_points: Duration,
+    pub battery_readings: Duration,
+    pub transitions: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            path_points: Duration::days(30),
+            battery_readings: Duration::days(30),
+            transitions: Duration::days(90),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Reads how many days each table is kept from
+    /// `RETENTION_PATH_POINTS_DAYS`, `RETENTION_BATTERY_READINGS_DAYS` and
+    /// `RETENTION_TRANSITIONS_DAYS`, any that aren't set keep the default
+    pub fn from_env() -> Self {
+        RetentionPolicy::from_vars(|var| env::var(var).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let days = |name: &str, default: Duration| {
+            var(name)
+                .and_then(|s| s.parse().ok())
+                .map(Duration::days)
+                .unwrap_or(default)
+        };
+        let default = RetentionPolicy::default();
+
+        RetentionPolicy {
+            path_points: days("RETENTION_PATH_POINTS_DAYS", default.path_points),
+            battery_readings: days("RETENTION_BATTERY_READINGS_DAYS", default.battery_readings),
+            transitions: days("RETENTION_TRANSITIONS_DAYS", default.transitions),
+        }
+    }
+}
+
+/// The number of rows removed from each table
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PruneReport {
+    pub path_points: u64,
+    pub battery_readings: u64,
+    pub transitions: u64,
+}
+
+/// Deletes history older than the policy allows, the most recent row for
+/// each robot is always kept.
+pub async fn prune(conn: &PgPool, policy: RetentionPolicy) -> Result<PruneReport, ApiError> {
+    let path_points = sqlx::query!(
+        r#"
+DELETE FROM path_points P
+WHERE P.recorded_at < $1 AND
+      P.path_point_id <> (
+          SELECT L.path_point_id FROM path_points L
+          WHERE L.robot_serial_number = P.robot_serial_number
+          ORDER BY L.recorded_at DESC, L.path_point_id DESC
+          LIMIT 1
+      )
+        "#,
+        Utc::now() - policy.path_points
+    )
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Retention Prune Path Points");
+        ApiError::from(e)
+    })?
+    .rows_affected();
+
+    let battery_readings = sqlx::query!(
+        r#"
+DELETE FROM battery_readings B
+WHERE B.recorded_at < $1 AND
+      B.battery_reading_id <> (
+          SELECT L.battery_reading_id FROM battery_readings L
+          WHERE L.robot_serial_number = B.robot_serial_number
+          ORDER BY L.recorded_at DESC, L.battery_reading_id DESC
+          LIMIT 1
+      )
+        "#,
+        Utc::now() - policy.battery_readings
+    )
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Retention Prune Battery Readings");
+        ApiError::from(e)
+    })?
+    .rows_affected();
+
+    let transitions = sqlx::query!(
+        r#"
+DELETE FROM transitions T
+WHERE T.transitioned_at < $1 AND
+      T.transition_id <> (
+          SELECT L.transition_id FROM transitions L
+          WHERE L.robot_serial_number = T.robot_serial_number
+          ORDER BY L.transitioned_at DESC, L.transition_id DESC
+          LIMIT 1
+      )
+        "#,
+        Utc::now() - policy.transitions
+    )
+    .execute(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Retention Prune Transitions");
+        ApiError::from(e)
+    })?
+    .rows_affected();
+
+    Ok(PruneReport {
+        path_points,
+        battery_readings,
+        transitions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetentionPolicy;
+    use chrono::Duration;
+
+    #[test]
+    fn windows_from_vars() {
+        let policy = RetentionPolicy::from_vars(|var| match var {
+            "RETENTION_PATH_POINTS_DAYS" => Some("7".to_string()),
+            "RETENTION_TRANSITIONS_DAYS" => Some("forever".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(Duration::days(7), policy.path_points);
+        // Unset and unreadable windows keep the default
+        assert_eq!(
+            RetentionPolicy::default().battery_readings,
+            policy.battery_readings
+        );
+        assert_eq!(RetentionPolicy::default().transitions, policy.transitions);
+        assert_eq!(
+            RetentionPolicy::default(),
+            RetentionPolicy::from_vars(|_| None)
+        );
+    }
+}
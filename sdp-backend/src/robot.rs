@@ -1,17 +1,179 @@
-use crate::command::Command;
+use crate::command::{check_callback_url, CleaningPattern, Command, Instruction};
 use crate::error::ApiError;
-use sqlx::postgres::PgPool;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPool, Done};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+use std::ops::Deref;
+use tracing::{error, warn};
 
-pub struct Robot;
+// Most points returned for a robot's path
+const MAX_PATH_POINTS: usize = 500;
+// Event recorded when a robot finishes everything it was queued
+const QUEUE_DRAINED: &str = "queue_drained";
+// Longest serial number a robot can be registered with
+const MAX_SERIAL_LEN: usize = 64;
+
+/// A robot in the registry, along with the model that it is and the
+/// lowest battery level it can safely carry on at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Robot {
+    pub robot_serial_number: String,
+    pub model: Option<String>,
+    pub min_battery_level: Option<i64>,
+}
+
+/// A robot's serial number, which is 1 to 64 ASCII letters, digits, `-`
+/// and `_`.
+///
+/// Robots that belong to an organization are stored under their serial
+/// qualified with the organization, as `<organization>:<serial>`, so robots
+/// in different organizations can use the same serial.
+///
+/// Serial numbers coming from outside are parsed into one of these before
+/// anything is stored against them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct RobotSerial(String);
+
+impl RobotSerial {
+    pub fn parse(robot_serial_number: &str) -> Result<Self, ApiError> {
+        let valid = match robot_serial_number.split_once(':') {
+            Some((organization, serial)) => valid_id(organization) && valid_id(serial),
+            None => valid_id(robot_serial_number),
+        };
+
+        if valid {
+            Ok(Self(robot_serial_number.to_string()))
+        } else {
+            Err(ApiError::InvalidSerial)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The serial without the organization the robot belongs to
+    pub fn unqualified(&self) -> &str {
+        self.0.split_once(':').map_or(&self.0, |(_, serial)| serial)
+    }
+
+    /// The organization the robot belongs to, if it is in one
+    pub fn organization(&self) -> Option<OrganizationId> {
+        self.0
+            .split_once(':')
+            .map(|(organization, _)| OrganizationId(organization.to_string()))
+    }
+
+    /// The serial the robot is stored under in the organization
+    pub fn in_organization(&self, organization: &OrganizationId) -> Self {
+        Self(format!("{}:{}", organization, self.unqualified()))
+    }
+}
+
+impl Deref for RobotSerial {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RobotSerial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for RobotSerial {
+    type Error = ApiError;
+
+    fn try_from(robot_serial_number: String) -> Result<Self, ApiError> {
+        RobotSerial::parse(&robot_serial_number)
+    }
+}
+
+/// The organization a robot belongs to, made up of the same characters as
+/// a serial number
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct OrganizationId(String);
+
+impl OrganizationId {
+    pub fn parse(organization_id: &str) -> Result<Self, ApiError> {
+        if valid_id(organization_id) {
+            Ok(Self(organization_id.to_string()))
+        } else {
+            Err(ApiError::InvalidOrganization)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for OrganizationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for OrganizationId {
+    type Error = ApiError;
+
+    fn try_from(organization_id: String) -> Result<Self, ApiError> {
+        OrganizationId::parse(&organization_id)
+    }
+}
+
+impl From<OrganizationId> for String {
+    fn from(organization_id: OrganizationId) -> Self {
+        organization_id.0
+    }
+}
+
+impl From<RobotSerial> for String {
+    fn from(robot_serial_number: RobotSerial) -> Self {
+        robot_serial_number.0
+    }
+}
+
+impl PartialEq<str> for RobotSerial {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<String> for RobotSerial {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+/// A robot to register, as read from an import
+#[derive(Debug, Clone, PartialEq)]
+pub struct RobotRecord {
+    pub robot_serial_number: RobotSerial,
+    pub timezone: Option<Tz>,
+    pub capabilities: Option<Vec<CleaningPattern>>,
+}
 
 impl Robot {
-    pub async fn new(conn: &PgPool, robot_serial_number: &str) -> Result<(), ApiError> {
+    pub async fn new(conn: &PgPool, robot_serial_number: &RobotSerial) -> Result<Self, ApiError> {
         sqlx::query!(
             r#"
 INSERT INTO robot (robot_serial_number)
 VALUES ($1)
         "#,
-            &robot_serial_number
+            robot_serial_number.as_str()
         )
         .execute(conn)
         .await
@@ -20,6 +182,1145 @@ VALUES ($1)
         // By default a new robot will be in idle.
         Command::idle(conn, robot_serial_number).await?;
 
+        Ok(Self {
+            robot_serial_number: robot_serial_number.to_string(),
+            model: None,
+            min_battery_level: None,
+        })
+    }
+
+    /// Registers the robot as the given model with its own minimum battery
+    /// level, updating it if it is already registered
+    pub async fn register(
+        conn: &PgPool,
+        robot_serial_number: &RobotSerial,
+        model: &str,
+        min_battery_level: i64,
+    ) -> Result<Self, ApiError> {
+        let inserted = sqlx::query!(
+            r#"
+INSERT INTO robot (robot_serial_number, model, min_battery_level)
+VALUES ($1, $2, $3)
+ON CONFLICT (robot_serial_number) DO UPDATE
+SET model = EXCLUDED.model,
+    min_battery_level = EXCLUDED.min_battery_level
+RETURNING (xmax = 0) AS "inserted!"
+        "#,
+            robot_serial_number.as_str(),
+            model,
+            min_battery_level
+        )
+        .fetch_one(conn)
+        .await
+        .map(|r| r.inserted)
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Register");
+            ApiError::from(e)
+        })?;
+
+        // By default a new robot will be in idle.
+        if inserted {
+            Command::idle(conn, robot_serial_number).await?;
+        }
+
+        Ok(Self {
+            robot_serial_number: robot_serial_number.to_string(),
+            model: Some(model.to_string()),
+            min_battery_level: Some(min_battery_level),
+        })
+    }
+
+    /// The robot with the given serial number, if it is in the registry
+    pub async fn get(conn: &PgPool, robot_serial_number: &str) -> Result<Option<Self>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT robot_serial_number, model, min_battery_level FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            robot.map(|r| Self {
+                robot_serial_number: r.robot_serial_number,
+                model: r.model,
+                min_battery_level: r.min_battery_level,
+            })
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Get");
+            ApiError::from(e)
+        })
+    }
+
+    /// Sets whether the robot has to acknowledge a command before it is
+    /// sent a new one, a robot that isn't registered is not found
+    pub async fn set_require_ack(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        require_ack: bool,
+    ) -> Result<(), ApiError> {
+        let updated = sqlx::query!(
+            r#"
+UPDATE robot
+SET require_ack = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            require_ack
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Require Ack");
+            ApiError::from(e)
+        })?;
+
+        if updated.rows_affected() == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the robot has to acknowledge commands, robots that are not
+    /// registered don't.
+    pub async fn requires_ack(conn: &PgPool, robot_serial_number: &str) -> Result<bool, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT require_ack FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| robot.is_some_and(|r| r.require_ack))
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Requires Ack");
+            ApiError::from(e)
+        })
+    }
+
+    /// Sets what the robot is given when it has no commands waiting, a
+    /// robot that isn't registered is not found
+    pub async fn set_pending_fallback(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        instruction: &Instruction,
+    ) -> Result<(), ApiError> {
+        let instruction_json = serde_json::to_string(instruction).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        let updated = sqlx::query!(
+            r#"
+UPDATE robot
+SET pending_fallback = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            instruction_json
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Pending Fallback");
+            ApiError::from(e)
+        })?;
+
+        if updated.rows_affected() == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// What the robot is given when it has no commands waiting, idle
+    /// unless it has been set
+    pub async fn pending_fallback(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Instruction, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT pending_fallback FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            robot
+                .and_then(|r| r.pending_fallback)
+                .and_then(|f| serde_json::from_str(&f).ok())
+                .unwrap_or(Instruction::Idle)
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Pending Fallback");
+            ApiError::from(e)
+        })
+    }
+
+    /// Remembers the instruction the robot paused, so it can be picked
+    /// back up when the robot continues. This is called while polling, so
+    /// a robot that hasn't been registered is left alone.
+    pub async fn set_paused(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        instruction: &Instruction,
+    ) -> Result<(), ApiError> {
+        let instruction_json = serde_json::to_string(instruction).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        sqlx::query!(
+            r#"
+UPDATE robot
+SET paused_instruction = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            instruction_json
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Set Paused");
+            ApiError::from(e)
+        })?;
+
         Ok(())
     }
+
+    /// The instruction the robot paused, if it paused one, which is
+    /// forgotten once it has been taken
+    pub async fn take_paused(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<Instruction>, ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE robot R
+SET paused_instruction = NULL
+FROM (SELECT R1.paused_instruction FROM robot R1
+      WHERE R1.robot_serial_number = $1
+      FOR UPDATE) Paused
+WHERE R.robot_serial_number = $1
+RETURNING Paused.paused_instruction
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            robot
+                .and_then(|r| r.paused_instruction)
+                .and_then(|p| serde_json::from_str(&p).ok())
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Take Paused");
+            ApiError::from(e)
+        })
+    }
+
+    /// Records that the robot has finished all of its queued commands
+    pub async fn record_drained(conn: &PgPool, robot_serial_number: &str) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+INSERT INTO robot_events (robot_serial_number, event)
+VALUES ($1, $2)
+        "#,
+            robot_serial_number,
+            QUEUE_DRAINED
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Record Drained");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// When the robot last finished all of its queued commands
+    pub async fn last_drained(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<DateTime<Utc>>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT MAX(E.recorded_at) AS last_drained FROM robot_events E
+WHERE E.robot_serial_number = $1 AND
+      E.event = $2
+        "#,
+            robot_serial_number,
+            QUEUE_DRAINED
+        )
+        .fetch_one(conn)
+        .await
+        .map(|r| r.last_drained)
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Last Drained");
+            ApiError::from(e)
+        })
+    }
+
+    /// Records that the robot has just been heard from, a robot that
+    /// hasn't been registered is left alone. The firmware version is only
+    /// changed when the robot reports one.
+    pub async fn record_seen(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        firmware_version: Option<&str>,
+    ) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+UPDATE robot
+SET last_seen = GREATEST(last_seen, NOW()),
+    firmware_version = COALESCE($2, firmware_version)
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            firmware_version
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Record Seen");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// Whether `api_key` is the robot's key, a robot that hasn't been given
+    /// a key can't be authenticated
+    pub async fn authenticate(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        api_key: &str,
+    ) -> Result<bool, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT api_key_hash FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|r| {
+            r.and_then(|r| r.api_key_hash)
+                .is_some_and(|api_key_hash| api_key_hash == hash_api_key(api_key))
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Authenticate");
+            ApiError::from(e)
+        })
+    }
+
+    /// The serial the robot holding `api_key` is stored under, whether it
+    /// is in an organization or not, so robots in an organization can poll
+    /// with just their own serial
+    pub async fn authenticate_key(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        api_key: &str,
+    ) -> Result<Option<RobotSerial>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT R.robot_serial_number FROM robot R
+WHERE R.api_key_hash = $2 AND
+      (R.robot_serial_number = $1 OR
+       split_part(R.robot_serial_number, ':', 2) = $1)
+        "#,
+            robot_serial_number,
+            hash_api_key(api_key)
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Authenticate Key");
+            ApiError::from(e)
+        })?
+        .map(|r| RobotSerial::parse(&r.robot_serial_number))
+        .transpose()
+    }
+
+    /// Adds the robot to the organization, returning the serial it is
+    /// stored under there
+    pub async fn add_to_organization(
+        conn: &PgPool,
+        organization: &OrganizationId,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<RobotSerial, ApiError> {
+        let scoped = robot_serial_number.in_organization(organization);
+
+        sqlx::query!(
+            r#"
+INSERT INTO robot (robot_serial_number)
+VALUES ($1)
+ON CONFLICT (robot_serial_number) DO NOTHING
+        "#,
+            scoped.as_str()
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %scoped, error = ?e, "Robot Add To Organization");
+            ApiError::from(e)
+        })?;
+
+        Ok(scoped)
+    }
+
+    /// The serial the robot is stored under in the organization, a robot
+    /// that isn't in the organization is not found, whether or not it's
+    /// in another one
+    pub async fn in_organization(
+        conn: &PgPool,
+        organization: &OrganizationId,
+        robot_serial_number: &RobotSerial,
+    ) -> Result<RobotSerial, ApiError> {
+        let scoped = robot_serial_number.in_organization(organization);
+
+        sqlx::query!(
+            r#"
+SELECT R.robot_serial_number FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            scoped.as_str()
+        )
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %scoped, error = ?e, "Robot In Organization");
+            ApiError::from(e)
+        })?
+        .map(|_| scoped)
+        .ok_or(ApiError::NotFound)
+    }
+
+    /// Gives the robot a new API key, a robot that isn't registered is not
+    /// found. Only the key's hash is kept so the key is returned to be
+    /// handed to the robot, any previous key stops working.
+    pub async fn rotate_key(conn: &PgPool, robot_serial_number: &str) -> Result<String, ApiError> {
+        let api_key = generate_api_key();
+
+        let updated = sqlx::query!(
+            r#"
+UPDATE robot
+SET api_key_hash = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            hash_api_key(&api_key)
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Rotate Key");
+            ApiError::from(e)
+        })?;
+
+        if updated.rows_affected() == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(api_key)
+    }
+
+    /// Whether the robot has been heard from within `stale_after`, a robot
+    /// that has never been seen is offline
+    pub async fn is_online(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        stale_after: Duration,
+    ) -> Result<bool, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT last_seen FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            let last_seen = robot.and_then(|r| r.last_seen);
+            !is_stale(last_seen, Utc::now(), stale_after)
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Is Online");
+            ApiError::from(e)
+        })
+    }
+
+    /// The serial numbers of every robot that hasn't been heard from within
+    /// `stale_after`, including robots that have never been seen
+    pub async fn offline_robots(
+        conn: &PgPool,
+        stale_after: Duration,
+    ) -> Result<Vec<String>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT robot_serial_number FROM robot R
+WHERE R.last_seen IS NULL OR
+      R.last_seen < $1
+ORDER BY R.robot_serial_number
+        "#,
+            Utc::now() - stale_after
+        )
+        .fetch_all(conn)
+        .await
+        .map(|robots| robots.into_iter().map(|r| r.robot_serial_number).collect())
+        .map_err(|e| {
+            error!(error = ?e, "Robot Offline Robots");
+            ApiError::from(e)
+        })
+    }
+
+    /// Sets the time zone the robot's schedules are given in, a robot that
+    /// isn't registered is not found
+    pub async fn set_timezone(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        timezone: Tz,
+    ) -> Result<(), ApiError> {
+        let updated = sqlx::query!(
+            r#"
+UPDATE robot
+SET timezone = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            timezone.name()
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Set Timezone");
+            ApiError::from(e)
+        })?;
+
+        if updated.rows_affected() == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Sets where the robot's completed commands are sent, for those
+    /// issued without a callback of their own, `None` stops sending them.
+    /// A robot that isn't registered is not found.
+    pub async fn set_webhook_url(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        webhook_url: Option<&str>,
+    ) -> Result<(), ApiError> {
+        if let Some(webhook_url) = webhook_url {
+            check_callback_url(webhook_url)?;
+        }
+
+        let updated = sqlx::query!(
+            r#"
+UPDATE robot
+SET webhook_url = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            webhook_url
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Set Webhook");
+            ApiError::from(e)
+        })?;
+
+        if updated.rows_affected() == 0 {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// Where the robot's completed commands are sent, if anywhere
+    pub async fn webhook_url(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<String>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT webhook_url FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| robot.and_then(|r| r.webhook_url))
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Webhook");
+            ApiError::from(e)
+        })
+    }
+
+    /// The time zone the robot's schedules are given in, UTC unless it
+    /// has been set
+    pub async fn timezone(conn: &PgPool, robot_serial_number: &str) -> Result<Tz, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT timezone FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            robot
+                .and_then(|r| r.timezone)
+                .and_then(|t| t.parse().ok())
+                .unwrap_or(Tz::UTC)
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Timezone");
+            ApiError::from(e)
+        })
+    }
+
+    /// Registers every robot in a CSV, returning how many were registered
+    ///
+    /// Each line is `serial,timezone,capabilities` where the timezone and
+    /// the `;` separated cleaning patterns the robot can run are optional,
+    /// and robots already registered have whichever of them are given
+    /// updated. A serial repeated in the CSV is only registered once, and
+    /// nothing is registered if any line is invalid.
+    pub async fn import_csv<R: Read>(conn: &PgPool, mut reader: R) -> Result<u64, ApiError> {
+        let mut csv = String::new();
+        reader
+            .read_to_string(&mut csv)
+            .map_err(|_| ApiError::SerializationError)?;
+        let records = parse_robot_csv(&csv)?;
+
+        let mut tx = conn.begin().await.map_err(|e| {
+            error!(error = ?e, "Robot Import CSV");
+            ApiError::from(e)
+        })?;
+
+        let mut added = Vec::new();
+        for record in &records {
+            let capabilities = record
+                .capabilities
+                .as_ref()
+                .map(|c| serde_json::to_string(c).unwrap_or_default());
+
+            let inserted = sqlx::query!(
+                r#"
+INSERT INTO robot (robot_serial_number, timezone, capabilities)
+VALUES ($1, $2, $3)
+ON CONFLICT (robot_serial_number) DO UPDATE
+SET timezone = COALESCE(EXCLUDED.timezone, robot.timezone),
+    capabilities = COALESCE(EXCLUDED.capabilities, robot.capabilities)
+RETURNING (xmax = 0) AS "inserted!"
+        "#,
+                record.robot_serial_number.as_str(),
+                record.timezone.map(|tz| tz.name()),
+                capabilities
+            )
+            .fetch_one(&mut tx)
+            .await
+            .map(|r| r.inserted)
+            .map_err(|e| {
+                error!(error = ?e, "Robot Import CSV");
+                ApiError::from(e)
+            })?;
+
+            if inserted {
+                added.push(&record.robot_serial_number);
+            }
+        }
+
+        tx.commit().await.map_err(|e| {
+            error!(error = ?e, "Robot Import CSV");
+            ApiError::from(e)
+        })?;
+
+        // New robots start in idle, the same as when registered one at a time
+        for robot_serial_number in added {
+            Command::idle(conn, robot_serial_number).await?;
+        }
+
+        Ok(records.len() as u64)
+    }
+
+    /// The cleaning patterns the robot can run, if they have been given
+    pub async fn capabilities(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<Vec<CleaningPattern>>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT capabilities FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| {
+            robot
+                .and_then(|r| r.capabilities)
+                .and_then(|c| serde_json::from_str(&c).ok())
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Capabilities");
+            ApiError::from(e)
+        })
+    }
+
+    /// The last battery level the robot reported, if it has reported one
+    pub async fn battery_level(
+        conn: &PgPool,
+        robot_serial_number: &str,
+    ) -> Result<Option<i64>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT battery_level FROM robot R
+WHERE R.robot_serial_number = $1
+        "#,
+            robot_serial_number
+        )
+        .fetch_optional(conn)
+        .await
+        .map(|robot| robot.and_then(|r| r.battery_level))
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Battery Level");
+            ApiError::from(e)
+        })
+    }
+
+    /// How many robots are running each firmware version, robots that have
+    /// never reported one are left out
+    pub async fn firmware_distribution(conn: &PgPool) -> Result<HashMap<String, i64>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT R.firmware_version AS "firmware_version!", COUNT(*) AS "count!" FROM robot R
+WHERE R.firmware_version IS NOT NULL
+GROUP BY R.firmware_version
+        "#
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|r| (r.firmware_version, r.count))
+                .collect()
+        })
+        .map_err(|e| {
+            error!(error = ?e, "Robot Firmware Distribution");
+            ApiError::from(e)
+        })
+    }
+
+    /// Adds the reading, and whether it was low, to the robot's battery
+    /// history, returning how many low readings there have been in a row
+    ///
+    /// Only a robot that has been registered has its latest level kept,
+    /// polling doesn't register a robot. Failing to add the reading to the
+    /// history is only logged as the history is just telemetry, the count
+    /// then falls back to the readings already stored, with this one added
+    /// on if it was low and the count reset if it wasn't.
+    pub async fn record_battery_reading(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        battery_level: i64,
+        low: bool,
+    ) -> Result<i32, ApiError> {
+        let history = sqlx::query!(
+            r#"
+INSERT INTO battery_readings (robot_serial_number, battery_level, low)
+VALUES ($1, $2, $3)
+        "#,
+            robot_serial_number,
+            battery_level,
+            low
+        )
+        .execute(conn)
+        .await;
+        if let Err(e) = &history {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Battery History");
+        }
+
+        sqlx::query!(
+            r#"
+UPDATE robot
+SET battery_level = $2
+WHERE robot_serial_number = $1
+        "#,
+            robot_serial_number,
+            battery_level
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Battery Reading");
+            ApiError::from(e)
+        })?;
+
+        // Every reading since the last one that wasn't low
+        sqlx::query!(
+            r#"
+SELECT COUNT(*)::INTEGER AS "low_readings!" FROM battery_readings B
+WHERE B.robot_serial_number = $1 AND
+      B.battery_reading_id > COALESCE(
+          (SELECT MAX(G.battery_reading_id) FROM battery_readings G
+           WHERE G.robot_serial_number = $1 AND
+                 NOT G.low),
+          0)
+        "#,
+            robot_serial_number
+        )
+        .fetch_one(conn)
+        .await
+        .map(|r| match (&history, low) {
+            (Ok(_), _) => r.low_readings,
+            (Err(_), true) => r.low_readings + 1,
+            (Err(_), false) => 0,
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Battery Reading");
+            ApiError::from(e)
+        })
+    }
+
+    /// Stores where the robot currently is, and which way it is facing if
+    /// it said
+    pub async fn record_position(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        x: f64,
+        y: f64,
+        heading: Option<f64>,
+    ) -> Result<(), ApiError> {
+        sqlx::query!(
+            r#"
+INSERT INTO path_points (robot_serial_number, x, y, heading)
+VALUES ($1, $2, $3, $4)
+        "#,
+            robot_serial_number,
+            x,
+            y,
+            heading
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Record Position");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// The path the robot took between two times, in order, as
+    /// `(time, x, y)`. Long paths are thinned out to at most
+    /// `MAX_PATH_POINTS` points.
+    pub async fn path(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Utc>, f64, f64)>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT recorded_at, x, y FROM path_points P
+WHERE P.robot_serial_number = $1 AND
+      P.recorded_at BETWEEN $2 AND $3
+ORDER BY P.recorded_at, P.path_point_id
+        "#,
+            robot_serial_number,
+            from,
+            to
+        )
+        .fetch_all(conn)
+        .await
+        .map(|points| {
+            let points = points
+                .into_iter()
+                .map(|p| (p.recorded_at, p.x, p.y))
+                .collect();
+
+            downsample(points, MAX_PATH_POINTS)
+        })
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Robot Path");
+            ApiError::from(e)
+        })
+    }
+}
+
+// A robot is stale when it was last seen longer than `stale_after` ago, or
+// has never been seen
+fn is_stale(last_seen: Option<DateTime<Utc>>, now: DateTime<Utc>, stale_after: Duration) -> bool {
+    match last_seen {
+        Some(last_seen) => now - last_seen > stale_after,
+        None => true,
+    }
+}
+
+// 32 random bytes as hex
+fn generate_api_key() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Serials and organization ids are 1 to 64 ASCII letters, digits, `-` and `_`
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_SERIAL_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// The keys are random so a plain SHA-256 is enough, and quick to check on
+// every poll unlike a password hash
+fn hash_api_key(api_key: &str) -> String {
+    format!("{:x}", Sha256::digest(api_key.as_bytes()))
+}
+
+// Reads the robots out of a CSV, skipping blank lines, a header and
+// repeated serials, and failing with the line number of the first invalid line
+fn parse_robot_csv(csv: &str) -> Result<Vec<RobotRecord>, ApiError> {
+    let mut seen = HashSet::new();
+    let mut records = Vec::new();
+
+    for (index, line) in csv.lines().enumerate() {
+        let line_number = index + 1;
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let invalid = || ApiError::InvalidCsv(line_number);
+
+        match fields.as_slice() {
+            [""] => continue,
+            ["robot_serial_number", ..] if line_number == 1 => continue,
+            _ if fields.len() > 3 => return Err(invalid()),
+            _ => {}
+        }
+
+        let robot_serial_number = RobotSerial::parse(fields[0]).map_err(|_| invalid())?;
+
+        let timezone = match fields.get(1) {
+            Some(tz) if !tz.is_empty() => Some(tz.parse().map_err(|_| invalid())?),
+            _ => None,
+        };
+
+        let capabilities = match fields.get(2) {
+            Some(patterns) if !patterns.is_empty() => Some(
+                patterns
+                    .split(';')
+                    .map(|p| serde_json::from_value(Value::String(p.trim().to_string())))
+                    .collect::<Result<Vec<CleaningPattern>, _>>()
+                    .map_err(|_| invalid())?,
+            ),
+            _ => None,
+        };
+
+        if !seen.insert(robot_serial_number.clone()) {
+            warn!(
+                robot_serial_number = %robot_serial_number,
+                line_number, "Robot Import CSV: skipping duplicate"
+            );
+            continue;
+        }
+
+        records.push(RobotRecord {
+            robot_serial_number,
+            timezone,
+            capabilities,
+        });
+    }
+
+    Ok(records)
+}
+
+// Picks at most `max` evenly spaced points, always keeping the first and last
+fn downsample<T>(points: Vec<T>, max: usize) -> Vec<T> {
+    if points.len() <= max {
+        return points;
+    }
+    if max < 2 {
+        return points.into_iter().take(max).collect();
+    }
+
+    let last = points.len() - 1;
+    let keep: Vec<usize> = (0..max).map(|i| i * last / (max - 1)).collect();
+
+    points
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep.binary_search(i).is_ok())
+        .map(|(_, p)| p)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        downsample, generate_api_key, hash_api_key, is_stale, parse_robot_csv, OrganizationId,
+        RobotRecord, RobotSerial,
+    };
+    use crate::command::CleaningPattern;
+    use crate::error::ApiError;
+    use chrono::{Duration, TimeZone, Utc};
+    use chrono_tz::Tz;
+
+    #[test]
+    fn accepted_serials() {
+        for serial in &[
+            "testing1",
+            "SDP-0042",
+            "alpha_beta-9",
+            &"x".repeat(64),
+            "acme:SDP-0042",
+        ] {
+            assert_eq!(*serial, RobotSerial::parse(serial).unwrap().as_str());
+        }
+    }
+
+    #[test]
+    fn rejected_serials() {
+        for serial in &[
+            "",
+            &"x".repeat(65),
+            "has space",
+            "dot.ted",
+            "slash/ed",
+            "émile",
+            ":SDP-0042",
+            "acme:",
+            "a:b:c",
+        ] {
+            assert!(matches!(
+                RobotSerial::parse(serial),
+                Err(ApiError::InvalidSerial)
+            ));
+        }
+
+        assert!(serde_json::from_str::<RobotSerial>(r#""""#).is_err());
+        assert_eq!(
+            RobotSerial::parse("SDP-0042").unwrap(),
+            serde_json::from_str::<RobotSerial>(r#""SDP-0042""#).unwrap()
+        );
+    }
+
+    #[test]
+    fn organization_serials() {
+        let acme = OrganizationId::parse("acme").unwrap();
+        let serial = RobotSerial::parse("SDP-0042").unwrap();
+
+        let scoped = serial.in_organization(&acme);
+        assert_eq!("acme:SDP-0042", scoped.as_str());
+        assert_eq!("SDP-0042", scoped.unqualified());
+        assert_eq!("SDP-0042", serial.unqualified());
+        assert_eq!(Some(acme.clone()), scoped.organization());
+        assert_eq!(None, serial.organization());
+
+        // Moving between organizations doesn't keep the old one
+        let other = OrganizationId::parse("other").unwrap();
+        assert_eq!("other:SDP-0042", scoped.in_organization(&other).as_str());
+
+        assert!(matches!(
+            OrganizationId::parse("ac:me"),
+            Err(ApiError::InvalidOrganization)
+        ));
+        assert!(OrganizationId::parse("").is_err());
+    }
+
+    #[test]
+    fn api_keys() {
+        let api_key = generate_api_key();
+
+        assert_eq!(64, api_key.len());
+        assert_ne!(api_key, generate_api_key());
+        assert_eq!(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            hash_api_key("hello")
+        );
+    }
+
+    #[test]
+    fn stale_robots() {
+        let now = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let stale_after = Duration::minutes(5);
+
+        assert!(!is_stale(
+            Some(now - Duration::minutes(1)),
+            now,
+            stale_after
+        ));
+        assert!(!is_stale(Some(now - stale_after), now, stale_after));
+        assert!(is_stale(Some(now - Duration::minutes(6)), now, stale_after));
+        assert!(is_stale(None, now, stale_after));
+    }
+
+    #[test]
+    fn downsample_short_path() {
+        assert_eq!(vec![1, 2, 3], downsample(vec![1, 2, 3], 5));
+    }
+
+    #[test]
+    fn downsample_long_path() {
+        let points: Vec<_> = (0..100).collect();
+
+        assert_eq!(vec![0, 33, 66, 99], downsample(points, 4));
+    }
+
+    #[test]
+    fn parse_csv() {
+        let csv = "robot_serial_number,timezone,capabilities\n\
+                   alpha-1\n\
+                   \n\
+                   beta_2, Europe/London ,ZigZag;circular\n\
+                   alpha-1,Asia/Tokyo\n";
+
+        assert_eq!(
+            vec![
+                RobotRecord {
+                    robot_serial_number: RobotSerial::parse("alpha-1").unwrap(),
+                    timezone: None,
+                    capabilities: None,
+                },
+                RobotRecord {
+                    robot_serial_number: RobotSerial::parse("beta_2").unwrap(),
+                    timezone: Some(Tz::Europe__London),
+                    capabilities: Some(vec![CleaningPattern::ZigZag, CleaningPattern::Circular]),
+                },
+            ],
+            parse_robot_csv(csv).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_csv_invalid_lines() {
+        let invalid_line = |csv| match parse_robot_csv(csv) {
+            Err(ApiError::InvalidCsv(line)) => line,
+            other => panic!("expected an invalid line, got {:?}", other),
+        };
+
+        assert_eq!(2, invalid_line("alpha\nbad serial\n"));
+        assert_eq!(1, invalid_line(",Europe/London"));
+        assert_eq!(1, invalid_line("alpha,Mars/Olympus"));
+        assert_eq!(1, invalid_line("alpha,,FigureEight"));
+        assert_eq!(1, invalid_line("alpha,UTC,ZigZag,extra"));
+    }
 }
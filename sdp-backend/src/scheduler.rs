@@ -0,0 +1,169 @@
+use crate::command::{Command, Instruction};
+use crate::error::ApiError;
+use crate::robot::{Robot, RobotSerial};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use sqlx::postgres::PgPool;
+use tracing::error;
+
+// How much the battery level and idle time count towards a robot's score
+const BATTERY_WEIGHT: f64 = 0.6;
+const IDLE_WEIGHT: f64 = 0.4;
+// Robots idle for longer than this all count as equally rested
+const IDLE_SATURATION_SECS: i64 = 60 * 60;
+
+/// Picks the robot best suited to take on a new task from the candidates,
+/// favouring robots with more battery that have been idle for longer.
+pub async fn pick_best_robot(conn: &PgPool, candidates: &[String]) -> Result<String, ApiError> {
+    let robots = sqlx::query!(
+        r#"
+SELECT S.robot_serial_number AS "robot_serial_number!",
+       R.battery_level AS "battery_level?",
+       (SELECT MAX(C.time_instruction) FROM Commands C
+        WHERE C.robot_serial_number = S.robot_serial_number AND
+              C.instruction <> '"Idle"') AS last_active
+FROM UNNEST($1::TEXT[]) AS S (robot_serial_number)
+LEFT JOIN robot R ON R.robot_serial_number = S.robot_serial_number
+        "#,
+        candidates
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|e| {
+        error!(error = ?e, "Scheduler Pick Robot");
+        ApiError::from(e)
+    })?;
+
+    let now = Utc::now();
+
+    robots
+        .into_iter()
+        .map(|r| {
+            let idle_for = r.last_active.map(|t| now - t);
+            (score(r.battery_level, idle_for), r.robot_serial_number)
+        })
+        .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(_, serial)| serial)
+        .ok_or(ApiError::NotFound)
+}
+
+/// Queues the instruction for the next time the cron schedule comes round
+/// in the robot's own time zone
+pub async fn schedule(
+    conn: &PgPool,
+    robot_serial_number: &RobotSerial,
+    cron: &str,
+    instruction: &Instruction,
+) -> Result<Command, ApiError> {
+    let timezone = Robot::timezone(conn, robot_serial_number).await?;
+    let run_at = next_run(cron, timezone, Utc::now())?;
+
+    Command::schedule(conn, robot_serial_number, instruction, run_at).await
+}
+
+/// The first time after `after` that a cron schedule runs, with the
+/// schedule read in the given time zone
+///
+/// Only daily schedules, `minute hour * * *`, are supported. Days where
+/// the time doesn't exist because the clocks go forward are skipped.
+pub fn next_run(cron: &str, timezone: Tz, after: DateTime<Utc>) -> Result<DateTime<Utc>, ApiError> {
+    let fields: Vec<&str> = cron.split_whitespace().collect();
+    let time = match fields.as_slice() {
+        [minute, hour, "*", "*", "*"] => minute
+            .parse()
+            .ok()
+            .zip(hour.parse().ok())
+            .and_then(|(m, h)| NaiveTime::from_hms_opt(h, m, 0)),
+        _ => None,
+    }
+    .ok_or(ApiError::InvalidSchedule)?;
+
+    let mut day = after.with_timezone(&timezone).date().naive_local();
+    loop {
+        if let Some(run_at) = timezone.from_local_datetime(&day.and_time(time)).earliest() {
+            if run_at > after {
+                return Ok(run_at.with_timezone(&Utc));
+            }
+        }
+        day = day.succ();
+    }
+}
+
+/// Scores a robot between 0 and 1 from its battery level and how long
+/// it has been idle. An unknown battery level scores nothing, and a
+/// robot that has never been active counts as fully rested.
+pub fn score(battery_level: Option<i64>, idle_for: Option<Duration>) -> f64 {
+    let battery = battery_level.map_or(0.0, |b| b.clamp(0, 100) as f64 / 100.0);
+    let idle = idle_for.map_or(1.0, |d| {
+        d.num_seconds().clamp(0, IDLE_SATURATION_SECS) as f64 / IDLE_SATURATION_SECS as f64
+    });
+
+    battery * BATTERY_WEIGHT + idle * IDLE_WEIGHT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_run, score};
+    use crate::error::ApiError;
+    use chrono::{Duration, TimeZone, Utc};
+    use chrono_tz::Tz;
+
+    #[test]
+    fn score_prefers_battery_and_idle_time() {
+        let rested = score(Some(90), Some(Duration::minutes(50)));
+
+        assert!(rested > score(Some(60), Some(Duration::minutes(50))));
+        assert!(rested > score(Some(90), Some(Duration::minutes(1))));
+        assert!(rested > score(None, Some(Duration::hours(5))));
+    }
+
+    #[test]
+    fn score_bounds() {
+        assert_eq!(1.0, score(Some(100), None));
+        assert_eq!(1.0, score(Some(150), Some(Duration::days(3))));
+        assert_eq!(0.0, score(None, Some(Duration::zero())));
+    }
+
+    #[test]
+    fn next_run_in_local_time() {
+        let after = Utc.ymd(2021, 1, 15).and_hms(0, 0, 0);
+
+        assert_eq!(
+            Utc.ymd(2021, 1, 15).and_hms(2, 0, 0),
+            next_run("0 2 * * *", Tz::Europe__London, after).unwrap()
+        );
+        assert_eq!(
+            Utc.ymd(2021, 1, 15).and_hms(7, 0, 0),
+            next_run("0 2 * * *", Tz::America__New_York, after).unwrap()
+        );
+
+        // Already past 2am in Tokyo, so it runs the next day
+        assert_eq!(
+            Utc.ymd(2021, 1, 15).and_hms(17, 30, 0),
+            next_run("30 2 * * *", Tz::Asia__Tokyo, after).unwrap()
+        );
+    }
+
+    #[test]
+    fn next_run_skips_missing_times() {
+        // The clocks went forward at 2am in New York on the 14th of March
+        let after = Utc.ymd(2021, 3, 14).and_hms(0, 0, 0);
+
+        assert_eq!(
+            Utc.ymd(2021, 3, 15).and_hms(6, 30, 0),
+            next_run("30 2 * * *", Tz::America__New_York, after).unwrap()
+        );
+    }
+
+    #[test]
+    fn only_daily_schedules() {
+        let after = Utc::now();
+
+        for cron in &["0 2 * * 1", "*/5 * * * *", "0 25 * * *", "0 2"] {
+            assert!(matches!(
+                next_run(cron, Tz::UTC, after),
+                Err(ApiError::InvalidSchedule)
+            ));
+        }
+    }
+}
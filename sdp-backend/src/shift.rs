@@ -0,0 +1,99 @@
+use chrono::{DateTime, NaiveTime, Utc};
+
+/// A named window of the day that cleaning happens in, in UTC
+///
+/// A shift that ends before it starts runs over midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shift {
+    pub id: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Shift {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShiftConfig {
+    pub shifts: Vec<Shift>,
+}
+
+impl ShiftConfig {
+    /// Reads shifts given as `id=HH:MM-HH:MM` separated by commas, for
+    /// example `day=06:00-18:00,night=18:00-06:00`
+    pub fn parse(config: &str) -> Option<Self> {
+        config
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| {
+                let (id, window) = s.trim().split_once('=')?;
+                let (start, end) = window.split_once('-')?;
+
+                Some(Shift {
+                    id: id.to_string(),
+                    start: NaiveTime::parse_from_str(start, "%H:%M").ok()?,
+                    end: NaiveTime::parse_from_str(end, "%H:%M").ok()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|shifts| Self { shifts })
+    }
+}
+
+/// The shift the time falls in, the first match wins if shifts overlap
+pub fn current(now: DateTime<Utc>, config: &ShiftConfig) -> Option<String> {
+    let time = now.time();
+
+    config
+        .shifts
+        .iter()
+        .find(|shift| shift.contains(time))
+        .map(|shift| shift.id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, ShiftConfig};
+    use chrono::{TimeZone, Utc};
+
+    fn config() -> ShiftConfig {
+        ShiftConfig::parse("morning=06:00-14:00,evening=14:00-22:00,night=22:00-06:00").unwrap()
+    }
+
+    #[test]
+    fn maps_times_to_shifts() {
+        let at = |h, m| Utc.ymd(2021, 3, 1).and_hms(h, m, 0);
+        let config = config();
+
+        assert_eq!(Some("morning".to_string()), current(at(6, 0), &config));
+        assert_eq!(Some("morning".to_string()), current(at(13, 59), &config));
+        assert_eq!(Some("evening".to_string()), current(at(14, 0), &config));
+        assert_eq!(Some("night".to_string()), current(at(23, 30), &config));
+        assert_eq!(Some("night".to_string()), current(at(2, 0), &config));
+    }
+
+    #[test]
+    fn outside_every_shift() {
+        let config = ShiftConfig::parse("day=08:00-17:00").unwrap();
+
+        assert_eq!(
+            None,
+            current(Utc.ymd(2021, 3, 1).and_hms(20, 0, 0), &config)
+        );
+        assert_eq!(None, current(Utc::now(), &ShiftConfig::default()));
+    }
+
+    #[test]
+    fn rejects_malformed_config() {
+        assert!(ShiftConfig::parse("day=8am-5pm").is_none());
+        assert!(ShiftConfig::parse("day").is_none());
+        assert_eq!(Some(ShiftConfig::default()), ShiftConfig::parse(""));
+    }
+}
@@ -0,0 +1,13 @@
+use std::env;
+use tracing_subscriber::EnvFilter;
+
+/// Writes the backend's tracing events to stdout, for use by binaries. The
+/// filter is read from `SDP_LOG` and defaults to `sdp_backend=info`, so it
+/// doesn't clash with the `RUST_LOG` used by actix's logger
+pub fn init() {
+    let filter = env::var("SDP_LOG").unwrap_or_else(|_| "sdp_backend=info".to_string());
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .try_init();
+}
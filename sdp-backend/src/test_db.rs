@@ -0,0 +1,128 @@
+//! The database the integration tests run against
+//!
+//! By default this is the database at `DATABASE_URL`, which has to have
+//! the migrations applied already. With the `testcontainers` feature a
+//! throwaway Postgres is started in docker instead and migrated from
+//! scratch, so the tests can run without a database being set up.
+//!
+//! Tests that act on the whole fleet use `isolated_db_connect` so the
+//! robots of other tests running alongside them aren't touched.
+
+use sqlx::postgres::{PgPool, PgPoolOptions};
+
+#[cfg(not(feature = "testcontainers"))]
+pub async fn db_connect() -> PgPool {
+    dotenv::dotenv().ok();
+
+    PgPool::connect(&server_url())
+        .await
+        .expect("to get database pool")
+}
+
+#[cfg(feature = "testcontainers")]
+pub async fn db_connect() -> PgPool {
+    let database_pool = PgPool::connect(container::database_url())
+        .await
+        .expect("to get database pool");
+
+    // The migrator locks the database, so every test can run it and the
+    // migrations are still only applied once
+    sqlx::migrate!("./migrations")
+        .run(&database_pool)
+        .await
+        .expect("to run the migrations");
+
+    database_pool
+}
+
+/// A database of its own for the test, created fresh and migrated from
+/// scratch, it's kept until the test with the same name next runs
+pub async fn isolated_db_connect(test_name: &str) -> PgPool {
+    let server = db_connect().await;
+    let database_name = format!("sdp_test_{}", test_name);
+
+    sqlx::query(&format!(
+        "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+        database_name
+    ))
+    .execute(&server)
+    .await
+    .expect("to drop the old test database");
+    sqlx::query(&format!("CREATE DATABASE {}", database_name))
+        .execute(&server)
+        .await
+        .expect("to create the test database");
+
+    let server_url = server_url();
+    let (server_url, _) = server_url
+        .rsplit_once('/')
+        .expect("database url to name a database");
+    let database_pool = PgPool::connect(&format!("{}/{}", server_url, database_name))
+        .await
+        .expect("to get database pool");
+
+    sqlx::migrate!("./migrations")
+        .run(&database_pool)
+        .await
+        .expect("to run the migrations");
+
+    database_pool
+}
+
+/// A pool for a database that doesn't exist on the server, it connects
+/// lazily so it can be made but every query through it fails
+pub fn missing_db_pool() -> PgPool {
+    dotenv::dotenv().ok();
+
+    let server_url = server_url();
+    let (server_url, _) = server_url
+        .rsplit_once('/')
+        .expect("database url to name a database");
+
+    PgPoolOptions::new()
+        .connect_timeout(std::time::Duration::from_secs(2))
+        .connect_lazy(&format!("{}/sdp_test_missing", server_url))
+        .expect("a valid database url")
+}
+
+#[cfg(not(feature = "testcontainers"))]
+fn server_url() -> String {
+    std::env::var("DATABASE_URL").expect("DATABASE_URL to be set")
+}
+
+#[cfg(feature = "testcontainers")]
+fn server_url() -> String {
+    container::database_url().to_string()
+}
+
+#[cfg(feature = "testcontainers")]
+mod container {
+    use std::sync::OnceLock;
+    use testcontainers::{clients::Cli, images::postgres::Postgres, RunnableImage};
+
+    // The same major version as production
+    const POSTGRES_TAG: &str = "15-alpine";
+
+    static DATABASE_URL: OnceLock<String> = OnceLock::new();
+
+    /// Starts Postgres the first time it's asked for, every test in the
+    /// run then shares the one container.
+    ///
+    /// Each test has its own runtime so only the address is shared, not a
+    /// pool. The container is kept until the tests exit, it's removed if
+    /// they are interrupted but otherwise is left for docker to clean up.
+    pub fn database_url() -> &'static str {
+        DATABASE_URL.get_or_init(|| {
+            let docker: &'static Cli = Box::leak(Box::default());
+            let postgres =
+                docker.run(RunnableImage::from(Postgres::default()).with_tag(POSTGRES_TAG));
+            let url = format!(
+                "postgres://postgres@127.0.0.1:{}/postgres",
+                postgres.get_host_port_ipv4(5432)
+            );
+            std::mem::forget(postgres);
+
+            url
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use crate::command::Instruction;
+use crate::error::ApiError;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use tracing::error;
+
+/// The audit trail of robots moving from one instruction to another
+pub struct Transition;
+
+impl Transition {
+    /// Records the robot being handed a new instruction
+    pub async fn record(
+        conn: &PgPool,
+        robot_serial_number: &str,
+        from: &Instruction,
+        to: &Instruction,
+    ) -> Result<(), ApiError> {
+        let from_json = serde_json::to_string(from).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+        let to_json = serde_json::to_string(to).map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Instrution Json");
+            ApiError::SerializationError
+        })?;
+
+        sqlx::query!(
+            r#"
+INSERT INTO transitions (robot_serial_number, from_instruction, to_instruction)
+VALUES ($1, $2, $3)
+        "#,
+            robot_serial_number,
+            from_json,
+            to_json
+        )
+        .execute(conn)
+        .await
+        .map_err(|e| {
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "Transition Record");
+            ApiError::from(e)
+        })?;
+
+        Ok(())
+    }
+
+    /// How often robots across the fleet went from one kind of instruction
+    /// to another since the given time
+    pub async fn frequency_matrix(
+        conn: &PgPool,
+        since: DateTime<Utc>,
+    ) -> Result<HashMap<(String, String), i64>, ApiError> {
+        sqlx::query!(
+            r#"
+SELECT T.from_instruction, T.to_instruction, COUNT(*) AS "count!" FROM transitions T
+WHERE T.transitioned_at >= $1
+GROUP BY T.from_instruction, T.to_instruction
+        "#,
+            since
+        )
+        .fetch_all(conn)
+        .await
+        .map(|rows| {
+            tally_transitions(
+                rows.into_iter()
+                    .map(|r| (r.from_instruction, r.to_instruction, r.count)),
+            )
+        })
+        .map_err(|e| {
+            error!(error = ?e, "Transition Frequency Matrix");
+            ApiError::from(e)
+        })
+    }
+}
+
+// Adds up counts of instruction JSON pairs by the kinds of instruction
+fn tally_transitions(
+    counts: impl Iterator<Item = (String, String, i64)>,
+) -> HashMap<(String, String), i64> {
+    let kind = |json: &str| {
+        serde_json::from_str::<Instruction>(json)
+            .map(|i| i.kind().to_string())
+            .unwrap_or_else(|_| "Unknown".to_string())
+    };
+
+    let mut tally = HashMap::new();
+    for (from, to, count) in counts {
+        *tally.entry((kind(&from), kind(&to))).or_insert(0) += count;
+    }
+
+    tally
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tally_transitions;
+
+    #[test]
+    fn tally_by_kind() {
+        let counts = vec![
+            (
+                "\"Idle\"".to_string(),
+                r#"{"Task":"ZigZag"}"#.to_string(),
+                2,
+            ),
+            (
+                "\"Idle\"".to_string(),
+                r#"{"Task":"Circular"}"#.to_string(),
+                1,
+            ),
+            (
+                r#"{"Task":"ZigZag"}"#.to_string(),
+                "\"Idle\"".to_string(),
+                4,
+            ),
+            ("bad".to_string(), "\"Idle\"".to_string(), 1),
+        ];
+
+        let tally = tally_transitions(counts.into_iter());
+        assert_eq!(3, tally[&("Idle".to_string(), "Task".to_string())]);
+        assert_eq!(4, tally[&("Task".to_string(), "Idle".to_string())]);
+        assert_eq!(1, tally[&("Unknown".to_string(), "Idle".to_string())]);
+        assert_eq!(3, tally.len());
+    }
+}
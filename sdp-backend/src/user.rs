@@ -1,8 +1,9 @@
 use crate::error::ApiError;
-use crate::robot::Robot;
+use crate::robot::{Robot, RobotSerial};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPool;
+use tracing::error;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
@@ -21,7 +22,7 @@ impl User {
     ) -> Result<Self, ApiError> {
         // TODO: fix this
         let user_name = user_name.to_string();
-        let robot_serial_number = robot_serial_number.to_string();
+        let robot_serial_number = RobotSerial::parse(robot_serial_number)?;
 
         let password_hash = hash(password, DEFAULT_COST).map_err(|_| ApiError::HashingFailed)?;
 
@@ -35,13 +36,13 @@ RETURNING user_id
         "#,
             user_name,
             password_hash,
-            robot_serial_number
+            robot_serial_number.as_str()
         )
         .fetch_one(conn)
         .await
         .map_err(|e| {
-            println!("User Insert: {:?}", e);
-            ApiError::DatabaseConnFailed
+            error!(robot_serial_number = %robot_serial_number, error = ?e, "User Insert");
+            ApiError::from(e)
         })?
         .user_id;
 
@@ -49,7 +50,7 @@ RETURNING user_id
             user_id,
             user_name,
             password_hash,
-            robot_serial_number,
+            robot_serial_number: robot_serial_number.into(),
         })
     }
 
@@ -74,15 +75,15 @@ WHERE U.user_name = $1
                 robot_serial_number: u.robot_serial_number,
             })
         })
-        .map_err(|_| ApiError::DatabaseConnFailed)
+        .map_err(ApiError::from)
     }
 
     pub async fn login(conn: &PgPool, user_name: &str, password: &str) -> Result<Self, ApiError> {
-        let user = Self::search_by_username(&conn, user_name)
+        let user = Self::search_by_username(conn, user_name)
             .await?
             .ok_or(ApiError::LoginFailedUserNotExist)?;
 
-        match verify(&password, &user.password_hash) {
+        match verify(password, &user.password_hash) {
             Ok(verified) if verified => Ok(user),
             _ => Err(ApiError::LoginFailedPasswordIncorrect),
         }